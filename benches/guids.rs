@@ -0,0 +1,185 @@
+// Copyright 2018-2019 Mozilla
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Measures memory that the merger allocates when merging two large trees.
+//! Run with `cargo bench --bench guids`.
+//!
+//! This uses a counting global allocator instead of a timing harness, since
+//! we're interested in how many bytes the merger allocates, not how long it
+//! takes. The benchmark only uses the public tree and merger API, so it can
+//! be run unchanged before and after a change to the merger's internals, to
+//! compare both on the same trees.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+use dogear::{
+    DefaultAbortSignal, Guid, Item, Kind, Merger, Result, Tree, MENU_GUID, ROOT_GUID, UNFILED_GUID,
+};
+
+/// The number of bookmarks in each tree, including deleted ones.
+const ITEMS: usize = 100_000;
+
+/// The number of bookmarks in each folder.
+const ITEMS_PER_FOLDER: usize = 100;
+
+/// Every `LOCAL_DELETION_STEP`th bookmark is deleted locally.
+const LOCAL_DELETION_STEP: usize = 7;
+
+/// Every `REMOTE_DELETION_STEP`th bookmark is deleted remotely.
+const REMOTE_DELETION_STEP: usize = 5;
+
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+        PEAK.fetch_max(allocated, Ordering::SeqCst);
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::SeqCst);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Memory stats for a measured section.
+struct Stats {
+    /// Bytes still allocated at the end of the section.
+    retained: usize,
+    /// The most bytes allocated at once during the section.
+    peak: usize,
+    /// The number of allocations during the section.
+    allocations: usize,
+}
+
+/// Runs `f`, and returns its result with memory stats.
+fn measure<T>(f: impl FnOnce() -> T) -> (T, Stats) {
+    let start = ALLOCATED.load(Ordering::SeqCst);
+    PEAK.store(start, Ordering::SeqCst);
+    let start_allocations = ALLOCATIONS.load(Ordering::SeqCst);
+    let result = f();
+    let stats = Stats {
+        retained: ALLOCATED.load(Ordering::SeqCst).saturating_sub(start),
+        peak: PEAK.load(Ordering::SeqCst) - start,
+        allocations: ALLOCATIONS.load(Ordering::SeqCst) - start_allocations,
+    };
+    (result, stats)
+}
+
+fn report(name: &str, stats: &Stats) {
+    println!(
+        "{:<40} {:>12} retained {:>12} peak {:>10} allocations",
+        name, stats.retained, stats.peak, stats.allocations
+    );
+}
+
+/// Returns GUIDs for all bookmarks. Every tenth GUID is invalid, so that it
+/// needs a heap-allocated string.
+fn bookmark_guids() -> Vec<Guid> {
+    (0..ITEMS)
+        .map(|index| {
+            if index % 10 == 0 {
+                format!("invalid-bookmark-{}", index).into()
+            } else {
+                format!("bmk{:0>9}", index).into()
+            }
+        })
+        .collect()
+}
+
+/// Builds a tree with all bookmarks in folders under the menu, except for
+/// every `deletion_step`th bookmark, which is replaced with a tombstone.
+fn build_tree(guids: &[Guid], needs_merge: bool, deletion_step: usize) -> Result<Tree> {
+    let mut builder = Tree::with_root(Item::new(ROOT_GUID, Kind::Folder));
+    builder.reparent_orphans_to(&UNFILED_GUID);
+    builder
+        .item(Item::new(MENU_GUID, Kind::Folder))?
+        .by_structure(&ROOT_GUID)?;
+    builder
+        .item(Item::new(UNFILED_GUID, Kind::Folder))?
+        .by_structure(&ROOT_GUID)?;
+    for (folder_index, chunk) in guids.chunks(ITEMS_PER_FOLDER).enumerate() {
+        let folder_guid = Guid::from(format!("fld{:0>9}", folder_index));
+        builder
+            .item(Item::new(folder_guid.clone(), Kind::Folder))?
+            .by_structure(&MENU_GUID)?;
+        for (index, guid) in chunk.iter().enumerate() {
+            if (folder_index * ITEMS_PER_FOLDER + index).is_multiple_of(deletion_step) {
+                builder.deletion(guid.clone());
+                continue;
+            }
+            let mut item = Item::new(guid.clone(), Kind::Bookmark);
+            item.needs_merge = needs_merge;
+            builder.item(item)?.by_structure(&folder_guid)?;
+        }
+    }
+    builder.into_tree()
+}
+
+/// A driver that passes through invalid GUIDs, instead of replacing them.
+struct Driver;
+
+impl dogear::Driver for Driver {
+    fn generate_new_guid(&self, invalid_guid: &Guid) -> Result<Guid> {
+        Ok(invalid_guid.clone())
+    }
+}
+
+fn main() -> Result<()> {
+    let guids = bookmark_guids();
+
+    let (local_tree, stats) = measure(|| build_tree(&guids, false, LOCAL_DELETION_STEP));
+    let local_tree = local_tree?;
+    report("Local tree", &stats);
+    let (remote_tree, stats) = measure(|| build_tree(&guids, true, REMOTE_DELETION_STEP));
+    let remote_tree = remote_tree?;
+    report("Remote tree", &stats);
+
+    // The merged root keeps the merged and deleted GUIDs, so the retained
+    // bytes include them, and the peak includes everything that the merger
+    // allocated along the way.
+    let now = Instant::now();
+    let (merged_root, stats) = measure(|| {
+        Merger::with_driver(&Driver, &DefaultAbortSignal, &local_tree, &remote_tree).merge()
+    });
+    let elapsed = now.elapsed();
+    let merged_root = merged_root?;
+    report("Merged tree", &stats);
+    println!(
+        "Merged {} nodes, with {} deletions, in {:?}",
+        merged_root.counts().merged_nodes,
+        merged_root.deletions().count(),
+        elapsed
+    );
+
+    let (ops, stats) = measure(|| merged_root.completion_ops());
+    report("Completion ops", &stats);
+    drop(ops);
+    drop(merged_root);
+
+    Ok(())
+}
@@ -0,0 +1,386 @@
+// Copyright 2018-2019 Mozilla
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::BTreeSet, hash::Hasher, time::Duration};
+
+use crate::driver::MergePhase;
+use crate::guid::Guid;
+use crate::merge::{
+    Conflict, CrossFolderDupe, KeywordClash, ResolvedConflict, Side, StructureCounts,
+};
+use crate::tree::{Content, MergeState, MergedNode, Node, Tree};
+
+/// Saved state for a merge that was aborted, so that a later merge of the
+/// same trees can pick up where it left off.
+///
+/// Pass the same checkpoint to `Merger::merge_with_checkpoint` on every
+/// attempt. If the merge aborts after the two-way merge finishes, the merger
+/// saves the merged tree and the results of the phases that ran after it,
+/// and the next attempt skips those phases. The two-way merge itself can't
+/// be resumed partway through: if the merge aborts during it, the merger
+/// only saves the dupes that it matched, and the next attempt walks both
+/// trees again, without rescanning folders for those dupes. If either tree
+/// changed in between, the merger discards the checkpoint and starts over.
+/// The checkpoint is cleared once a merge finishes.
+///
+/// Checkpoints own their state, so the trees can be dropped and rebuilt
+/// between attempts.
+#[derive(Debug, Default)]
+pub struct MergeCheckpoint {
+    pub(crate) fingerprints: Option<(u64, u64)>,
+    pub(crate) dupes: Vec<SavedDupes>,
+    pub(crate) merge: Option<SavedMerge>,
+}
+
+impl MergeCheckpoint {
+    /// Creates an empty checkpoint.
+    #[inline]
+    pub fn new() -> MergeCheckpoint {
+        MergeCheckpoint::default()
+    }
+
+    /// Indicates if the checkpoint doesn't have any saved state.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.fingerprints.is_none()
+    }
+
+    /// Returns the last merge phase that finished before the merge aborted,
+    /// or `None` if the two-way merge didn't finish, and must run again.
+    #[inline]
+    pub fn phase(&self) -> Option<MergePhase> {
+        self.merge.as_ref().map(|merge| merge.phase)
+    }
+
+    /// Indicates if the checkpoint was saved for the given trees, and can be
+    /// resumed.
+    pub fn matches(&self, local_tree: &Tree, remote_tree: &Tree) -> bool {
+        self.fingerprints == Some((local_tree.fingerprint(), remote_tree.fingerprint()))
+    }
+
+    /// Discards all saved state.
+    #[inline]
+    pub fn clear(&mut self) {
+        *self = MergeCheckpoint::default();
+    }
+}
+
+/// Matching local and remote dupes for a local folder, saved from the
+/// merger's dupe cache.
+#[derive(Debug)]
+pub(crate) struct SavedDupes {
+    pub local_parent_guid: Guid,
+    /// (local child GUID, remote child GUID) pairs.
+    pub matches: Vec<(Guid, Guid)>,
+}
+
+/// A finished two-way merge, and the results of the phases after it.
+#[derive(Debug)]
+pub(crate) struct SavedMerge {
+    pub phase: MergePhase,
+    pub root: SavedNode,
+    pub merged_guids: Vec<Guid>,
+    pub delete_locally: Vec<Guid>,
+    pub delete_remotely: Vec<Guid>,
+    pub structure_counts: StructureCounts,
+    pub conflicts: Vec<SavedConflict>,
+    pub cross_folder_dupes: Vec<(Guid, Guid, bool)>,
+    pub keyword_clashes: Vec<KeywordClash>,
+    pub dedupe_time: Duration,
+    pub dedupe_nodes: usize,
+}
+
+/// An owned copy of a merged node, which refers to its local and remote
+/// nodes by GUID.
+#[derive(Debug)]
+pub(crate) struct SavedNode {
+    guid: Guid,
+    state: SavedState,
+    local_guid: Option<Guid>,
+    remote_guid: Option<Guid>,
+    children: Vec<SavedNode>,
+    merged_tags: Option<BTreeSet<String>>,
+    merged_content: Option<Content>,
+    needs_reupload: bool,
+}
+
+impl SavedNode {
+    /// Saves a merged node and its descendants. If `with_content` is `false`,
+    /// merged content from later phases is dropped, so that those phases
+    /// can run again.
+    pub fn new(merged_node: &MergedNode<'_>, with_content: bool) -> SavedNode {
+        SavedNode {
+            guid: merged_node.guid.clone(),
+            state: SavedState::new(&merged_node.merge_state),
+            local_guid: merged_node
+                .merge_state
+                .local_node()
+                .map(|node| node.guid.clone()),
+            remote_guid: merged_node
+                .merge_state
+                .remote_node()
+                .map(|node| node.guid.clone()),
+            children: merged_node
+                .merged_children
+                .iter()
+                .map(|child| SavedNode::new(child, with_content))
+                .collect(),
+            merged_tags: merged_node.merged_tags.clone(),
+            merged_content: if with_content {
+                merged_node.merged_content.clone()
+            } else {
+                None
+            },
+            needs_reupload: if with_content {
+                merged_node.needs_reupload
+            } else {
+                // Only merged tags flag items for reupload before the later
+                // phases run.
+                merged_node
+                    .merged_tags
+                    .as_ref()
+                    .zip(merged_node.merge_state.remote_node())
+                    .is_some_and(|(merged_tags, remote_node)| *merged_tags != remote_node.tags)
+            },
+        }
+    }
+
+    /// Rebuilds the merged node from the trees, or returns `None` if either
+    /// tree doesn't have a node that the saved node refers to.
+    pub fn restore<'t>(
+        &self,
+        local_tree: &'t Tree,
+        remote_tree: &'t Tree,
+    ) -> Option<MergedNode<'t>> {
+        let local_node = match &self.local_guid {
+            Some(guid) => Some(local_tree.node_for_guid(guid)?),
+            None => None,
+        };
+        let remote_node = match &self.remote_guid {
+            Some(guid) => Some(remote_tree.node_for_guid(guid)?),
+            None => None,
+        };
+        let merge_state = self.state.restore(local_node, remote_node)?;
+        let mut merged_node = MergedNode::new(self.guid.clone(), merge_state);
+        merged_node.merged_children = self
+            .children
+            .iter()
+            .map(|child| child.restore(local_tree, remote_tree))
+            .collect::<Option<_>>()?;
+        merged_node.merged_tags = self.merged_tags.clone();
+        merged_node.merged_content = self.merged_content.clone();
+        merged_node.needs_reupload = self.needs_reupload;
+        Some(merged_node)
+    }
+}
+
+/// A `MergeState` without its nodes.
+#[derive(Clone, Copy, Debug)]
+enum SavedState {
+    LocalOnly,
+    LocalOnlyWithNewLocalStructure,
+    RemoteOnly,
+    RemoteOnlyWithNewRemoteStructure,
+    Local,
+    LocalWithNewLocalStructure,
+    Remote,
+    RemoteWithNewRemoteStructure,
+    Unchanged,
+    UnchangedWithNewLocalStructure,
+}
+
+impl SavedState {
+    fn new(merge_state: &MergeState<'_>) -> SavedState {
+        match merge_state {
+            MergeState::LocalOnly(_) => SavedState::LocalOnly,
+            MergeState::LocalOnlyWithNewLocalStructure(_) => {
+                SavedState::LocalOnlyWithNewLocalStructure
+            }
+            MergeState::RemoteOnly(_) => SavedState::RemoteOnly,
+            MergeState::RemoteOnlyWithNewRemoteStructure(_) => {
+                SavedState::RemoteOnlyWithNewRemoteStructure
+            }
+            MergeState::Local { .. } => SavedState::Local,
+            MergeState::LocalWithNewLocalStructure { .. } => SavedState::LocalWithNewLocalStructure,
+            MergeState::Remote { .. } => SavedState::Remote,
+            MergeState::RemoteWithNewRemoteStructure { .. } => {
+                SavedState::RemoteWithNewRemoteStructure
+            }
+            MergeState::Unchanged { .. } => SavedState::Unchanged,
+            MergeState::UnchangedWithNewLocalStructure { .. } => {
+                SavedState::UnchangedWithNewLocalStructure
+            }
+        }
+    }
+
+    fn restore<'t>(
+        self,
+        local_node: Option<Node<'t>>,
+        remote_node: Option<Node<'t>>,
+    ) -> Option<MergeState<'t>> {
+        Some(match (self, local_node, remote_node) {
+            (SavedState::LocalOnly, Some(local_node), None) => MergeState::LocalOnly(local_node),
+            (SavedState::LocalOnlyWithNewLocalStructure, Some(local_node), None) => {
+                MergeState::LocalOnlyWithNewLocalStructure(local_node)
+            }
+            (SavedState::RemoteOnly, None, Some(remote_node)) => {
+                MergeState::RemoteOnly(remote_node)
+            }
+            (SavedState::RemoteOnlyWithNewRemoteStructure, None, Some(remote_node)) => {
+                MergeState::RemoteOnlyWithNewRemoteStructure(remote_node)
+            }
+            (state, Some(local_node), Some(remote_node)) => match state {
+                SavedState::Local => MergeState::Local {
+                    local_node,
+                    remote_node,
+                },
+                SavedState::LocalWithNewLocalStructure => MergeState::LocalWithNewLocalStructure {
+                    local_node,
+                    remote_node,
+                },
+                SavedState::Remote => MergeState::Remote {
+                    local_node,
+                    remote_node,
+                },
+                SavedState::RemoteWithNewRemoteStructure => {
+                    MergeState::RemoteWithNewRemoteStructure {
+                        local_node,
+                        remote_node,
+                    }
+                }
+                SavedState::Unchanged => MergeState::Unchanged {
+                    local_node,
+                    remote_node,
+                },
+                SavedState::UnchangedWithNewLocalStructure => {
+                    MergeState::UnchangedWithNewLocalStructure {
+                        local_node,
+                        remote_node,
+                    }
+                }
+                _ => return None,
+            },
+            _ => return None,
+        })
+    }
+}
+
+/// An owned copy of a resolved conflict.
+#[derive(Debug)]
+pub(crate) struct SavedConflict {
+    /// The local and remote parent GUIDs, for structure conflicts.
+    parent_guids: Option<(Guid, Guid)>,
+    local_guid: Guid,
+    remote_guid: Guid,
+    side: Side,
+    by_driver: bool,
+}
+
+impl SavedConflict {
+    pub fn new(resolved: &ResolvedConflict<'_>) -> SavedConflict {
+        let parent_guids = match resolved.conflict {
+            Conflict::Value { .. } => None,
+            Conflict::Structure {
+                local_parent_node,
+                remote_parent_node,
+                ..
+            } => Some((
+                local_parent_node.guid.clone(),
+                remote_parent_node.guid.clone(),
+            )),
+        };
+        SavedConflict {
+            parent_guids,
+            local_guid: resolved.conflict.local_node().guid.clone(),
+            remote_guid: resolved.conflict.remote_node().guid.clone(),
+            side: resolved.side,
+            by_driver: resolved.by_driver,
+        }
+    }
+
+    pub fn restore<'t>(
+        &self,
+        local_tree: &'t Tree,
+        remote_tree: &'t Tree,
+    ) -> Option<ResolvedConflict<'t>> {
+        let local_node = local_tree.node_for_guid(&self.local_guid)?;
+        let remote_node = remote_tree.node_for_guid(&self.remote_guid)?;
+        let conflict = match &self.parent_guids {
+            None => Conflict::Value {
+                local_node,
+                remote_node,
+            },
+            Some((local_parent_guid, remote_parent_guid)) => Conflict::Structure {
+                local_parent_node: local_tree.node_for_guid(local_parent_guid)?,
+                local_node,
+                remote_parent_node: remote_tree.node_for_guid(remote_parent_guid)?,
+                remote_node,
+            },
+        };
+        Some(ResolvedConflict {
+            conflict,
+            side: self.side,
+            by_driver: self.by_driver,
+        })
+    }
+}
+
+/// Saves a cross-folder dupe as (local GUID, remote GUID, merged).
+pub(crate) fn save_cross_folder_dupe(dupe: &CrossFolderDupe<'_>) -> (Guid, Guid, bool) {
+    (
+        dupe.local_node.guid.clone(),
+        dupe.remote_node.guid.clone(),
+        dupe.merged,
+    )
+}
+
+/// Rebuilds a saved cross-folder dupe from the trees.
+pub(crate) fn restore_cross_folder_dupe<'t>(
+    (local_guid, remote_guid, merged): &(Guid, Guid, bool),
+    local_tree: &'t Tree,
+    remote_tree: &'t Tree,
+) -> Option<CrossFolderDupe<'t>> {
+    Some(CrossFolderDupe {
+        local_node: local_tree.node_for_guid(local_guid)?,
+        remote_node: remote_tree.node_for_guid(remote_guid)?,
+        merged: *merged,
+    })
+}
+
+/// A 64-bit FNV-1a hasher for tree fingerprints. Unlike `DefaultHasher`, it
+/// isn't randomly seeded, so equal trees always have the same fingerprint.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct FingerprintHasher(u64);
+
+impl Default for FingerprintHasher {
+    #[inline]
+    fn default() -> FingerprintHasher {
+        FingerprintHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for FingerprintHasher {
+    #[inline]
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    #[inline]
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
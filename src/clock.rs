@@ -0,0 +1,129 @@
+// Copyright 2018-2019 Mozilla
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{cmp::Ordering, collections::BTreeMap, fmt, iter::FromIterator};
+
+/// A version vector tracks causality between changes to an item, without
+/// relying on device clocks. It maps each device that changed the item to the
+/// number of changes that device has made.
+///
+/// A device increments its own counter every time it changes the item, and
+/// merges in the vectors of other devices' changes that it sees. If one
+/// vector has all counters greater than or equal to another, its change
+/// happened after the other; if neither does, the changes are concurrent.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct VersionVector(BTreeMap<String, u64>);
+
+impl VersionVector {
+    /// Creates an empty version vector.
+    #[inline]
+    pub fn new() -> VersionVector {
+        VersionVector::default()
+    }
+
+    /// Returns the number of changes made by a device.
+    #[inline]
+    pub fn get(&self, device: &str) -> u64 {
+        self.0.get(device).cloned().unwrap_or(0)
+    }
+
+    /// Records a change by a device, and returns the device's new counter.
+    pub fn increment(&mut self, device: &str) -> u64 {
+        let counter = self.0.entry(device.into()).or_insert(0);
+        *counter += 1;
+        *counter
+    }
+
+    /// Merges another vector into this one, by taking the maximum counter
+    /// for each device. The merged vector happened after both.
+    pub fn merge(&mut self, other: &VersionVector) {
+        for (device, &other_counter) in &other.0 {
+            let counter = self.0.entry(device.clone()).or_insert(0);
+            *counter = (*counter).max(other_counter);
+        }
+    }
+
+    /// Indicates if no device has changed the item.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.0.values().all(|&counter| counter == 0)
+    }
+
+    /// Returns the causal order of this vector and another.
+    pub fn causality(&self, other: &VersionVector) -> Causality {
+        let mut ordering = Ordering::Equal;
+        for device in self.0.keys().chain(other.0.keys()) {
+            let device_ordering = self.get(device).cmp(&other.get(device));
+            ordering = match (ordering, device_ordering) {
+                (ordering, Ordering::Equal) => ordering,
+                (Ordering::Equal, device_ordering) => device_ordering,
+                (ordering, device_ordering) if ordering == device_ordering => ordering,
+                _ => return Causality::Concurrent,
+            };
+        }
+        match ordering {
+            Ordering::Less => Causality::Before,
+            Ordering::Equal => Causality::Equal,
+            Ordering::Greater => Causality::After,
+        }
+    }
+}
+
+impl<S: Into<String>> FromIterator<(S, u64)> for VersionVector {
+    fn from_iter<I: IntoIterator<Item = (S, u64)>>(iter: I) -> VersionVector {
+        VersionVector(
+            iter.into_iter()
+                .map(|(device, counter)| (device.into(), counter))
+                .collect(),
+        )
+    }
+}
+
+impl fmt::Display for VersionVector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("{")?;
+        for (i, (device, counter)) in self.0.iter().enumerate() {
+            if i != 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}: {}", device, counter)?;
+        }
+        f.write_str("}")
+    }
+}
+
+/// The causal order of two changes, from the first change's point of view.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Causality {
+    /// Both changes have the same history.
+    Equal,
+    /// The first change happened before the second.
+    Before,
+    /// The first change happened after the second.
+    After,
+    /// Neither change saw the other.
+    Concurrent,
+}
+
+/// Controls how the merger orders local and remote changes to the same item.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ConflictOrdering {
+    /// Take the side with the newer modification time or younger age.
+    Time,
+    /// Order changes by their version vectors first. If one change happened
+    /// after the other, take it without asking the driver. Only concurrent
+    /// changes, or changes without version vectors, are resolved as
+    /// conflicts, and ordered by time as a tie-breaker.
+    Causal,
+}
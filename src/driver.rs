@@ -12,13 +12,15 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{fmt::Arguments, time::Duration};
+use std::{cell::Cell, fmt::Arguments, time::Duration};
 
 use log::{Level, LevelFilter, Log};
 
+use crate::clock::ConflictOrdering;
 use crate::error::{ErrorKind, Result};
 use crate::guid::Guid;
-use crate::merge::StructureCounts;
+use crate::matcher::{CrossFolderDedupe, DefaultDupeMatcher, DupeMatcher, NormalizingDupeMatcher};
+use crate::merge::{Conflict, Side, StructureCounts};
 use crate::tree::ProblemCounts;
 
 /// An abort signal is used to abort merging. Implementations of `AbortSignal`
@@ -28,7 +30,8 @@ use crate::tree::ProblemCounts;
 /// Since merging is synchronous, it's not possible to interrupt a merge from
 /// the same thread that started it. In practice, this means a signal will
 /// implement `Send` and `Sync`, too, so that another thread can set the
-/// aborted flag.
+/// aborted flag. `AtomicAbortSignal` does this, `DeadlineSignal` aborts after
+/// a time budget, and `AnySignal` and `AllSignal` combine signals.
 ///
 /// The name comes from the `AbortSignal` DOM API.
 pub trait AbortSignal {
@@ -55,16 +58,100 @@ impl AbortSignal for DefaultAbortSignal {
     }
 }
 
+/// Reports how far along the merger is, so that apps can show a progress bar
+/// for long merges.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Progress {
+    /// Building the merged tree. `merged_nodes` is the number of nodes merged
+    /// so far, and `local_nodes` and `remote_nodes` are the sizes of the local
+    /// and remote trees.
+    Merge {
+        merged_nodes: usize,
+        local_nodes: usize,
+        remote_nodes: usize,
+    },
+    /// Building completion ops. `visited_nodes` is the number of merged nodes
+    /// and tombstones visited so far, out of about `total_nodes`.
+    CompletionOps {
+        visited_nodes: usize,
+        total_nodes: usize,
+    },
+}
+
+impl Progress {
+    /// Returns an estimate of how much of the current step is done, between
+    /// 0 and 1. For merging, this compares the merged nodes to the size of
+    /// the larger tree, since most nodes exist on both sides.
+    pub fn fraction(&self) -> f64 {
+        let (done, total) = match *self {
+            Progress::Merge {
+                merged_nodes,
+                local_nodes,
+                remote_nodes,
+            } => (
+                merged_nodes,
+                // The merged node count doesn't include the root.
+                local_nodes.max(remote_nodes).saturating_sub(1),
+            ),
+            Progress::CompletionOps {
+                visited_nodes,
+                total_nodes,
+            } => (visited_nodes, total_nodes),
+        };
+        if total == 0 {
+            1.0
+        } else {
+            (done as f64 / total as f64).min(1.0)
+        }
+    }
+}
+
 /// A merge telemetry event.
 pub enum TelemetryEvent {
     FetchLocalTree(TreeStats),
     FetchRemoteTree(TreeStats),
     Merge(Duration, StructureCounts),
     Apply(Duration),
+    ClockSkew(ClockSkew),
+    MergePhase(PhaseStats),
+}
+
+/// A step of merging or applying a merged tree, for timing telemetry.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MergePhase {
+    /// Walking the local and remote trees to build the merged tree. This
+    /// includes the time for deduping.
+    TwoWayMerge,
+    /// Finding new local and remote items with matching content, summed over
+    /// all folders. This includes matching and indexing new bookmarks across
+    /// folders.
+    Dedupe,
+    /// Removing clashing keywords from the merged tree.
+    UniqueKeywords,
+    /// Propagating local and remote tombstones that weren't merged.
+    Deletions,
+    /// Checking that the merged tree mentions all local and remote items.
+    Completeness,
+    /// Building completion ops for the merged tree. Recorded when the store
+    /// asks the merged root for its completion ops.
+    CompletionOps,
+}
+
+/// Records the time taken by a merge phase, and the number of nodes and
+/// tombstones that it visited.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PhaseStats {
+    pub phase: MergePhase,
+    pub time: Duration,
+    pub nodes: usize,
 }
 
 /// Records the time taken to build a local or remote tree, number of items
 /// in the tree, and structure problem counts.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TreeStats {
     pub time: Duration,
     pub items: usize,
@@ -72,6 +159,23 @@ pub struct TreeStats {
     pub problems: ProblemCounts,
 }
 
+/// Records a conflict where the items' modification times and ages disagree
+/// about which side changed more recently. This usually means that the local
+/// clock was wrong when one of the trees was fetched, or that the local
+/// tree's clock offset is stale. The merger trusts the modification times.
+pub struct ClockSkew {
+    /// The GUID of the conflicting item.
+    pub guid: Guid,
+    /// The youngest local age, in milliseconds.
+    pub local_age: i64,
+    /// The youngest remote age, in milliseconds.
+    pub remote_age: i64,
+    /// The newest local modification time, on the server clock.
+    pub local_modified: i64,
+    /// The newest remote modification time, on the server clock.
+    pub remote_modified: i64,
+}
+
 /// A merge driver provides methods to customize merging behavior.
 pub trait Driver {
     /// Generates a new GUID for the given invalid GUID. This is used to fix up
@@ -79,11 +183,13 @@ pub trait Driver {
     ///
     /// The default implementation returns an error, forbidding invalid GUIDs.
     ///
-    /// Implementations of `Driver` can either use the `rand` and `base64`
-    /// crates to generate a new, random GUID (9 bytes, Base64url-encoded
-    /// without padding), or use an existing method like Desktop's
-    /// `nsINavHistoryService::MakeGuid`. Dogear doesn't generate new GUIDs
-    /// automatically to avoid depending on those crates.
+    /// Implementations of `Driver` can either generate a new, random GUID
+    /// (9 bytes, Base64url-encoded without padding), or use an existing
+    /// method like Desktop's `nsINavHistoryService::MakeGuid`. With the
+    /// `rand` feature, `Guid::random` generates random GUIDs, and
+    /// `RandomGuidDriver` uses it to replace invalid GUIDs. Dogear doesn't
+    /// generate new GUIDs by default, to avoid depending on `rand`. Tests
+    /// that need stable GUIDs can use `SeededGuidDriver` instead.
     ///
     /// Implementations can also return `Ok(invalid_guid.clone())` to pass
     /// through all invalid GUIDs, as the tests do.
@@ -91,6 +197,80 @@ pub trait Driver {
         Err(ErrorKind::InvalidGuid(invalid_guid.clone()).into())
     }
 
+    /// Resolves a conflict between local and remote changes to an item.
+    ///
+    /// The default implementation returns `None`, which tells the merger to
+    /// take the side with the newer change, based on the items' modification
+    /// times or ages.
+    ///
+    /// Implementations can override this method to let the user choose which
+    /// side to keep, either by asking them during the merge, or by returning
+    /// choices that they made after a previous merge, using the conflicts from
+    /// `MergedRoot::conflicts`. The merger still checks that the merged tree
+    /// mentions all local and remote items, no matter which sides the driver
+    /// picks.
+    fn resolve_conflict(&self, _: &Conflict<'_>) -> Option<Side> {
+        None
+    }
+
+    /// Returns how the merger orders local and remote changes to the same
+    /// item.
+    ///
+    /// The default implementation returns `ConflictOrdering::Time`, which
+    /// treats every change on both sides as a conflict. Implementations that
+    /// store version vectors for their items can return
+    /// `ConflictOrdering::Causal`, so that device clocks only break ties
+    /// between concurrent changes.
+    fn conflict_ordering(&self) -> ConflictOrdering {
+        ConflictOrdering::Time
+    }
+
+    /// Indicates if the default dupe matcher should normalize content before
+    /// matching dupes.
+    ///
+    /// The default implementation returns `false`, so only items with
+    /// identical content are deduped.
+    ///
+    /// Implementations can return `true` to dedupe bookmarks whose URLs
+    /// differ only in scheme, case, trailing slashes, default ports, or
+    /// tracking parameters, and items whose titles differ only in leading or
+    /// trailing whitespace. See `normalize_url` for the exact rules.
+    fn normalize_dupes(&self) -> bool {
+        false
+    }
+
+    /// Returns the matcher for deduping new local and remote items.
+    ///
+    /// The default implementation returns a `NormalizingDupeMatcher` if
+    /// `normalize_dupes` returns `true`, or a `DefaultDupeMatcher` otherwise.
+    /// Implementations can override this method to provide their own rules;
+    /// for example, to exclude separators from deduping.
+    fn dupe_matcher(&self) -> &dyn DupeMatcher {
+        if self.normalize_dupes() {
+            &NormalizingDupeMatcher
+        } else {
+            &DefaultDupeMatcher
+        }
+    }
+
+    /// Indicates if the merger should look for new bookmarks with matching
+    /// content in different folders, after it fails to find a match in the
+    /// same folder.
+    ///
+    /// The default implementation returns `CrossFolderDedupe::Off`. Matching
+    /// uses the keys from `dupe_matcher`, and only considers bookmarks with
+    /// keys that don't depend on their positions.
+    fn cross_folder_dedupe(&self) -> CrossFolderDedupe {
+        CrossFolderDedupe::Off
+    }
+
+    /// Reports merge progress. The merger calls this method every time it
+    /// checks the abort signal, while merging the trees and building
+    /// completion ops, so implementations should throttle expensive updates.
+    ///
+    /// The default implementation is a no-op.
+    fn report_progress(&self, _: Progress) {}
+
     /// Returns the maximum log level for merge messages. The default
     /// implementation returns the `log` crate's global maximum level.
     fn max_log_level(&self) -> LevelFilter {
@@ -112,7 +292,8 @@ pub trait Driver {
     ///
     /// The default implementation is a no-op that discards the event.
     /// Implementations can override this method to capture event and bookmark
-    /// validation telemetry.
+    /// validation telemetry, and collect the events for each sync into a
+    /// `SyncReport`.
     fn record_telemetry_event(&self, _: TelemetryEvent) {}
 }
 
@@ -121,6 +302,58 @@ pub struct DefaultDriver;
 
 impl Driver for DefaultDriver {}
 
+/// A merge driver that replaces invalid GUIDs with new, random GUIDs. All
+/// other methods use the default implementations.
+#[cfg(feature = "rand")]
+pub struct RandomGuidDriver;
+
+#[cfg(feature = "rand")]
+impl Driver for RandomGuidDriver {
+    #[inline]
+    fn generate_new_guid(&self, _: &Guid) -> Result<Guid> {
+        Ok(Guid::random())
+    }
+}
+
+/// A merge driver that replaces invalid GUIDs with a reproducible sequence
+/// of valid GUIDs, generated from a seed. Two drivers with the same seed
+/// generate the same GUIDs in the same order, so merged trees and completion
+/// ops with fixed-up GUIDs are stable between runs. This is meant for tests;
+/// the GUIDs are predictable, so real syncs should use random GUIDs.
+#[derive(Debug)]
+pub struct SeededGuidDriver {
+    state: Cell<u64>,
+}
+
+impl SeededGuidDriver {
+    /// Creates a driver that generates GUIDs from the given seed.
+    #[inline]
+    pub fn new(seed: u64) -> SeededGuidDriver {
+        SeededGuidDriver {
+            state: Cell::new(seed),
+        }
+    }
+
+    /// Returns the next 64 pseudorandom bits, using SplitMix64.
+    fn next_u64(&self) -> u64 {
+        let state = self.state.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+        self.state.set(state);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl Driver for SeededGuidDriver {
+    fn generate_new_guid(&self, _: &Guid) -> Result<Guid> {
+        let mut bytes = [0u8; 9];
+        bytes[..8].copy_from_slice(&self.next_u64().to_le_bytes());
+        bytes[8] = self.next_u64() as u8;
+        Ok(Guid::from_random_bytes(&bytes))
+    }
+}
+
 /// Logs a merge message.
 pub fn log<D: Driver>(
     driver: &D,
@@ -210,3 +443,43 @@ macro_rules! trace {
         }
     }
 }
+
+/// Exits a `tracing` span when dropped. Without the `tracing` feature, this
+/// holds nothing.
+#[must_use]
+pub(crate) struct SpanGuard {
+    #[cfg(feature = "tracing")]
+    pub _entered: tracing::span::EnteredSpan,
+}
+
+/// Enters a `tracing` span with the given name and fields, and returns a
+/// `SpanGuard` for it. Without the `tracing` feature, this does nothing.
+#[cfg(feature = "tracing")]
+macro_rules! enter_span {
+    ($name:expr $(, $($fields:tt)+)?) => {
+        $crate::driver::SpanGuard {
+            _entered: tracing::info_span!($name $(, $($fields)+)?).entered(),
+        }
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! enter_span {
+    ($($args:tt)+) => {
+        $crate::driver::SpanGuard {}
+    };
+}
+
+/// Emits a `tracing` event at the trace level, with the given fields. Without
+/// the `tracing` feature, this does nothing, and the fields aren't evaluated.
+#[cfg(feature = "tracing")]
+macro_rules! trace_event {
+    ($($args:tt)+) => {
+        tracing::trace!($($args)+)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace_event {
+    ($($args:tt)+) => {};
+}
@@ -19,8 +19,9 @@ use crate::Item;
 
 pub type Result<T> = result::Result<T, Error>;
 
+// The kind is boxed to keep `Result`s small, since some kinds hold items.
 #[derive(Debug)]
-pub struct Error(ErrorKind);
+pub struct Error(Box<ErrorKind>);
 
 impl Error {
     pub fn kind(&self) -> &ErrorKind {
@@ -39,19 +40,19 @@ impl error::Error for Error {
 
 impl From<ErrorKind> for Error {
     fn from(kind: ErrorKind) -> Error {
-        Error(kind)
+        Error(Box::new(kind))
     }
 }
 
 impl From<FromUtf16Error> for Error {
     fn from(error: FromUtf16Error) -> Error {
-        Error(ErrorKind::MalformedString(error.into()))
+        ErrorKind::MalformedString(error.into()).into()
     }
 }
 
 impl From<Utf8Error> for Error {
     fn from(error: Utf8Error) -> Error {
-        Error(ErrorKind::MalformedString(error.into()))
+        ErrorKind::MalformedString(error.into()).into()
     }
 }
 
@@ -97,7 +98,17 @@ impl fmt::Display for Error {
                 parent_guid,
             ),
             ErrorKind::Cycle(guid) => write!(f, "Item {} can't contain itself", guid),
+            ErrorKind::ImmutableRoot(guid) => write!(f, "Can't change root {}", guid),
+            ErrorKind::MismatchedChildren(guid) => write!(
+                f,
+                "New order for folder {} doesn't match its children",
+                guid
+            ),
+            ErrorKind::UntitledItem(guid) => write!(f, "Item {} doesn't have a title", guid),
             ErrorKind::MergeConflict => write!(f, "Local tree changed during merge"),
+            ErrorKind::MismatchedRoots => {
+                write!(f, "Local and remote trees have different built-in roots")
+            }
             ErrorKind::UnmergedLocalItems => {
                 write!(f, "Merged tree doesn't mention all items from local tree")
             }
@@ -124,7 +135,11 @@ pub enum ErrorKind {
     MissingParentForUnknownChild(Guid, Guid),
     MissingItem(Guid),
     Cycle(Guid),
+    ImmutableRoot(Guid),
+    MismatchedChildren(Guid),
+    UntitledItem(Guid),
     MergeConflict,
+    MismatchedRoots,
     UnmergedLocalItems,
     UnmergedRemoteItems,
     InvalidGuid(Guid),
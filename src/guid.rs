@@ -14,11 +14,15 @@
 
 use std::{
     cmp::Ordering,
+    collections::HashMap,
     fmt,
     hash::{Hash, Hasher},
     ops, str,
+    sync::Arc,
 };
 
+use smallbitvec::SmallBitVec;
+
 use crate::error::{ErrorKind, Result};
 
 /// A GUID for an item in a bookmark tree.
@@ -33,11 +37,12 @@ pub trait IsValidGuid {
 /// The internal representation of a GUID. Valid GUIDs are 12 bytes, and contain
 /// only Base64url characters; we can store them on the stack without a heap
 /// allocation. However, both local and remote items might have invalid GUIDs,
-/// in which case we fall back to a heap-allocated string.
+/// in which case we fall back to a heap-allocated string. The string is
+/// reference-counted, so that cloning an invalid GUID doesn't reallocate it.
 #[derive(Clone)]
 enum Repr {
     Valid([u8; 12]),
-    Invalid(Box<str>),
+    Invalid(Arc<str>),
 }
 
 /// The Places root GUID, used to root all items in a bookmark tree.
@@ -69,7 +74,37 @@ const VALID_GUID_BYTES: [u8; 255] = [
     0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
 ];
 
+/// The Base64url alphabet, used to encode random GUIDs.
+const BASE64URL_CHARS: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
 impl Guid {
+    /// Returns a new random GUID: 9 random bytes, Base64url-encoded without
+    /// padding. The GUID is always 12 characters, and valid.
+    #[cfg(feature = "rand")]
+    pub fn random() -> Guid {
+        use rand::RngCore;
+
+        let mut random_bytes = [0u8; 9];
+        rand::thread_rng().fill_bytes(&mut random_bytes);
+        Guid::from_random_bytes(&random_bytes)
+    }
+
+    /// Encodes 9 random bytes as a valid GUID. Each 3-byte chunk encodes to
+    /// 4 Base64url characters, so 9 bytes fit exactly into 12 characters,
+    /// without padding.
+    pub(crate) fn from_random_bytes(random_bytes: &[u8; 9]) -> Guid {
+        let mut bytes = [0u8; 12];
+        for (chunk, encoded) in random_bytes.chunks(3).zip(bytes.chunks_mut(4)) {
+            let n = u32::from(chunk[0]) << 16 | u32::from(chunk[1]) << 8 | u32::from(chunk[2]);
+            for (index, byte) in encoded.iter_mut().enumerate() {
+                *byte = BASE64URL_CHARS[(n >> (18 - 6 * index) & 0x3f) as usize];
+            }
+        }
+        debug_assert!(bytes.is_valid_guid());
+        Guid(Repr::Valid(bytes))
+    }
+
     /// Converts a UTF-8 byte slice to a GUID.
     pub fn from_utf8(b: &[u8]) -> Result<Guid> {
         let repr = if b.is_valid_guid() {
@@ -90,7 +125,7 @@ impl Guid {
         let repr = if b.is_valid_guid() {
             let mut bytes = [0u8; 12];
             for (index, &byte) in b.iter().enumerate() {
-                if byte > u16::from(u8::max_value()) {
+                if byte > u16::from(u8::MAX) {
                     return Err(ErrorKind::InvalidByte(byte).into());
                 }
                 bytes[index] = byte as u8;
@@ -129,7 +164,8 @@ impl Guid {
     }
 
     /// Indicates if the GUID is one of the five Places built-in roots,
-    /// including the user content roots and the tags root.
+    /// including the user content roots and the tags root. Trees with other
+    /// roots should use `Roots::is_built_in_root` instead.
     #[inline]
     pub fn is_built_in_root(&self) -> bool {
         self == TOOLBAR_GUID
@@ -157,7 +193,7 @@ impl<T: Copy + Into<usize>> IsValidGuid for [T] {
         self.len() == 12
             && self
                 .iter()
-                .all(|&byte| VALID_GUID_BYTES.get(byte.into()).map_or(false, |&b| b == 1))
+                .all(|&byte| VALID_GUID_BYTES.get(byte.into()).is_some_and(|&b| b == 1))
     }
 }
 
@@ -240,7 +276,7 @@ impl PartialEq for Guid {
     }
 }
 
-impl<'a> PartialEq<Guid> for &'a Guid {
+impl PartialEq<Guid> for &Guid {
     #[inline]
     fn eq(&self, other: &Guid) -> bool {
         *self == other
@@ -268,6 +304,126 @@ impl fmt::Display for Guid {
     }
 }
 
+/// A compact index for a GUID, either in a `GuidTable` or in a `Tree`.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct GuidIndex(u32);
+
+impl GuidIndex {
+    /// Creates an index from a position in a table or tree.
+    pub(crate) fn new(index: usize) -> GuidIndex {
+        assert!(
+            index < u32::MAX as usize,
+            "Can't index more than {} GUIDs",
+            u32::MAX
+        );
+        GuidIndex(index as u32)
+    }
+
+    /// Returns the position for this index.
+    #[inline]
+    pub fn as_usize(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// Interns GUIDs, so that sets of GUIDs can be stored as compact bit vectors
+/// of `GuidIndex`es instead of hash sets of cloned GUIDs. The merger uses
+/// the local and remote trees' own indices for GUIDs that exist in either
+/// tree, and only interns tombstones and new GUIDs in a table.
+///
+/// Each interned GUID is stored once, and shared between the list and the
+/// index.
+#[derive(Debug, Default)]
+pub struct GuidTable {
+    guids: Vec<Arc<Guid>>,
+    index_by_guid: HashMap<Arc<Guid>, GuidIndex>,
+}
+
+impl GuidTable {
+    /// Creates an empty table.
+    #[inline]
+    pub fn new() -> GuidTable {
+        GuidTable::default()
+    }
+
+    /// Returns the index for a GUID, adding it to the table if it's not
+    /// already interned.
+    pub fn intern(&mut self, guid: &Guid) -> GuidIndex {
+        if let Some(&index) = self.index_by_guid.get(guid) {
+            return index;
+        }
+        let index = GuidIndex::new(self.guids.len());
+        let guid = Arc::new(guid.clone());
+        self.guids.push(Arc::clone(&guid));
+        self.index_by_guid.insert(guid, index);
+        index
+    }
+
+    /// Returns the index for an interned GUID, or `None` if the GUID isn't
+    /// in the table.
+    #[inline]
+    pub fn index(&self, guid: &Guid) -> Option<GuidIndex> {
+        self.index_by_guid.get(guid).cloned()
+    }
+
+    /// Returns the GUID for an index.
+    #[inline]
+    pub fn guid(&self, index: GuidIndex) -> &Guid {
+        &self.guids[index.as_usize()]
+    }
+
+    /// Returns the number of interned GUIDs.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.guids.len()
+    }
+
+    /// Indicates if the table is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.guids.is_empty()
+    }
+}
+
+/// A set of interned GUIDs, stored as a bit vector indexed by `GuidIndex`.
+#[derive(Clone, Debug, Default)]
+pub struct GuidSet(SmallBitVec);
+
+impl GuidSet {
+    /// Creates an empty set.
+    #[inline]
+    pub fn new() -> GuidSet {
+        GuidSet::default()
+    }
+
+    /// Adds an index to the set. Returns `true` if the index wasn't already
+    /// in the set.
+    pub fn insert(&mut self, index: GuidIndex) -> bool {
+        let index = index.as_usize();
+        if index >= self.0.len() {
+            self.0.resize(index + 1, false);
+        }
+        let inserted = !self.0[index];
+        self.0.set(index, true);
+        inserted
+    }
+
+    /// Indicates if the set contains an index.
+    #[inline]
+    pub fn contains(&self, index: GuidIndex) -> bool {
+        self.0.get(index.as_usize()).unwrap_or(false)
+    }
+
+    /// Returns an iterator for all indices in the set, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = GuidIndex> + '_ {
+        self.0
+            .iter()
+            .enumerate()
+            .filter(|(_, present)| *present)
+            .map(|(index, _)| GuidIndex(index as u32))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -298,4 +454,22 @@ mod tests {
             Guid::from_utf8(bytes).expect_err("Should not make GUID from invalid UTF-8");
         }
     }
+
+    #[test]
+    fn guid_table() {
+        let mut table = GuidTable::new();
+        let menu_index = table.intern(&MENU_GUID);
+        let invalid_index = table.intern(&"b@dgu1d!".into());
+        assert_eq!(table.intern(&MENU_GUID), menu_index);
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.guid(invalid_index), "b@dgu1d!");
+        assert_eq!(table.index(&TOOLBAR_GUID), None);
+
+        let mut set = GuidSet::new();
+        assert!(set.insert(invalid_index));
+        assert!(!set.insert(invalid_index));
+        assert!(set.contains(invalid_index));
+        assert!(!set.contains(menu_index));
+        assert_eq!(set.iter().collect::<Vec<_>>(), &[invalid_index]);
+    }
 }
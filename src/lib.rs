@@ -15,20 +15,42 @@
 #![allow(unknown_lints)]
 #![warn(rust_2018_idioms)]
 
+mod checkpoint;
+mod clock;
 #[macro_use]
 mod driver;
 mod error;
 mod guid;
+mod matcher;
 mod merge;
+mod normalize;
+mod preview;
+mod repair;
+mod roots;
+mod signal;
 mod store;
+mod telemetry;
 mod tree;
+mod undo;
+mod validation;
 
 #[cfg(test)]
 mod tests;
 
+pub use crate::checkpoint::*;
+pub use crate::clock::*;
 pub use crate::driver::*;
 pub use crate::error::*;
 pub use crate::guid::*;
+pub use crate::matcher::*;
 pub use crate::merge::*;
+pub use crate::normalize::*;
+pub use crate::preview::*;
+pub use crate::repair::*;
+pub use crate::roots::*;
+pub use crate::signal::*;
 pub use crate::store::*;
+pub use crate::telemetry::*;
 pub use crate::tree::*;
+pub use crate::undo::*;
+pub use crate::validation::*;
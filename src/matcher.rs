@@ -0,0 +1,121 @@
+// Copyright 2018-2019 Mozilla
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::borrow::Cow;
+
+use crate::normalize::normalize_content;
+use crate::tree::{Content, Node};
+
+/// A hash key used to match dupes by content. New local and remote items in
+/// matching folders with equal keys are deduped. The content is borrowed from
+/// the node, or owned if the matcher transforms it.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum DupeKey<'a> {
+    /// Matches a dupe by content only. Used for bookmarks, queries, folders,
+    /// and livemarks.
+    WithoutPosition(Cow<'a, Content>),
+    /// Matches a dupe by content and position. Used for separators.
+    WithPosition(Cow<'a, Content>, usize),
+}
+
+impl<'a> DupeKey<'a> {
+    /// Returns the default key for an item with the given content and
+    /// position in its parent. Separators match by position; all other items
+    /// match by content only. Bookmarks match by title and URL, ignoring
+    /// their keywords and descriptions.
+    pub fn new(content: Cow<'a, Content>, position: usize) -> DupeKey<'a> {
+        match content.as_ref() {
+            Content::Bookmark {
+                keyword: None,
+                description: None,
+                ..
+            }
+            | Content::Query { .. }
+            | Content::Folder { .. }
+            | Content::Livemark { .. } => DupeKey::WithoutPosition(content),
+            Content::Bookmark {
+                title, url_href, ..
+            } => DupeKey::WithoutPosition(Cow::Owned(Content::Bookmark {
+                title: title.clone(),
+                url_href: url_href.clone(),
+                keyword: None,
+                description: None,
+            })),
+            Content::Separator => DupeKey::WithPosition(content, position),
+        }
+    }
+}
+
+/// Controls deduping new local and remote bookmarks in different folders.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum CrossFolderDedupe {
+    /// Only dedupe items in matching folders.
+    Off,
+    /// Find bookmarks with matching content in different folders, and report
+    /// them in `MergedRoot::cross_folder_dupes`, but don't dedupe them.
+    Report,
+    /// Find and dedupe bookmarks with matching content in different folders.
+    /// The deduped bookmark stays in the folder where the merger first sees
+    /// it, and is moved on the other side.
+    Merge,
+}
+
+/// A dupe matcher decides which new local and remote items are duplicates of
+/// each other. The merger asks the driver for a matcher, and uses it to
+/// compute keys for all new children of a folder that exists on both sides.
+pub trait DupeMatcher {
+    /// Returns the key for matching an item with the given content, at the
+    /// given position in its local or remote parent. Returning `None` excludes
+    /// the item from deduping.
+    fn dupe_key<'t>(
+        &self,
+        parent_node: Node<'t>,
+        position: usize,
+        content: &'t Content,
+    ) -> Option<DupeKey<'t>>;
+}
+
+/// The default dupe matcher, which matches items with identical content.
+pub struct DefaultDupeMatcher;
+
+impl DupeMatcher for DefaultDupeMatcher {
+    #[inline]
+    fn dupe_key<'t>(
+        &self,
+        _: Node<'t>,
+        position: usize,
+        content: &'t Content,
+    ) -> Option<DupeKey<'t>> {
+        Some(DupeKey::new(Cow::Borrowed(content), position))
+    }
+}
+
+/// A fuzzy dupe matcher, which matches items with equivalent URLs and titles.
+/// See `normalize_content` for the rules.
+pub struct NormalizingDupeMatcher;
+
+impl DupeMatcher for NormalizingDupeMatcher {
+    #[inline]
+    fn dupe_key<'t>(
+        &self,
+        _: Node<'t>,
+        position: usize,
+        content: &'t Content,
+    ) -> Option<DupeKey<'t>> {
+        Some(DupeKey::new(
+            Cow::Owned(normalize_content(content)),
+            position,
+        ))
+    }
+}
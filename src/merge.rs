@@ -13,13 +13,27 @@
 // limitations under the License.
 
 use std::{
-    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    cmp::Ordering,
+    collections::{hash_map::Entry, BTreeSet, HashMap, HashSet, VecDeque},
     fmt, mem,
+    time::{Duration, Instant},
 };
 
-use crate::driver::{AbortSignal, DefaultAbortSignal, DefaultDriver, Driver};
+use crate::checkpoint::{
+    restore_cross_folder_dupe, save_cross_folder_dupe, MergeCheckpoint, SavedConflict, SavedDupes,
+    SavedMerge, SavedNode,
+};
+use crate::clock::{Causality, ConflictOrdering, VersionVector};
+use crate::driver::{
+    AbortSignal, ClockSkew, DefaultAbortSignal, DefaultDriver, Driver, MergePhase, PhaseStats,
+    Progress, TelemetryEvent,
+};
 use crate::error::{ErrorKind, Result};
-use crate::guid::{Guid, IsValidGuid, TAGS_GUID};
+use crate::guid::{Guid, GuidIndex, GuidSet, GuidTable, IsValidGuid};
+use crate::matcher::{CrossFolderDedupe, DupeKey};
+use crate::normalize::normalize_keyword;
+use crate::preview::MergePreview;
+use crate::roots::Roots;
 use crate::tree::{Content, MergeState, MergedNode, Node, Tree, Validity};
 
 /// Structure change types, used to indicate if a node on one side is moved
@@ -36,6 +50,7 @@ enum StructureChange {
 
 /// Records structure change counters for telemetry.
 #[derive(Clone, Copy, Default, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StructureCounts {
     /// Remote non-folder change wins over local deletion.
     pub remote_revives: usize,
@@ -47,6 +62,9 @@ pub struct StructureCounts {
     pub remote_deletes: usize,
     /// Deduped local items.
     pub dupes: usize,
+    /// New local items with content matches in different remote folders.
+    /// These are included in `dupes` if the merger deduped them.
+    pub cross_folder_dupes: usize,
     /// Total number of nodes in the merged tree, excluding the
     /// root.
     pub merged_nodes: usize,
@@ -56,6 +74,9 @@ pub struct StructureCounts {
 /// remote GUIDs).
 type MatchingDupes<'t> = (HashMap<Guid, Node<'t>>, HashMap<Guid, Node<'t>>);
 
+/// Holds candidates for cross-folder deduping, in tree order.
+type DupesByKey<'t> = HashMap<DupeKey<'t>, VecDeque<Node<'t>>>;
+
 /// Indicates which side to take in case of a merge conflict.
 #[derive(Clone, Copy, Debug)]
 enum ConflictResolution {
@@ -64,14 +85,282 @@ enum ConflictResolution {
     Unchanged,
 }
 
-/// A hash key used to match dupes by content.
+impl From<Side> for ConflictResolution {
+    fn from(side: Side) -> ConflictResolution {
+        match side {
+            Side::Local => ConflictResolution::Local,
+            Side::Remote => ConflictResolution::Remote,
+        }
+    }
+}
+
+/// Identifies one side of a merge.
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
-enum DupeKey<'a> {
-    /// Matches a dupe by content only. Used for bookmarks, queries, folders,
-    /// and livemarks.
-    WithoutPosition(&'a Content),
-    /// Matches a dupe by content and position. Used for separators.
-    WithPosition(&'a Content, usize),
+pub enum Side {
+    Local,
+    Remote,
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// A conflict between local and remote changes to the same item. The merger
+/// can't resolve a conflict without picking a side, so it asks the driver
+/// first, and falls back to the side with the newer change.
+#[derive(Clone, Copy, Debug)]
+pub enum Conflict<'t> {
+    /// The item changed on both sides. The chosen side decides which item to
+    /// keep, and which children to merge first.
+    Value {
+        local_node: Node<'t>,
+        remote_node: Node<'t>,
+    },
+
+    /// The item was moved to a different parent or position on both sides.
+    /// The chosen side decides where to keep the item.
+    Structure {
+        local_parent_node: Node<'t>,
+        local_node: Node<'t>,
+        remote_parent_node: Node<'t>,
+        remote_node: Node<'t>,
+    },
+}
+
+impl<'t> Conflict<'t> {
+    /// Returns the local node for the conflicting item.
+    #[inline]
+    pub fn local_node(&self) -> Node<'t> {
+        match self {
+            Conflict::Value { local_node, .. } | Conflict::Structure { local_node, .. } => {
+                *local_node
+            }
+        }
+    }
+
+    /// Returns the remote node for the conflicting item.
+    #[inline]
+    pub fn remote_node(&self) -> Node<'t> {
+        match self {
+            Conflict::Value { remote_node, .. } | Conflict::Structure { remote_node, .. } => {
+                *remote_node
+            }
+        }
+    }
+
+    /// Returns the side with the newer change, based on the items'
+    /// modification times if both sides have them, or their ages otherwise.
+    /// The merger takes this side if the driver doesn't pick one.
+    pub fn newer_side(&self) -> Side {
+        let (local_nodes, remote_nodes) = self.nodes();
+        if compare_changes(&local_nodes, &remote_nodes) == Ordering::Greater {
+            Side::Local
+        } else {
+            Side::Remote
+        }
+    }
+
+    /// Returns the causal order of the local and remote changes, or `None`
+    /// if any of the changed nodes don't have version vectors. For structure
+    /// conflicts, the child's and parent's versions are merged on each side.
+    pub fn causality(&self) -> Option<Causality> {
+        let (local_nodes, remote_nodes) = self.nodes();
+        let local_version = merged_version(&local_nodes)?;
+        let remote_version = merged_version(&remote_nodes)?;
+        Some(local_version.causality(&remote_version))
+    }
+
+    /// Returns the changed nodes on each side. For structure conflicts, these
+    /// are the child and its parent; for value conflicts, the item is
+    /// repeated, so that both sides have the same shape.
+    fn nodes(&self) -> ([Node<'t>; 2], [Node<'t>; 2]) {
+        match *self {
+            Conflict::Value {
+                local_node,
+                remote_node,
+            } => ([local_node; 2], [remote_node; 2]),
+            Conflict::Structure {
+                local_parent_node,
+                local_node,
+                remote_parent_node,
+                remote_node,
+            } => (
+                [local_node, local_parent_node],
+                [remote_node, remote_parent_node],
+            ),
+        }
+    }
+
+    /// Returns the clock skew for this conflict, if the items' modification
+    /// times and ages disagree about which side changed more recently.
+    fn clock_skew(&self) -> Option<ClockSkew> {
+        let (local_nodes, remote_nodes) = self.nodes();
+        let local_modified = last_modified(&local_nodes)?;
+        let remote_modified = last_modified(&remote_nodes)?;
+        let local_age = min_age(&local_nodes);
+        let remote_age = min_age(&remote_nodes);
+        match (
+            local_modified.cmp(&remote_modified),
+            remote_age.cmp(&local_age),
+        ) {
+            (Ordering::Greater, Ordering::Less) | (Ordering::Less, Ordering::Greater) => {
+                Some(ClockSkew {
+                    guid: self.local_node().guid.clone(),
+                    local_age,
+                    remote_age,
+                    local_modified,
+                    remote_modified,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Compares when two sets of nodes last changed, on a common clock. Returns
+/// `Ordering::Greater` if `nodes` changed more recently than `other_nodes`.
+/// If all nodes have modification times, we compare the newest times on the
+/// server clock; otherwise, we compare the youngest ages.
+fn compare_changes(nodes: &[Node<'_>], other_nodes: &[Node<'_>]) -> Ordering {
+    match (last_modified(nodes), last_modified(other_nodes)) {
+        (Some(modified), Some(other_modified)) => modified.cmp(&other_modified),
+        _ => min_age(other_nodes).cmp(&min_age(nodes)),
+    }
+}
+
+/// Returns the newest modification time for the nodes on the server clock, or
+/// `None` if any of the nodes don't have one.
+fn last_modified(nodes: &[Node<'_>]) -> Option<i64> {
+    nodes
+        .iter()
+        .map(Node::server_modified)
+        .try_fold(i64::MIN, |newest, modified| {
+            modified.map(|modified| newest.max(modified))
+        })
+}
+
+/// Returns the merged version vector for the nodes, or `None` if any of the
+/// nodes don't have one.
+fn merged_version(nodes: &[Node<'_>]) -> Option<VersionVector> {
+    nodes
+        .iter()
+        .try_fold(VersionVector::new(), |mut merged_version, node| {
+            merged_version.merge(node.version.as_ref()?);
+            Some(merged_version)
+        })
+}
+
+/// Returns the youngest age of the nodes.
+fn min_age(nodes: &[Node<'_>]) -> i64 {
+    nodes.iter().map(|node| node.age).min().unwrap_or(i64::MAX)
+}
+
+impl<'t> fmt::Display for Conflict<'t> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Conflict::Value {
+                local_node,
+                remote_node,
+            } => write!(
+                f,
+                "{} changed locally and remotely as {}",
+                local_node, remote_node
+            ),
+            Conflict::Structure {
+                local_parent_node,
+                local_node,
+                remote_parent_node,
+                ..
+            } => write!(
+                f,
+                "{} moved locally to {} and remotely to {}",
+                local_node, local_parent_node.guid, remote_parent_node.guid
+            ),
+        }
+    }
+}
+
+/// A conflict that the merger resolved, and the side that it took.
+#[derive(Clone, Copy, Debug)]
+pub struct ResolvedConflict<'t> {
+    pub conflict: Conflict<'t>,
+    pub side: Side,
+    /// Indicates if the driver picked the side. If `false`, the merger took
+    /// the side with the newer change.
+    pub by_driver: bool,
+}
+
+impl<'t> fmt::Display for ResolvedConflict<'t> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}; taking {}", self.conflict, self.side)
+    }
+}
+
+/// A new local bookmark with the same content as a new remote bookmark in a
+/// different folder.
+#[derive(Clone, Copy, Debug)]
+pub struct CrossFolderDupe<'t> {
+    pub local_node: Node<'t>,
+    pub remote_node: Node<'t>,
+    /// Indicates if the merger deduped the local and remote bookmarks, or
+    /// only reported them.
+    pub merged: bool,
+}
+
+impl<'t> fmt::Display for CrossFolderDupe<'t> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Local {} in {} matches remote {} in {}",
+            self.local_node.guid,
+            self.local_node
+                .parent()
+                .map_or("(none)", |node| node.item().guid.as_str()),
+            self.remote_node.guid,
+            self.remote_node
+                .parent()
+                .map_or("(none)", |node| node.item().guid.as_str()),
+        )?;
+        if self.merged {
+            write!(f, "; deduped")
+        } else {
+            write!(f, "; reported")
+        }
+    }
+}
+
+/// A keyword that points to different URLs in the merged tree. The merger keeps
+/// the keyword for the most recently changed bookmark, and removes it from
+/// bookmarks with other URLs.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct KeywordClash {
+    /// The normalized keyword.
+    pub keyword: String,
+    /// The URL that the keyword points to after merging.
+    pub url_href: String,
+    /// The GUID of the bookmark that kept the keyword.
+    pub kept_guid: Guid,
+    /// The GUIDs of bookmarks with different URLs, which lost the keyword.
+    pub removed_guids: Vec<Guid>,
+}
+
+impl fmt::Display for KeywordClash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Keyword {} points to {} for {}; removed from [{}]",
+            self.keyword,
+            self.url_href,
+            self.kept_guid,
+            self.removed_guids
+                .iter()
+                .map(Guid::as_str)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
 }
 
 /// A two-way merger that produces a complete merged tree from a complete local
@@ -102,10 +391,20 @@ pub struct Merger<'t, D = DefaultDriver, A = DefaultAbortSignal> {
     local_tree: &'t Tree,
     remote_tree: &'t Tree,
     matching_dupes_by_local_parent_guid: HashMap<Guid, MatchingDupes<'t>>,
-    merged_guids: HashSet<Guid>,
-    delete_locally: HashSet<Guid>,
-    delete_remotely: HashSet<Guid>,
+    guids: MergedGuids<'t>,
+    merged_guids: GuidSet,
+    delete_locally: GuidSet,
+    delete_remotely: GuidSet,
     structure_counts: StructureCounts,
+    conflicts: Vec<ResolvedConflict<'t>>,
+    structure_conflict_sides: HashMap<Guid, Side>,
+    local_dupes_by_key: Option<DupesByKey<'t>>,
+    remote_dupes_by_key: Option<DupesByKey<'t>>,
+    cross_folder_dupes: Vec<CrossFolderDupe<'t>>,
+    keyword_clashes: Vec<KeywordClash>,
+    dedupe_time: Duration,
+    dedupe_nodes: usize,
+    record_telemetry: bool,
 }
 
 impl<'t> Merger<'t, DefaultDriver, DefaultAbortSignal> {
@@ -117,10 +416,20 @@ impl<'t> Merger<'t, DefaultDriver, DefaultAbortSignal> {
             local_tree,
             remote_tree,
             matching_dupes_by_local_parent_guid: HashMap::new(),
-            merged_guids: HashSet::new(),
-            delete_locally: HashSet::new(),
-            delete_remotely: HashSet::new(),
+            guids: MergedGuids::new(local_tree, remote_tree),
+            merged_guids: GuidSet::new(),
+            delete_locally: GuidSet::new(),
+            delete_remotely: GuidSet::new(),
             structure_counts: StructureCounts::default(),
+            conflicts: Vec::new(),
+            structure_conflict_sides: HashMap::new(),
+            local_dupes_by_key: None,
+            remote_dupes_by_key: None,
+            cross_folder_dupes: Vec::new(),
+            keyword_clashes: Vec::new(),
+            dedupe_time: Duration::default(),
+            dedupe_nodes: 0,
+            record_telemetry: true,
         }
     }
 }
@@ -139,76 +448,542 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
             local_tree,
             remote_tree,
             matching_dupes_by_local_parent_guid: HashMap::new(),
-            merged_guids: HashSet::new(),
-            delete_locally: HashSet::new(),
-            delete_remotely: HashSet::new(),
+            guids: MergedGuids::new(local_tree, remote_tree),
+            merged_guids: GuidSet::new(),
+            delete_locally: GuidSet::new(),
+            delete_remotely: GuidSet::new(),
             structure_counts: StructureCounts::default(),
+            conflicts: Vec::new(),
+            structure_conflict_sides: HashMap::new(),
+            local_dupes_by_key: None,
+            remote_dupes_by_key: None,
+            cross_folder_dupes: Vec::new(),
+            keyword_clashes: Vec::new(),
+            dedupe_time: Duration::default(),
+            dedupe_nodes: 0,
+            record_telemetry: true,
         }
     }
 
+    /// Sets whether the merger records telemetry events with its driver.
+    /// Stores turn this off for merge previews, which aren't syncs.
+    pub(crate) fn record_telemetry(mut self, record_telemetry: bool) -> Self {
+        self.record_telemetry = record_telemetry;
+        self
+    }
+
+    /// Builds a merged tree from the local and remote trees, and summarizes
+    /// the changes that applying it would make, without applying them. Like
+    /// `Store::merge_preview_with_driver`, this doesn't record telemetry.
+    pub fn preview(self) -> Result<MergePreview> {
+        let signal = self.signal;
+        let merged_root = self.record_telemetry(false).merge()?;
+        MergePreview::with_signal(&merged_root, signal)
+    }
+
     /// Builds a merged tree from the local and remote trees.
     pub fn merge(mut self) -> Result<MergedRoot<'t>> {
+        self.check_roots()?;
+        let mut merged_root_node = self.merge_roots()?;
+        let mut phase = MergePhase::TwoWayMerge;
+        self.finish_merge(&mut merged_root_node, &mut phase)?;
+        Ok(self.into_merged_root(merged_root_node))
+    }
+
+    /// Builds a merged tree from the local and remote trees, resuming from a
+    /// checkpoint saved by an earlier attempt that aborted. If this attempt
+    /// aborts, too, the merger saves its progress to the checkpoint before
+    /// returning `ErrorKind::Abort`. The checkpoint is discarded if either
+    /// tree changed since it was saved, and cleared once the merge finishes.
+    ///
+    /// Progress is saved between phases, so an attempt that aborts during
+    /// the two-way merge only saves its dupes; see `MergeCheckpoint`.
+    ///
+    /// Checking the checkpoint hashes both trees, so this is slower than
+    /// `merge` for trees that merge quickly.
+    pub fn merge_with_checkpoint(
+        mut self,
+        checkpoint: &mut MergeCheckpoint,
+    ) -> Result<MergedRoot<'t>> {
+        self.check_roots()?;
+        let fingerprints = (
+            self.local_tree.fingerprint(),
+            self.remote_tree.fingerprint(),
+        );
+        let resumed = self.restore_checkpoint(checkpoint, fingerprints);
+        let (mut merged_root_node, mut phase) = match resumed {
+            Some(resumed) => resumed,
+            None => match self.merge_roots() {
+                Ok(merged_root_node) => (merged_root_node, MergePhase::TwoWayMerge),
+                Err(err) => {
+                    if let ErrorKind::Abort = err.kind() {
+                        self.save_checkpoint(checkpoint, fingerprints, None);
+                    }
+                    return Err(err);
+                }
+            },
+        };
+        match self.finish_merge(&mut merged_root_node, &mut phase) {
+            Ok(()) => {
+                checkpoint.clear();
+                Ok(self.into_merged_root(merged_root_node))
+            }
+            Err(err) => {
+                if let ErrorKind::Abort = err.kind() {
+                    let merged = Some((&merged_root_node, phase));
+                    self.save_checkpoint(checkpoint, fingerprints, merged);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn check_roots(&self) -> Result<()> {
+        // Both trees must agree on which roots are built in, so that we
+        // protect the same roots on both sides.
+        if self.local_tree.roots() != self.remote_tree.roots() {
+            return Err(ErrorKind::MismatchedRoots.into());
+        }
+        Ok(())
+    }
+
+    /// Walks the local and remote trees to build the merged tree.
+    fn merge_roots(&mut self) -> Result<MergedNode<'t>> {
+        let now = Instant::now();
         let merged_root_node = {
             let local_root_node = self.local_tree.root();
             let remote_root_node = self.remote_tree.root();
             self.two_way_merge(local_root_node, remote_root_node)?
         };
+        self.record_phase(
+            MergePhase::TwoWayMerge,
+            now.elapsed(),
+            self.structure_counts.merged_nodes,
+        );
+        self.record_phase(MergePhase::Dedupe, self.dedupe_time, self.dedupe_nodes);
+        Ok(merged_root_node)
+    }
 
-        // Any remaining deletions on one side should be deleted on the other side.
-        // This happens when the remote tree has tombstones for items that don't
-        // exist locally, or the local tree has tombstones for items that
-        // aren't on the server.
-        for guid in self.local_tree.deletions() {
-            self.signal.err_if_aborted()?;
-            if !self.mentions(guid) {
-                self.delete_remotely.insert(guid.clone());
-            }
+    /// Runs the merge phases after `phase`, the last one that finished, and
+    /// updates it as each phase finishes.
+    fn finish_merge(
+        &mut self,
+        merged_root_node: &mut MergedNode<'t>,
+        phase: &mut MergePhase,
+    ) -> Result<()> {
+        if *phase == MergePhase::TwoWayMerge {
+            // Keywords can only point to one URL, so we remove clashing keywords
+            // that were added on different sides, or to different bookmarks.
+            let now = Instant::now();
+            let visited_nodes = self.enforce_unique_keywords(merged_root_node)?;
+            self.record_phase(MergePhase::UniqueKeywords, now.elapsed(), visited_nodes);
+            *phase = MergePhase::UniqueKeywords;
         }
-        for guid in self.remote_tree.deletions() {
-            self.signal.err_if_aborted()?;
-            if !self.mentions(guid) {
-                self.delete_locally.insert(guid.clone());
+
+        if *phase == MergePhase::UniqueKeywords {
+            // Any remaining deletions on one side should be deleted on the other side.
+            // This happens when the remote tree has tombstones for items that don't
+            // exist locally, or the local tree has tombstones for items that
+            // aren't on the server.
+            let now = Instant::now();
+            for guid in self.local_tree.deletions() {
+                self.checkpoint()?;
+                if !self.mentions(guid) {
+                    self.insert_delete_remotely(guid);
+                }
+            }
+            for guid in self.remote_tree.deletions() {
+                self.checkpoint()?;
+                if !self.mentions(guid) {
+                    self.insert_delete_locally(guid);
+                }
             }
+            self.record_phase(
+                MergePhase::Deletions,
+                now.elapsed(),
+                self.local_tree.deletions().len() + self.remote_tree.deletions().len(),
+            );
+            *phase = MergePhase::Deletions;
         }
 
         // The merged tree should know about all items mentioned in the local
         // and remote trees. Otherwise, it's incomplete, and we can't apply it.
         // This indicates a bug in the merger.
+        let now = Instant::now();
         for guid in self.local_tree.guids() {
-            self.signal.err_if_aborted()?;
+            self.checkpoint()?;
             if !self.mentions(guid) {
                 return Err(ErrorKind::UnmergedLocalItems.into());
             }
         }
         for guid in self.remote_tree.guids() {
-            self.signal.err_if_aborted()?;
+            self.checkpoint()?;
             if !self.mentions(guid) {
                 return Err(ErrorKind::UnmergedRemoteItems.into());
             }
         }
+        self.record_phase(
+            MergePhase::Completeness,
+            now.elapsed(),
+            self.local_tree.size()
+                + self.local_tree.deletions().len()
+                + self.remote_tree.size()
+                + self.remote_tree.deletions().len(),
+        );
+        *phase = MergePhase::Completeness;
+
+        Ok(())
+    }
 
-        Ok(MergedRoot {
+    fn into_merged_root(self, merged_root_node: MergedNode<'t>) -> MergedRoot<'t> {
+        MergedRoot {
+            driver: self.driver,
+            record_telemetry: self.record_telemetry,
             local_tree: self.local_tree,
             remote_tree: self.remote_tree,
             node: merged_root_node,
-            merged_guids: self.merged_guids,
+            guids: self.guids,
             delete_locally: self.delete_locally,
             delete_remotely: self.delete_remotely,
             structure_counts: self.structure_counts,
-        })
+            conflicts: self.conflicts,
+            cross_folder_dupes: self.cross_folder_dupes,
+            keyword_clashes: self.keyword_clashes,
+        }
+    }
+
+    /// Saves the merger's progress to a checkpoint. `merged` is the merged
+    /// root and the last phase that finished, or `None` if the two-way merge
+    /// didn't finish. In that case, we only save the dupe cache.
+    fn save_checkpoint(
+        &self,
+        checkpoint: &mut MergeCheckpoint,
+        fingerprints: (u64, u64),
+        merged: Option<(&MergedNode<'t>, MergePhase)>,
+    ) {
+        checkpoint.fingerprints = Some(fingerprints);
+        checkpoint.dupes = self
+            .matching_dupes_by_local_parent_guid
+            .iter()
+            .map(|(local_parent_guid, (local_to_remote, _))| SavedDupes {
+                local_parent_guid: local_parent_guid.clone(),
+                matches: local_to_remote
+                    .iter()
+                    .map(|(local_guid, remote_node)| (local_guid.clone(), remote_node.guid.clone()))
+                    .collect(),
+            })
+            .collect();
+        checkpoint.merge = merged.map(|(merged_root_node, phase)| {
+            // Keyword clashes are only final once their phase finishes.
+            let has_keywords = phase != MergePhase::TwoWayMerge;
+            let guids = |set: &GuidSet| {
+                set.iter()
+                    .map(|index| self.guids.guid(index).clone())
+                    .collect()
+            };
+            SavedMerge {
+                phase,
+                root: SavedNode::new(merged_root_node, has_keywords),
+                merged_guids: guids(&self.merged_guids),
+                delete_locally: guids(&self.delete_locally),
+                delete_remotely: guids(&self.delete_remotely),
+                structure_counts: self.structure_counts,
+                conflicts: self.conflicts.iter().map(SavedConflict::new).collect(),
+                cross_folder_dupes: self
+                    .cross_folder_dupes
+                    .iter()
+                    .map(save_cross_folder_dupe)
+                    .collect(),
+                keyword_clashes: if has_keywords {
+                    self.keyword_clashes.clone()
+                } else {
+                    Vec::new()
+                },
+                dedupe_time: self.dedupe_time,
+                dedupe_nodes: self.dedupe_nodes,
+            }
+        });
+        debug!(
+            self.driver,
+            "Saved merge checkpoint after {:?}",
+            checkpoint.phase()
+        );
+    }
+
+    /// Restores the merger's progress from a checkpoint, and returns the
+    /// merged root and the last phase that finished, or `None` if the
+    /// two-way merge must run again. Discards the checkpoint if it was saved
+    /// for different trees.
+    fn restore_checkpoint(
+        &mut self,
+        checkpoint: &mut MergeCheckpoint,
+        fingerprints: (u64, u64),
+    ) -> Option<(MergedNode<'t>, MergePhase)> {
+        if checkpoint.is_empty() {
+            return None;
+        }
+        if checkpoint.fingerprints != Some(fingerprints) {
+            warn!(
+                self.driver,
+                "Trees changed since merge was checkpointed; starting over"
+            );
+            checkpoint.clear();
+            return None;
+        }
+        let (local_tree, remote_tree) = (self.local_tree, self.remote_tree);
+
+        // Rebuild everything before changing the merger, so that we can start
+        // over if the trees don't have a node that the checkpoint refers to.
+        let matching_dupes = checkpoint
+            .dupes
+            .iter()
+            .map(|dupes| {
+                let mut local_to_remote = HashMap::new();
+                let mut remote_to_local = HashMap::new();
+                for (local_guid, remote_guid) in &dupes.matches {
+                    let local_node = local_tree.node_for_guid(local_guid)?;
+                    let remote_node = remote_tree.node_for_guid(remote_guid)?;
+                    local_to_remote.insert(local_guid.clone(), remote_node);
+                    remote_to_local.insert(remote_guid.clone(), local_node);
+                }
+                Some((
+                    dupes.local_parent_guid.clone(),
+                    (local_to_remote, remote_to_local),
+                ))
+            })
+            .collect::<Option<HashMap<_, _>>>();
+        let matching_dupes = match matching_dupes {
+            Some(matching_dupes) => matching_dupes,
+            None => {
+                warn!(self.driver, "Can't restore dupes from merge checkpoint");
+                checkpoint.clear();
+                return None;
+            }
+        };
+        self.matching_dupes_by_local_parent_guid = matching_dupes;
+
+        let saved = checkpoint.merge.as_ref()?;
+        let restored = saved
+            .root
+            .restore(local_tree, remote_tree)
+            .and_then(|root| {
+                let conflicts = saved
+                    .conflicts
+                    .iter()
+                    .map(|conflict| conflict.restore(local_tree, remote_tree))
+                    .collect::<Option<Vec<_>>>()?;
+                let cross_folder_dupes = saved
+                    .cross_folder_dupes
+                    .iter()
+                    .map(|dupe| restore_cross_folder_dupe(dupe, local_tree, remote_tree))
+                    .collect::<Option<Vec<_>>>()?;
+                Some((root, conflicts, cross_folder_dupes))
+            });
+        let (merged_root_node, conflicts, cross_folder_dupes) = match restored {
+            Some(restored) => restored,
+            None => {
+                warn!(
+                    self.driver,
+                    "Can't restore merged tree from merge checkpoint"
+                );
+                checkpoint.merge = None;
+                return None;
+            }
+        };
+        for guid in &saved.merged_guids {
+            self.insert_merged(guid);
+        }
+        for guid in &saved.delete_locally {
+            self.insert_delete_locally(guid);
+        }
+        for guid in &saved.delete_remotely {
+            self.insert_delete_remotely(guid);
+        }
+        self.structure_counts = saved.structure_counts;
+        self.structure_conflict_sides = conflicts
+            .iter()
+            .filter_map(|resolved| match resolved.conflict {
+                Conflict::Structure { local_node, .. } => {
+                    Some((local_node.guid.clone(), resolved.side))
+                }
+                Conflict::Value { .. } => None,
+            })
+            .collect();
+        self.conflicts = conflicts;
+        self.cross_folder_dupes = cross_folder_dupes;
+        self.keyword_clashes = saved.keyword_clashes.clone();
+        self.dedupe_time = saved.dedupe_time;
+        self.dedupe_nodes = saved.dedupe_nodes;
+        debug!(
+            self.driver,
+            "Resuming merge from checkpoint after {:?}", saved.phase
+        );
+        Some((merged_root_node, saved.phase))
+    }
+
+    /// Finds bookmarks in the merged tree with the same keyword and different
+    /// URLs, and removes the keyword from all but the most recently changed
+    /// bookmark and its URL. Bookmarks that lose their keywords are flagged
+    /// for reupload.
+    ///
+    /// Returns the number of merged nodes visited.
+    fn enforce_unique_keywords(&mut self, merged_root_node: &mut MergedNode<'t>) -> Result<usize> {
+        // Bookmarks with each keyword, in merged tree order. We store the
+        // node for the preferred side to find the most recent bookmark.
+        let mut bookmarks_by_keyword: HashMap<String, Vec<(&Guid, &str, Node<'t>)>> =
+            HashMap::new();
+        let mut visited_nodes = 0;
+        let mut stack = vec![&*merged_root_node];
+        while let Some(merged_node) = stack.pop() {
+            self.checkpoint()?;
+            visited_nodes += 1;
+            if let Some(Content::Bookmark {
+                url_href,
+                keyword: Some(keyword),
+                ..
+            }) = merged_node.content()
+            {
+                bookmarks_by_keyword
+                    .entry(normalize_keyword(keyword))
+                    .or_default()
+                    .push((&merged_node.guid, url_href, *merged_node.merge_state.node()));
+            }
+            stack.extend(merged_node.merged_children.iter().rev());
+        }
+
+        let mut clashing_guids = HashSet::new();
+        for (keyword, bookmarks) in bookmarks_by_keyword {
+            self.checkpoint()?;
+            let (kept_guid, kept_url_href, _) = bookmarks
+                .iter()
+                .min_by(|(_, _, node), (_, _, other_node)| {
+                    compare_changes(&[*other_node], &[*node])
+                })
+                .expect("Keywords should have at least one bookmark");
+            let removed_guids = bookmarks
+                .iter()
+                .filter(|(_, url_href, _)| url_href != kept_url_href)
+                .map(|(guid, _, _)| (*guid).clone())
+                .collect::<Vec<_>>();
+            if removed_guids.is_empty() {
+                continue;
+            }
+            warn!(
+                self.driver,
+                "Keyword {} points to different URLs; keeping {} for {}",
+                keyword,
+                kept_url_href,
+                kept_guid
+            );
+            clashing_guids.extend(removed_guids.iter().cloned());
+            self.keyword_clashes.push(KeywordClash {
+                keyword,
+                url_href: (*kept_url_href).into(),
+                kept_guid: (*kept_guid).clone(),
+                removed_guids,
+            });
+        }
+        if clashing_guids.is_empty() {
+            return Ok(visited_nodes);
+        }
+        self.keyword_clashes
+            .sort_by(|a, b| a.keyword.cmp(&b.keyword));
+
+        let mut stack = vec![merged_root_node];
+        while let Some(merged_node) = stack.pop() {
+            self.checkpoint()?;
+            if clashing_guids.contains(&merged_node.guid) {
+                if let Some(Content::Bookmark {
+                    title,
+                    url_href,
+                    description,
+                    ..
+                }) = merged_node.content()
+                {
+                    merged_node.merged_content = Some(Content::Bookmark {
+                        title: title.clone(),
+                        url_href: url_href.clone(),
+                        keyword: None,
+                        description: description.clone(),
+                    });
+                }
+                merged_node.needs_reupload = true;
+            }
+            stack.extend(merged_node.merged_children.iter_mut());
+        }
+        Ok(visited_nodes)
+    }
+
+    /// Reports merge progress to the driver, and returns an error if the
+    /// caller signaled to abort.
+    #[inline]
+    fn checkpoint(&self) -> Result<()> {
+        self.driver.report_progress(Progress::Merge {
+            merged_nodes: self.structure_counts.merged_nodes,
+            local_nodes: self.local_tree.size(),
+            remote_nodes: self.remote_tree.size(),
+        });
+        self.signal.err_if_aborted()
+    }
+
+    /// Records a telemetry event for a merge phase.
+    fn record_phase(&self, phase: MergePhase, time: Duration, nodes: usize) {
+        self.record_telemetry_event(TelemetryEvent::MergePhase(PhaseStats {
+            phase,
+            time,
+            nodes,
+        }));
+    }
+
+    /// Records a telemetry event with the driver, unless telemetry is off.
+    #[inline]
+    fn record_telemetry_event(&self, event: TelemetryEvent) {
+        if self.record_telemetry {
+            self.driver.record_telemetry_event(event);
+        }
     }
 
     #[inline]
     fn mentions(&self, guid: &Guid) -> bool {
-        self.merged_guids.contains(guid)
-            || self.delete_locally.contains(guid)
-            || self.delete_remotely.contains(guid)
+        self.guids.index(guid).is_some_and(|index| {
+            self.merged_guids.contains(index)
+                || self.delete_locally.contains(index)
+                || self.delete_remotely.contains(index)
+        })
+    }
+
+    /// Indicates if the GUID is in the merged tree.
+    #[inline]
+    fn is_merged(&self, guid: &Guid) -> bool {
+        self.guids
+            .index(guid)
+            .is_some_and(|index| self.merged_guids.contains(index))
+    }
+
+    #[inline]
+    fn insert_merged(&mut self, guid: &Guid) {
+        let index = self.guids.intern(guid);
+        self.merged_guids.insert(index);
+    }
+
+    #[inline]
+    fn insert_delete_locally(&mut self, guid: &Guid) {
+        trace_event!(guid = %guid, "Deleting item locally");
+        let index = self.guids.intern(guid);
+        self.delete_locally.insert(index);
+    }
+
+    #[inline]
+    fn insert_delete_remotely(&mut self, guid: &Guid) {
+        trace_event!(guid = %guid, "Deleting item remotely");
+        let index = self.guids.intern(guid);
+        self.delete_remotely.insert(index);
     }
 
     fn merge_local_only_node(&mut self, local_node: Node<'t>) -> Result<MergedNode<'t>> {
         trace!(self.driver, "Item {} only exists locally", local_node);
 
-        self.merged_guids.insert(local_node.guid.clone());
+        self.insert_merged(&local_node.guid);
 
         let merged_guid = if local_node.guid.is_valid_guid() {
             local_node.guid.clone()
@@ -217,13 +992,13 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
                 self.driver,
                 "Generating new GUID for local node {}", local_node
             );
-            self.signal.err_if_aborted()?;
+            self.checkpoint()?;
             let new_guid = self.driver.generate_new_guid(&local_node.guid)?;
             if new_guid != local_node.guid {
-                if self.merged_guids.contains(&new_guid) {
+                if self.is_merged(&new_guid) {
                     return Err(ErrorKind::DuplicateItem(new_guid).into());
                 }
-                self.merged_guids.insert(new_guid.clone());
+                self.insert_merged(&new_guid);
             }
             new_guid
         };
@@ -233,13 +1008,14 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
         // we still need to recursively walk and merge them. This method will
         // change the merge state from local to new if any children were moved
         // or deleted.
-        for local_child_node in local_node.children() {
-            self.signal.err_if_aborted()?;
+        for (position, local_child_node) in local_node.children().enumerate() {
+            self.checkpoint()?;
             self.merge_local_child_into_merged_node(
                 &mut merged_node,
                 local_node,
                 None,
                 local_child_node,
+                position,
             )?;
         }
 
@@ -247,13 +1023,19 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
             merged_node.merge_state = merged_node.merge_state.with_new_local_structure();
         }
 
+        trace_event!(
+            guid = %merged_node.guid,
+            local_guid = %local_node.guid,
+            state = %merged_node.merge_state,
+            "Merged local-only node"
+        );
         Ok(merged_node)
     }
 
     fn merge_remote_only_node(&mut self, remote_node: Node<'t>) -> Result<MergedNode<'t>> {
         trace!(self.driver, "Item {} only exists remotely", remote_node);
 
-        self.merged_guids.insert(remote_node.guid.clone());
+        self.insert_merged(&remote_node.guid);
 
         let merged_guid = if remote_node.guid.is_valid_guid() {
             remote_node.guid.clone()
@@ -262,15 +1044,15 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
                 self.driver,
                 "Generating new GUID for remote node {}", remote_node
             );
-            self.signal.err_if_aborted()?;
+            self.checkpoint()?;
             let new_guid = self.driver.generate_new_guid(&remote_node.guid)?;
             if new_guid != remote_node.guid {
-                if self.merged_guids.contains(&new_guid) {
+                if self.is_merged(&new_guid) {
                     return Err(ErrorKind::DuplicateItem(new_guid).into());
                 }
-                self.merged_guids.insert(new_guid.clone());
+                self.insert_merged(&new_guid);
                 // Upload tombstones for changed remote GUIDs.
-                self.delete_remotely.insert(remote_node.guid.clone());
+                self.insert_delete_remotely(&remote_node.guid);
             }
             new_guid
         };
@@ -278,13 +1060,14 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
         // As above, a remote folder's children might still exist locally, so we
         // need to merge them and update the merge state from remote to new if
         // any children were moved or deleted.
-        for remote_child_node in remote_node.children() {
-            self.signal.err_if_aborted()?;
+        for (position, remote_child_node) in remote_node.children().enumerate() {
+            self.checkpoint()?;
             self.merge_remote_child_into_merged_node(
                 &mut merged_node,
                 None,
                 remote_node,
                 remote_child_node,
+                position,
             )?;
         }
 
@@ -297,6 +1080,12 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
             merged_node.merge_state = merged_node.merge_state.with_new_remote_structure();
         }
 
+        trace_event!(
+            guid = %merged_node.guid,
+            remote_guid = %remote_node.guid,
+            state = %merged_node.merge_state,
+            "Merged remote-only node"
+        );
         Ok(merged_node)
     }
 
@@ -321,8 +1110,8 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
             return Err(ErrorKind::MismatchedItemKind(local_node.item().clone(), remote_node.item().clone()).into());
         }
 
-        self.merged_guids.insert(local_node.guid.clone());
-        self.merged_guids.insert(remote_node.guid.clone());
+        self.insert_merged(&local_node.guid);
+        self.insert_merged(&remote_node.guid);
 
         let merged_guid = if remote_node.guid.is_valid_guid() {
             remote_node.guid.clone()
@@ -331,15 +1120,15 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
                 self.driver,
                 "Generating new valid GUID for node {}", remote_node
             );
-            self.signal.err_if_aborted()?;
+            self.checkpoint()?;
             let new_guid = self.driver.generate_new_guid(&remote_node.guid)?;
             if new_guid != remote_node.guid {
-                if self.merged_guids.contains(&new_guid) {
+                if self.is_merged(&new_guid) {
                     return Err(ErrorKind::DuplicateItem(new_guid).into());
                 }
-                self.merged_guids.insert(new_guid.clone());
+                self.insert_merged(&new_guid);
                 // Upload tombstones for changed remote GUIDs.
-                self.delete_remotely.insert(remote_node.guid.clone());
+                self.insert_delete_remotely(&remote_node.guid);
             }
             new_guid
         };
@@ -364,45 +1153,71 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
             },
         );
 
+        if local_node.needs_merge && remote_node.needs_merge && local_node.tags != remote_node.tags
+        {
+            // Tags are sets, so we can merge them without picking a side. If
+            // the union has tags that the server doesn't know about, we need
+            // to reupload the item, even if we took the remote side.
+            let merged_tags = local_node
+                .tags
+                .union(&remote_node.tags)
+                .cloned()
+                .collect::<BTreeSet<_>>();
+            trace!(
+                self.driver,
+                "Merging local tags for {} with remote tags for {}",
+                local_node,
+                remote_node
+            );
+            if merged_tags != remote_node.tags {
+                merged_node.needs_reupload = true;
+            }
+            merged_node.merged_tags = Some(merged_tags);
+        }
+
         match children {
             ConflictResolution::Local => {
-                for local_child_node in local_node.children() {
-                    self.signal.err_if_aborted()?;
+                for (position, local_child_node) in local_node.children().enumerate() {
+                    self.checkpoint()?;
                     self.merge_local_child_into_merged_node(
                         &mut merged_node,
                         local_node,
                         Some(remote_node),
                         local_child_node,
+                        position,
                     )?;
                 }
-                for remote_child_node in remote_node.children() {
-                    self.signal.err_if_aborted()?;
+                for (position, remote_child_node) in remote_node.children().enumerate() {
+                    self.checkpoint()?;
                     self.merge_remote_child_into_merged_node(
                         &mut merged_node,
                         Some(local_node),
                         remote_node,
                         remote_child_node,
+                        position,
                     )?;
                 }
             }
 
             ConflictResolution::Remote => {
-                for remote_child_node in remote_node.children() {
-                    self.signal.err_if_aborted()?;
+                for (position, remote_child_node) in remote_node.children().enumerate() {
+                    self.checkpoint()?;
                     self.merge_remote_child_into_merged_node(
                         &mut merged_node,
                         Some(local_node),
                         remote_node,
                         remote_child_node,
+                        position,
                     )?;
                 }
-                for local_child_node in local_node.children() {
-                    self.signal.err_if_aborted()?;
+                for (position, local_child_node) in local_node.children().enumerate() {
+                    self.checkpoint()?;
                     self.merge_local_child_into_merged_node(
                         &mut merged_node,
                         local_node,
                         Some(remote_node),
                         local_child_node,
+                        position,
                     )?;
                 }
             }
@@ -412,7 +1227,7 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
                 for (local_child_node, remote_child_node) in
                     local_node.children().zip(remote_node.children())
                 {
-                    self.signal.err_if_aborted()?;
+                    self.checkpoint()?;
                     self.merge_unchanged_child_into_merged_node(
                         &mut merged_node,
                         local_node,
@@ -433,6 +1248,13 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
             merged_node.merge_state = merged_node.merge_state.with_new_remote_structure();
         }
 
+        trace_event!(
+            guid = %merged_node.guid,
+            local_guid = %local_node.guid,
+            remote_guid = %remote_node.guid,
+            state = %merged_node.merge_state,
+            "Merged node"
+        );
         Ok(merged_node)
     }
 
@@ -449,11 +1271,11 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
         remote_child_node: Node<'t>,
     ) -> Result<()> {
         assert!(
-            !self.merged_guids.contains(&local_child_node.guid),
+            !self.is_merged(&local_child_node.guid),
             "Unchanged local child shouldn't have been merged"
         );
         assert!(
-            !self.merged_guids.contains(&remote_child_node.guid),
+            !self.is_merged(&remote_child_node.guid),
             "Unchanged remote child shouldn't have been merged"
         );
 
@@ -538,8 +1360,9 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
         local_parent_node: Option<Node<'t>>,
         remote_parent_node: Node<'t>,
         remote_child_node: Node<'t>,
+        remote_position: usize,
     ) -> Result<()> {
-        if self.merged_guids.contains(&remote_child_node.guid) {
+        if self.is_merged(&remote_child_node.guid) {
             trace!(
                 self.driver,
                 "Remote child {} already seen in another folder and merged",
@@ -712,17 +1535,26 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
             remote_child_node
         );
 
-        let mut merged_child_node = if let Some(local_child_node_by_content) = self
-            .find_local_node_matching_remote_node(
-                merged_node,
-                local_parent_node,
+        let local_child_node_by_content = match self.find_local_node_matching_remote_node(
+            merged_node,
+            local_parent_node,
+            remote_parent_node,
+            remote_child_node,
+        )? {
+            Some(local_child_node) => Some(local_child_node),
+            None => self.find_dupe_in_any_folder(
+                Side::Remote,
                 remote_parent_node,
+                remote_position,
                 remote_child_node,
-            )? {
-            self.two_way_merge(local_child_node_by_content, remote_child_node)
-        } else {
-            self.merge_remote_only_node(remote_child_node)
-        }?;
+            )?,
+        };
+        let mut merged_child_node =
+            if let Some(local_child_node_by_content) = local_child_node_by_content {
+                self.two_way_merge(local_child_node_by_content, remote_child_node)
+            } else {
+                self.merge_remote_only_node(remote_child_node)
+            }?;
         if merged_child_node.local_guid_changed() {
             merged_child_node.merge_state =
                 merged_child_node.merge_state.with_new_local_structure();
@@ -749,8 +1581,9 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
         local_parent_node: Node<'t>,
         remote_parent_node: Option<Node<'t>>,
         local_child_node: Node<'t>,
+        local_position: usize,
     ) -> Result<()> {
-        if self.merged_guids.contains(&local_child_node.guid) {
+        if self.is_merged(&local_child_node.guid) {
             // We already merged the child when we walked another folder. Since
             // a tree can't have duplicate GUIDs, we must have merged the remote
             // child, so we have a new local structure.
@@ -942,43 +1775,63 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
             local_child_node
         );
 
-        let merged_child_node = if let Some(remote_child_node_by_content) = self
+        let (remote_child_node_by_content, in_other_folder) = match self
             .find_remote_node_matching_local_node(
                 merged_node,
                 local_parent_node,
                 remote_parent_node,
                 local_child_node,
             )? {
-            // The local child has a remote content match, so take the remote GUID
-            // and merge.
-            let mut merged_child_node =
-                self.two_way_merge(local_child_node, remote_child_node_by_content)?;
-            if merged_child_node.local_guid_changed() {
-                merged_child_node.merge_state =
-                    merged_child_node.merge_state.with_new_local_structure();
-            }
-            if merged_node.remote_guid_changed() {
-                merged_child_node.merge_state =
-                    merged_child_node.merge_state.with_new_remote_structure();
-            }
-            if merged_child_node.remote_guid_changed() {
+            Some(remote_child_node) => (Some(remote_child_node), false),
+            None => (
+                self.find_dupe_in_any_folder(
+                    Side::Local,
+                    local_parent_node,
+                    local_position,
+                    local_child_node,
+                )?,
+                true,
+            ),
+        };
+        let merged_child_node =
+            if let Some(remote_child_node_by_content) = remote_child_node_by_content {
+                // The local child has a remote content match, so take the remote GUID
+                // and merge.
+                let mut merged_child_node =
+                    self.two_way_merge(local_child_node, remote_child_node_by_content)?;
+                if in_other_folder {
+                    // The remote match lives in a different folder, so we're
+                    // moving it into this one.
+                    merged_child_node.merge_state =
+                        merged_child_node.merge_state.with_new_remote_structure();
+                    merged_node.merge_state = merged_node.merge_state.with_new_remote_structure();
+                }
+                if merged_child_node.local_guid_changed() {
+                    merged_child_node.merge_state =
+                        merged_child_node.merge_state.with_new_local_structure();
+                }
+                if merged_node.remote_guid_changed() {
+                    merged_child_node.merge_state =
+                        merged_child_node.merge_state.with_new_remote_structure();
+                }
+                if merged_child_node.remote_guid_changed() {
+                    merged_node.merge_state = merged_node.merge_state.with_new_remote_structure();
+                }
+                merged_node.merge_state = merged_node.merge_state.with_new_local_structure();
+                merged_child_node
+            } else {
+                // The local child doesn't exist remotely, so flag the merged parent and
+                // new child for upload, and walk its descendants.
+                let mut merged_child_node = self.merge_local_only_node(local_child_node)?;
+                if merged_child_node.local_guid_changed() {
+                    merged_child_node.merge_state =
+                        merged_child_node.merge_state.with_new_local_structure();
+                }
                 merged_node.merge_state = merged_node.merge_state.with_new_remote_structure();
-            }
-            merged_node.merge_state = merged_node.merge_state.with_new_local_structure();
-            merged_child_node
-        } else {
-            // The local child doesn't exist remotely, so flag the merged parent and
-            // new child for upload, and walk its descendants.
-            let mut merged_child_node = self.merge_local_only_node(local_child_node)?;
-            if merged_child_node.local_guid_changed() {
                 merged_child_node.merge_state =
-                    merged_child_node.merge_state.with_new_local_structure();
-            }
-            merged_node.merge_state = merged_node.merge_state.with_new_remote_structure();
-            merged_child_node.merge_state =
-                merged_child_node.merge_state.with_new_remote_structure();
-            merged_child_node
-        };
+                    merged_child_node.merge_state.with_new_remote_structure();
+                merged_child_node
+            };
         merged_node.merged_children.push(merged_child_node);
         self.structure_counts.merged_nodes += 1;
         Ok(())
@@ -987,7 +1840,7 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
     /// Determines which side to prefer, and which children to merge first,
     /// for an item that exists on both sides.
     fn resolve_value_conflict(
-        &self,
+        &mut self,
         local_node: Node<'t>,
         remote_node: Node<'t>,
     ) -> (ConflictResolution, ConflictResolution) {
@@ -998,7 +1851,23 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
 
         match (local_node.needs_merge, remote_node.needs_merge) {
             (true, true) => {
-                // The item changed locally and remotely.
+                // The item changed locally and remotely. We only need to pick
+                // a side if the item is valid on both sides, and not a root,
+                // or if the children are different.
+                let item_conflicts = !local_node.is_built_in_root()
+                    && local_node.validity != Validity::Replace
+                    && remote_node.validity != Validity::Replace;
+                let children_match = local_node.has_matching_children(remote_node);
+                let side = if item_conflicts || !children_match {
+                    self.resolve_conflict(Conflict::Value {
+                        local_node,
+                        remote_node,
+                    })
+                } else {
+                    // Neither the item nor its children conflict, so it
+                    // doesn't matter which side we pick.
+                    Side::Local
+                };
                 let item = if local_node.is_built_in_root() {
                     // For roots, we always prefer the local side for item
                     // changes, like the title (bug 1432614).
@@ -1015,30 +1884,22 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
                         // apply or upload those changes, anyway.
                         (Validity::Replace, _) => ConflictResolution::Remote,
                         (_, Validity::Replace) => ConflictResolution::Local,
-                        (_, _) => {
-                            // Otherwise, the item is either valid, or valid
-                            // but needs to be reuploaded or reapplied, so
-                            // compare timestamps to decide which side is newer.
-                            if local_node.age < remote_node.age {
-                                ConflictResolution::Local
-                            } else {
-                                ConflictResolution::Remote
-                            }
-                        }
+                        // Otherwise, the item is either valid, or valid but
+                        // needs to be reuploaded or reapplied, so take the
+                        // side that the driver picked, or the newer side.
+                        (_, _) => side.into(),
                     }
                 };
-                // For children, it's easier: we always use the newer side, even
-                // if we're taking local changes for the item.
-                let children = if local_node.has_matching_children(remote_node) {
+                // For children, it's easier: we always use the same side,
+                // even if we're taking local changes for a root. If the
+                // local side wins, we merge local children first, followed
+                // by remaining unmerged remote children; if the remote side
+                // wins, we walk and merge remote children first, then
+                // remaining local children.
+                let children = if children_match {
                     ConflictResolution::Unchanged
-                } else if local_node.age < remote_node.age {
-                    // The local change is newer, so merge local children first,
-                    // followed by remaining unmerged remote children.
-                    ConflictResolution::Local
                 } else {
-                    // The remote change is newer, so walk and merge remote
-                    // children first, then remaining local children.
-                    ConflictResolution::Remote
+                    side.into()
                 };
                 (item, children)
             }
@@ -1094,7 +1955,7 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
                 // flagged as unmerged, so we prefer the newer side.
                 let children = if local_node.has_matching_children(remote_node) {
                     ConflictResolution::Unchanged
-                } else if local_node.age < remote_node.age {
+                } else if compare_changes(&[local_node], &[remote_node]) == Ordering::Greater {
                     ConflictResolution::Local
                 } else {
                     ConflictResolution::Remote
@@ -1104,9 +1965,72 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
         }
     }
 
+    /// Picks a side for a conflicting change, and records the conflict. The
+    /// driver picks the side first; if it doesn't, we take the newer side.
+    fn resolve_conflict(&mut self, conflict: Conflict<'t>) -> Side {
+        if let Conflict::Structure { local_node, .. } = conflict {
+            // We check for structure conflicts when we walk the child's local
+            // and remote parents, so make sure we only ask once.
+            if let Some(&side) = self.structure_conflict_sides.get(&local_node.guid) {
+                return side;
+            }
+        }
+        if self.driver.conflict_ordering() == ConflictOrdering::Causal {
+            // If one change happened after the other, it's not a conflict, so
+            // we take the later side without asking the driver.
+            let side = match conflict.causality() {
+                Some(Causality::After) => Some(Side::Local),
+                Some(Causality::Before) => Some(Side::Remote),
+                Some(Causality::Equal) | Some(Causality::Concurrent) | None => None,
+            };
+            if let Some(side) = side {
+                trace!(
+                    self.driver,
+                    "Ordering changes by causality: {}; taking {} side",
+                    conflict,
+                    side
+                );
+                return side;
+            }
+        }
+        if let Some(skew) = conflict.clock_skew() {
+            warn!(
+                self.driver,
+                "Clocks disagree on newer side for {}: modified {}ms locally and {}ms \
+                 remotely, but ages are {}ms locally and {}ms remotely",
+                skew.guid,
+                skew.local_modified,
+                skew.remote_modified,
+                skew.local_age,
+                skew.remote_age
+            );
+            self.record_telemetry_event(TelemetryEvent::ClockSkew(skew));
+        }
+        let (side, by_driver) = match self.driver.resolve_conflict(&conflict) {
+            Some(side) => (side, true),
+            None => (conflict.newer_side(), false),
+        };
+        trace!(
+            self.driver,
+            "Resolving conflict: {}; taking {} side",
+            conflict,
+            side
+        );
+        if let Conflict::Structure { local_node, .. } = conflict {
+            self.structure_conflict_sides
+                .insert(local_node.guid.clone(), side);
+        }
+        self.conflicts.push(ResolvedConflict {
+            conflict,
+            side,
+            by_driver,
+        });
+        side
+    }
+
     /// Determines where to keep a child of a folder that exists on both sides.
     fn resolve_structure_conflict(
-        &self,
+        &mut self,
         local_parent_node: Node<'t>,
         local_child_node: Node<'t>,
         remote_parent_node: Node<'t>,
@@ -1122,16 +2046,15 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
             remote_parent_node.needs_merge,
         ) {
             (true, true) => {
-                // If both parents changed, compare timestamps to decide where
-                // to keep the local child.
-                let latest_local_age = local_child_node.age.min(local_parent_node.age);
-                let latest_remote_age = remote_child_node.age.min(remote_parent_node.age);
-
-                if latest_local_age < latest_remote_age {
-                    ConflictResolution::Local
-                } else {
-                    ConflictResolution::Remote
-                }
+                // If both parents changed, ask the driver, or compare
+                // timestamps, to decide where to keep the local child.
+                self.resolve_conflict(Conflict::Structure {
+                    local_parent_node,
+                    local_node: local_child_node,
+                    remote_parent_node,
+                    remote_node: remote_child_node,
+                })
+                .into()
             }
 
             // If only the local or remote parent changed, keep the child in its
@@ -1367,10 +2290,10 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
         merged_node: &mut MergedNode<'t>,
         remote_node: Node<'t>,
     ) -> Result<StructureChange> {
-        self.delete_remotely.insert(remote_node.guid.clone());
+        self.insert_delete_remotely(&remote_node.guid);
         for remote_child_node in remote_node.children() {
-            self.signal.err_if_aborted()?;
-            if self.merged_guids.contains(&remote_child_node.guid) {
+            self.checkpoint()?;
+            if self.is_merged(&remote_child_node.guid) {
                 trace!(
                     self.driver,
                     "Remote child {} can't be an orphan; already merged",
@@ -1429,10 +2352,10 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
         merged_node: &mut MergedNode<'t>,
         local_node: Node<'t>,
     ) -> Result<StructureChange> {
-        self.delete_locally.insert(local_node.guid.clone());
+        self.insert_delete_locally(&local_node.guid);
         for local_child_node in local_node.children() {
-            self.signal.err_if_aborted()?;
-            if self.merged_guids.contains(&local_child_node.guid) {
+            self.checkpoint()?;
+            if self.is_merged(&local_child_node.guid) {
                 trace!(
                     self.driver,
                     "Local child {} can't be an orphan; already merged",
@@ -1503,14 +2426,16 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
     /// remaining children of the same folder, on both sides, only needs two
     /// O(1) map lookups per child.
     fn find_all_matching_dupes_in_folders(
-        &self,
+        &mut self,
         local_parent_node: Node<'t>,
         remote_parent_node: Node<'t>,
     ) -> Result<MatchingDupes<'t>> {
+        let now = Instant::now();
+        let matcher = self.driver.dupe_matcher();
         let mut dupe_key_to_local_nodes: HashMap<DupeKey<'_>, VecDeque<_>> = HashMap::new();
 
         for (local_position, local_child_node) in local_parent_node.children().enumerate() {
-            self.signal.err_if_aborted()?;
+            self.checkpoint()?;
             if local_child_node.is_built_in_root() {
                 trace!(
                     self.driver,
@@ -1532,12 +2457,19 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
                     // Store matching local children in an array, in case multiple children
                     // have the same dupe key (for example, a toolbar containing multiple
                     // empty folders, as in bug 1213369).
-                    let dupe_key = match local_child_content {
-                        Content::Bookmark { .. } | Content::Folder { .. } => {
-                            DupeKey::WithoutPosition(local_child_content)
-                        }
-                        Content::Separator => {
-                            DupeKey::WithPosition(local_child_content, local_position)
+                    let dupe_key = match matcher.dupe_key(
+                        local_parent_node,
+                        local_position,
+                        local_child_content,
+                    ) {
+                        Some(dupe_key) => dupe_key,
+                        None => {
+                            trace!(
+                                self.driver,
+                                "Not deduping local child {}; excluded by matcher",
+                                local_child_node
+                            );
+                            continue;
                         }
                     };
                     let local_nodes_for_key = dupe_key_to_local_nodes.entry(dupe_key).or_default();
@@ -1557,7 +2489,7 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
         let mut remote_to_local = HashMap::new();
 
         for (remote_position, remote_child_node) in remote_parent_node.children().enumerate() {
-            self.signal.err_if_aborted()?;
+            self.checkpoint()?;
             if remote_child_node.is_built_in_root() {
                 trace!(
                     self.driver,
@@ -1587,12 +2519,19 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
             // were.
             match remote_child_node.content() {
                 Some(remote_child_content) => {
-                    let dupe_key = match remote_child_content {
-                        Content::Bookmark { .. } | Content::Folder { .. } => {
-                            DupeKey::WithoutPosition(remote_child_content)
-                        }
-                        Content::Separator => {
-                            DupeKey::WithPosition(remote_child_content, remote_position)
+                    let dupe_key = match matcher.dupe_key(
+                        remote_parent_node,
+                        remote_position,
+                        remote_child_content,
+                    ) {
+                        Some(dupe_key) => dupe_key,
+                        None => {
+                            trace!(
+                                self.driver,
+                                "Not deduping remote child {}; excluded by matcher",
+                                remote_child_node
+                            );
+                            continue;
                         }
                     };
                     if let Some(local_nodes_for_key) = dupe_key_to_local_nodes.get_mut(&dupe_key) {
@@ -1634,6 +2573,10 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
             }
         }
 
+        self.dedupe_time += now.elapsed();
+        self.dedupe_nodes +=
+            local_parent_node.children().count() + remote_parent_node.children().count();
+
         Ok((local_to_remote, remote_to_local))
     }
 
@@ -1649,10 +2592,8 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
         local_child_node: Node<'t>,
     ) -> Result<Option<Node<'t>>> {
         if let Some(remote_parent_node) = remote_parent_node {
-            let mut matching_dupes_by_local_parent_guid = mem::replace(
-                &mut self.matching_dupes_by_local_parent_guid,
-                HashMap::new(),
-            );
+            let mut matching_dupes_by_local_parent_guid =
+                mem::take(&mut self.matching_dupes_by_local_parent_guid);
             let new_remote_node = {
                 let (local_to_remote, _) = match matching_dupes_by_local_parent_guid
                     .entry(local_parent_node.guid.clone())
@@ -1667,23 +2608,32 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
                             local_parent_node,
                             remote_parent_node
                         );
-                        let matching_dupes = self.find_all_matching_dupes_in_folders(
+                        match self.find_all_matching_dupes_in_folders(
                             local_parent_node,
                             remote_parent_node,
-                        )?;
-                        entry.insert(matching_dupes)
+                        ) {
+                            Ok(matching_dupes) => entry.insert(matching_dupes),
+                            Err(err) => {
+                                // Keep the dupes that we already matched, so
+                                // that we can save them if the merge aborted.
+                                self.matching_dupes_by_local_parent_guid =
+                                    matching_dupes_by_local_parent_guid;
+                                return Err(err);
+                            }
+                        }
                     }
                 };
-                let new_remote_node = local_to_remote.get(&local_child_node.guid);
+                // A dupe that we already matched across folders can't be
+                // merged again.
+                let new_remote_node = local_to_remote
+                    .get(&local_child_node.guid)
+                    .filter(|node| !self.is_merged(&node.guid));
                 new_remote_node.map(|node| {
                     self.structure_counts.dupes += 1;
                     *node
                 })
             };
-            mem::replace(
-                &mut self.matching_dupes_by_local_parent_guid,
-                matching_dupes_by_local_parent_guid,
-            );
+            self.matching_dupes_by_local_parent_guid = matching_dupes_by_local_parent_guid;
             Ok(new_remote_node)
         } else {
             trace!(
@@ -1708,10 +2658,8 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
         remote_child_node: Node<'t>,
     ) -> Result<Option<Node<'t>>> {
         if let Some(local_parent_node) = local_parent_node {
-            let mut matching_dupes_by_local_parent_guid = mem::replace(
-                &mut self.matching_dupes_by_local_parent_guid,
-                HashMap::new(),
-            );
+            let mut matching_dupes_by_local_parent_guid =
+                mem::take(&mut self.matching_dupes_by_local_parent_guid);
             let new_local_node = {
                 let (_, remote_to_local) = match matching_dupes_by_local_parent_guid
                     .entry(local_parent_node.guid.clone())
@@ -1726,23 +2674,28 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
                             local_parent_node,
                             remote_parent_node
                         );
-                        let matching_dupes = self.find_all_matching_dupes_in_folders(
+                        match self.find_all_matching_dupes_in_folders(
                             local_parent_node,
                             remote_parent_node,
-                        )?;
-                        entry.insert(matching_dupes)
+                        ) {
+                            Ok(matching_dupes) => entry.insert(matching_dupes),
+                            Err(err) => {
+                                self.matching_dupes_by_local_parent_guid =
+                                    matching_dupes_by_local_parent_guid;
+                                return Err(err);
+                            }
+                        }
                     }
                 };
-                let new_local_node = remote_to_local.get(&remote_child_node.guid);
+                let new_local_node = remote_to_local
+                    .get(&remote_child_node.guid)
+                    .filter(|node| !self.is_merged(&node.guid));
                 new_local_node.map(|node| {
                     self.structure_counts.dupes += 1;
                     *node
                 })
             };
-            mem::replace(
-                &mut self.matching_dupes_by_local_parent_guid,
-                matching_dupes_by_local_parent_guid,
-            );
+            self.matching_dupes_by_local_parent_guid = matching_dupes_by_local_parent_guid;
             Ok(new_local_node)
         } else {
             trace!(
@@ -1754,18 +2707,261 @@ impl<'t, D: Driver, A: AbortSignal> Merger<'t, D, A> {
             Ok(None)
         }
     }
+
+    /// Finds a new bookmark on the other side, in any folder, with the same
+    /// content as a new bookmark on the given side. This is only used if the
+    /// driver opts in to cross-folder deduping, and there's no match in the
+    /// same folder.
+    ///
+    /// In report mode, this records the match, but returns `None`, so that
+    /// the merger keeps both bookmarks.
+    ///
+    /// Time spent here, including indexing each tree the first time, counts
+    /// toward the dedupe phase.
+    fn find_dupe_in_any_folder(
+        &mut self,
+        side: Side,
+        parent_node: Node<'t>,
+        position: usize,
+        node: Node<'t>,
+    ) -> Result<Option<Node<'t>>> {
+        let mode = self.driver.cross_folder_dedupe();
+        if mode == CrossFolderDedupe::Off {
+            return Ok(None);
+        }
+        let now = Instant::now();
+        let result = self.match_dupe_in_any_folder(mode, side, parent_node, position, node);
+        self.dedupe_time += now.elapsed();
+        result
+    }
+
+    fn match_dupe_in_any_folder(
+        &mut self,
+        mode: CrossFolderDedupe,
+        side: Side,
+        parent_node: Node<'t>,
+        position: usize,
+        node: Node<'t>,
+    ) -> Result<Option<Node<'t>>> {
+        let dupe_key = match self.cross_folder_dupe_key(parent_node, position, node) {
+            Some(dupe_key) => dupe_key,
+            None => return Ok(None),
+        };
+        let dupes_by_key = match side {
+            Side::Local => {
+                if self.remote_dupes_by_key.is_none() {
+                    let dupes_by_key =
+                        self.index_cross_folder_dupes(self.remote_tree, self.local_tree)?;
+                    self.remote_dupes_by_key = Some(dupes_by_key);
+                    self.dedupe_nodes += self.remote_tree.size();
+                }
+                self.remote_dupes_by_key.as_mut().unwrap()
+            }
+            Side::Remote => {
+                if self.local_dupes_by_key.is_none() {
+                    let dupes_by_key =
+                        self.index_cross_folder_dupes(self.local_tree, self.remote_tree)?;
+                    self.local_dupes_by_key = Some(dupes_by_key);
+                    self.dedupe_nodes += self.local_tree.size();
+                }
+                self.local_dupes_by_key.as_mut().unwrap()
+            }
+        };
+        let mut other_node = None;
+        if let Some(nodes) = dupes_by_key.get_mut(&dupe_key) {
+            while let Some(candidate_node) = nodes.pop_front() {
+                // Skip candidates that we already merged, either because
+                // they were deduped in their own folders, or because we
+                // visited their folders before this one. We check the
+                // fields directly, since `dupes_by_key` borrows `self`.
+                let merged_guids = &self.merged_guids;
+                let is_merged = self
+                    .guids
+                    .index(&candidate_node.guid)
+                    .is_some_and(|index| merged_guids.contains(index));
+                if !is_merged {
+                    other_node = Some(candidate_node);
+                    break;
+                }
+            }
+        }
+        let other_node = match other_node {
+            Some(other_node) => other_node,
+            None => return Ok(None),
+        };
+        let (local_node, remote_node) = match side {
+            Side::Local => (node, other_node),
+            Side::Remote => (other_node, node),
+        };
+        let dupe = CrossFolderDupe {
+            local_node,
+            remote_node,
+            merged: mode == CrossFolderDedupe::Merge,
+        };
+        trace!(self.driver, "Found cross-folder dupe: {}", dupe);
+        self.cross_folder_dupes.push(dupe);
+        self.structure_counts.cross_folder_dupes += 1;
+        if dupe.merged {
+            self.structure_counts.dupes += 1;
+            Ok(Some(other_node))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns the key for matching a new bookmark at `position` in
+    /// `parent_node` across folders, or `None` if the node isn't a bookmark,
+    /// or its key depends on its position.
+    fn cross_folder_dupe_key(
+        &self,
+        parent_node: Node<'t>,
+        position: usize,
+        node: Node<'t>,
+    ) -> Option<DupeKey<'t>> {
+        let content = node.content()?;
+        if !matches!(content, Content::Bookmark { .. }) {
+            return None;
+        }
+        match self
+            .driver
+            .dupe_matcher()
+            .dupe_key(parent_node, position, content)?
+        {
+            dupe_key @ DupeKey::WithoutPosition(_) => Some(dupe_key),
+            DupeKey::WithPosition(..) => None,
+        }
+    }
+
+    /// Indexes all new syncable bookmarks in a tree by their cross-folder
+    /// dupe keys. New bookmarks are those that the other tree doesn't mention.
+    fn index_cross_folder_dupes(
+        &self,
+        tree: &'t Tree,
+        other_tree: &'t Tree,
+    ) -> Result<DupesByKey<'t>> {
+        let mut dupes_by_key = DupesByKey::new();
+        // Walk the tree in order, keeping track of each node's position in its
+        // parent, so that we don't need to look it up for every node.
+        let mut nodes = vec![(tree.root(), 0)];
+        while let Some((node, position)) = nodes.pop() {
+            self.checkpoint()?;
+            let child_nodes = node.children().enumerate().collect::<Vec<_>>();
+            nodes.extend(
+                child_nodes
+                    .into_iter()
+                    .rev()
+                    .map(|(position, child_node)| (child_node, position)),
+            );
+            if node.is_built_in_root() || other_tree.mentions(&node.guid) || !node.is_syncable() {
+                continue;
+            }
+            let parent_node = match node.parent() {
+                Some(parent_node) => parent_node,
+                None => continue,
+            };
+            if let Some(dupe_key) = self.cross_folder_dupe_key(parent_node, position, node) {
+                dupes_by_key.entry(dupe_key).or_default().push_back(node);
+            }
+        }
+        Ok(dupes_by_key)
+    }
 }
 
-/// The root of a merged tree, from which all merged nodes descend.
+/// Assigns indices to all GUIDs that the merger sees, so that it can track
+/// merged and deleted items in `GuidSet`s. GUIDs of local nodes use their
+/// indices in the local tree, and GUIDs of remote-only nodes are offset by the
+/// size of the local tree. Only GUIDs that aren't in either tree, like
+/// tombstones and new GUIDs for invalid ones, are cloned into a table.
 #[derive(Debug)]
+struct MergedGuids<'t> {
+    local_tree: &'t Tree,
+    remote_tree: &'t Tree,
+    others: GuidTable,
+}
+
+impl<'t> MergedGuids<'t> {
+    fn new(local_tree: &'t Tree, remote_tree: &'t Tree) -> MergedGuids<'t> {
+        MergedGuids {
+            local_tree,
+            remote_tree,
+            others: GuidTable::new(),
+        }
+    }
+
+    /// Returns the index for a GUID, or `None` if the GUID isn't in either
+    /// tree and hasn't been interned.
+    fn index(&self, guid: &Guid) -> Option<GuidIndex> {
+        if let Some(index) = self.local_tree.guid_index(guid) {
+            return Some(index);
+        }
+        let offset = self.local_tree.size();
+        if let Some(index) = self.remote_tree.guid_index(guid) {
+            return Some(GuidIndex::new(offset + index.as_usize()));
+        }
+        let offset = offset + self.remote_tree.size();
+        self.others
+            .index(guid)
+            .map(|index| GuidIndex::new(offset + index.as_usize()))
+    }
+
+    /// Returns the index for a GUID, interning it if it's not in either tree.
+    fn intern(&mut self, guid: &Guid) -> GuidIndex {
+        self.index(guid).unwrap_or_else(|| {
+            let offset = self.local_tree.size() + self.remote_tree.size();
+            GuidIndex::new(offset + self.others.intern(guid).as_usize())
+        })
+    }
+
+    /// Returns the GUID for an index.
+    fn guid(&self, index: GuidIndex) -> &Guid {
+        let index = index.as_usize();
+        let local_size = self.local_tree.size();
+        if index < local_size {
+            return self.local_tree.guid_for_index(GuidIndex::new(index));
+        }
+        let remote_size = self.remote_tree.size();
+        if index < local_size + remote_size {
+            return self
+                .remote_tree
+                .guid_for_index(GuidIndex::new(index - local_size));
+        }
+        self.others
+            .guid(GuidIndex::new(index - local_size - remote_size))
+    }
+}
+
+/// The root of a merged tree, from which all merged nodes descend.
 pub struct MergedRoot<'t> {
+    driver: &'t dyn Driver,
+    record_telemetry: bool,
     local_tree: &'t Tree,
     remote_tree: &'t Tree,
     node: MergedNode<'t>,
-    merged_guids: HashSet<Guid>,
-    delete_locally: HashSet<Guid>,
-    delete_remotely: HashSet<Guid>,
+    guids: MergedGuids<'t>,
+    delete_locally: GuidSet,
+    delete_remotely: GuidSet,
     structure_counts: StructureCounts,
+    conflicts: Vec<ResolvedConflict<'t>>,
+    cross_folder_dupes: Vec<CrossFolderDupe<'t>>,
+    keyword_clashes: Vec<KeywordClash>,
+}
+
+impl<'t> fmt::Debug for MergedRoot<'t> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Drivers don't implement `Debug`, so we leave it out.
+        f.debug_struct("MergedRoot")
+            .field("local_tree", &self.local_tree)
+            .field("remote_tree", &self.remote_tree)
+            .field("node", &self.node)
+            .field("guids", &self.guids)
+            .field("delete_locally", &self.delete_locally)
+            .field("delete_remotely", &self.delete_remotely)
+            .field("structure_counts", &self.structure_counts)
+            .field("conflicts", &self.conflicts)
+            .field("cross_folder_dupes", &self.cross_folder_dupes)
+            .field("keyword_clashes", &self.keyword_clashes)
+            .finish()
+    }
 }
 
 impl<'t> MergedRoot<'t> {
@@ -1778,35 +2974,61 @@ impl<'t> MergedRoot<'t> {
     /// Returns a sequence of completion operations, or "completion ops", to
     /// apply to the local tree so that it matches the merged tree. The abort
     /// signal can be used to interrupt fetching the ops.
+    ///
+    /// This reports progress to the merger's driver, and records a telemetry
+    /// event for the `CompletionOps` phase, unless the merged root is for a
+    /// preview.
     pub fn completion_ops_with_signal(
         &self,
         signal: &impl AbortSignal,
     ) -> Result<CompletionOps<'_>> {
+        let now = Instant::now();
+        let ops = self.accumulate_completion_ops(signal)?;
+        if self.record_telemetry {
+            self.driver
+                .record_telemetry_event(TelemetryEvent::MergePhase(PhaseStats {
+                    phase: MergePhase::CompletionOps,
+                    time: now.elapsed(),
+                    nodes: self.structure_counts.merged_nodes,
+                }));
+        }
+        Ok(ops)
+    }
+
+    fn accumulate_completion_ops<A: AbortSignal>(&self, signal: &A) -> Result<CompletionOps<'_>> {
         let mut ops = CompletionOps::default();
-        accumulate(signal, &mut ops, self.node(), 1, false)?;
+        let mut checkpoint = OpsCheckpoint {
+            driver: self.driver,
+            signal,
+            visited_nodes: 0,
+            total_nodes: self.structure_counts.merged_nodes
+                + self.local_tree.deletions().len()
+                + self.remote_tree.deletions().len()
+                + self.deletions().count(),
+        };
+        let roots = self.local_tree.roots();
+        accumulate(&mut checkpoint, roots, &mut ops, self.node(), 1, false)?;
 
         // Clean up tombstones for local and remote items that are revived on
         // the other side.
-        for guid in self
-            .local_tree
-            .deletions()
-            .difference(&self.delete_remotely)
-        {
+        for guid in self.local_tree.deletions() {
+            checkpoint.visit()?;
+            if self.is_deleted_remotely(guid) {
+                continue;
+            }
             // For ignored local deletions, we remove the local tombstone. If
             // the item is already deleted remotely, we also flag the remote
             // tombstone as merged.
-            signal.err_if_aborted()?;
             ops.delete_local_tombstones.push(DeleteLocalTombstone(guid));
             if self.remote_tree.is_deleted(guid) {
                 ops.set_remote_merged.push(SetRemoteMerged(guid));
             }
         }
-        for guid in self
-            .remote_tree
-            .deletions()
-            .difference(&self.delete_locally)
-            .filter(|guid| !self.local_tree.exists(guid))
-        {
+        for guid in self.remote_tree.deletions() {
+            checkpoint.visit()?;
+            if self.is_deleted_locally(guid) || self.local_tree.exists(guid) {
+                continue;
+            }
             // Ignored remote deletions are handled a little differently. Unlike
             // local tombstones, which are stored separately from items, remote
             // tombstones and items are stored in the same table. This means we
@@ -1815,7 +3037,6 @@ impl<'t> MergedRoot<'t> {
             // we can avoid an extra write to flag the tombstone that we'll
             // replace with the item, anyway. If the item is already deleted
             // locally, we also delete the local tombstone.
-            signal.err_if_aborted()?;
             ops.set_remote_merged.push(SetRemoteMerged(guid));
             if self.local_tree.is_deleted(guid) {
                 ops.delete_local_tombstones.push(DeleteLocalTombstone(guid));
@@ -1824,7 +3045,7 @@ impl<'t> MergedRoot<'t> {
 
         // Emit completion ops for deleted items.
         for guid in self.deletions() {
-            signal.err_if_aborted()?;
+            checkpoint.visit()?;
             match (
                 self.local_tree.node_for_guid(guid),
                 self.remote_tree.node_for_guid(guid),
@@ -1883,21 +3104,49 @@ impl<'t> MergedRoot<'t> {
     /// Returns an iterator for all accepted local and remote deletions.
     #[inline]
     pub fn deletions(&self) -> impl Iterator<Item = &Guid> {
-        self.delete_locally.union(&self.delete_remotely)
+        self.delete_locally
+            .iter()
+            .chain(
+                self.delete_remotely
+                    .iter()
+                    .filter(move |&index| !self.delete_locally.contains(index)),
+            )
+            .map(move |index| self.guids.guid(index))
     }
 
     /// Returns an iterator for all items that should be deleted from the
     /// local tree.
     #[inline]
     pub fn local_deletions(&self) -> impl Iterator<Item = &Guid> {
-        self.delete_locally.difference(&self.delete_remotely)
+        self.delete_locally
+            .iter()
+            .filter(move |&index| !self.delete_remotely.contains(index))
+            .map(move |index| self.guids.guid(index))
     }
 
     /// Returns an iterator for all items that should be deleted from the
     /// remote tree.
     #[inline]
     pub fn remote_deletions(&self) -> impl Iterator<Item = &Guid> {
-        self.delete_remotely.iter()
+        self.delete_remotely
+            .iter()
+            .map(move |index| self.guids.guid(index))
+    }
+
+    /// Indicates if the merged tree deletes the item locally.
+    #[inline]
+    fn is_deleted_locally(&self, guid: &Guid) -> bool {
+        self.guids
+            .index(guid)
+            .is_some_and(|index| self.delete_locally.contains(index))
+    }
+
+    /// Indicates if the merged tree deletes the item remotely.
+    #[inline]
+    fn is_deleted_remotely(&self, guid: &Guid) -> bool {
+        self.guids
+            .index(guid)
+            .is_some_and(|index| self.delete_remotely.contains(index))
     }
 
     /// Returns structure change counts for this merged root.
@@ -1905,6 +3154,29 @@ impl<'t> MergedRoot<'t> {
     pub fn counts(&self) -> &StructureCounts {
         &self.structure_counts
     }
+
+    /// Returns all conflicts that the merger resolved, in the order that it
+    /// resolved them. In `ConflictOrdering::Causal` mode, changes that are
+    /// ordered by their version vectors aren't conflicts, so this only
+    /// includes concurrent changes.
+    #[inline]
+    pub fn conflicts(&self) -> &[ResolvedConflict<'t>] {
+        &self.conflicts
+    }
+
+    /// Returns all new bookmarks that the merger matched across folders, in
+    /// the order that it found them.
+    #[inline]
+    pub fn cross_folder_dupes(&self) -> &[CrossFolderDupe<'t>] {
+        &self.cross_folder_dupes
+    }
+
+    /// Returns all keywords that pointed to different URLs in the merged
+    /// tree, sorted by keyword.
+    #[inline]
+    pub fn keyword_clashes(&self) -> &[KeywordClash] {
+        &self.keyword_clashes
+    }
 }
 
 /// Completion operations to apply to the local tree after a merge. These are
@@ -1914,6 +3186,8 @@ impl<'t> MergedRoot<'t> {
 pub struct CompletionOps<'t> {
     pub change_guids: Vec<ChangeGuid<'t>>,
     pub apply_remote_items: Vec<ApplyRemoteItem<'t>>,
+    pub apply_merged_tags: Vec<ApplyMergedTags<'t>>,
+    pub apply_merged_content: Vec<ApplyMergedContent<'t>>,
     pub apply_new_local_structure: Vec<ApplyNewLocalStructure<'t>>,
     pub set_local_unmerged: Vec<SetLocalUnmerged<'t>>,
     pub set_local_merged: Vec<SetLocalMerged<'t>>,
@@ -1931,6 +3205,8 @@ impl<'t> CompletionOps<'t> {
     pub fn is_empty(&self) -> bool {
         self.change_guids.is_empty()
             && self.apply_remote_items.is_empty()
+            && self.apply_merged_tags.is_empty()
+            && self.apply_merged_content.is_empty()
             && self.apply_new_local_structure.is_empty()
             && self.set_local_unmerged.is_empty()
             && self.set_local_merged.is_empty()
@@ -1947,6 +3223,8 @@ impl<'t> CompletionOps<'t> {
         std::iter::empty()
             .chain(to_strings(&self.change_guids))
             .chain(to_strings(&self.apply_remote_items))
+            .chain(to_strings(&self.apply_merged_tags))
+            .chain(to_strings(&self.apply_merged_content))
             .chain(to_strings(&self.apply_new_local_structure))
             .chain(to_strings(&self.set_local_unmerged))
             .chain(to_strings(&self.set_local_merged))
@@ -2032,6 +3310,59 @@ impl<'t> fmt::Display for ApplyRemoteItem<'t> {
     }
 }
 
+/// A completion op to store the union of the local and remote tags for an
+/// item that changed on both sides. Stores should apply this op after
+/// `ApplyRemoteItem`, since applying the remote item replaces its tags.
+#[derive(Clone, Copy, Debug)]
+pub struct ApplyMergedTags<'t> {
+    pub merged_node: &'t MergedNode<'t>,
+}
+
+impl<'t> ApplyMergedTags<'t> {
+    /// Returns the merged tags to store.
+    #[inline]
+    pub fn tags(&self) -> &'t BTreeSet<String> {
+        self.merged_node.tags()
+    }
+}
+
+impl<'t> fmt::Display for ApplyMergedTags<'t> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Apply merged tags [{}] to {}",
+            self.tags()
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join(", "),
+            self.merged_node.guid
+        )
+    }
+}
+
+/// A completion op to store merged content for an item, like a bookmark
+/// without a clashing keyword. Stores should apply this op after
+/// `ApplyRemoteItem`, since applying the remote item replaces its content.
+#[derive(Clone, Copy, Debug)]
+pub struct ApplyMergedContent<'t> {
+    pub merged_node: &'t MergedNode<'t>,
+}
+
+impl<'t> ApplyMergedContent<'t> {
+    /// Returns the merged content to store.
+    #[inline]
+    pub fn content(&self) -> Option<&'t Content> {
+        self.merged_node.content()
+    }
+}
+
+impl<'t> fmt::Display for ApplyMergedContent<'t> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Apply merged content to {}", self.merged_node.guid)
+    }
+}
+
 /// A completion op to update the parent and position of a local item.
 #[derive(Clone, Copy, Debug)]
 pub struct ApplyNewLocalStructure<'t> {
@@ -2178,18 +3509,39 @@ impl<'t> fmt::Display for DeleteLocalItem<'t> {
     }
 }
 
+/// Reports progress and checks the abort signal while building completion
+/// ops. Each merged node and tombstone is one checkpoint.
+struct OpsCheckpoint<'a, A> {
+    driver: &'a dyn Driver,
+    signal: &'a A,
+    visited_nodes: usize,
+    total_nodes: usize,
+}
+
+impl<'a, A: AbortSignal> OpsCheckpoint<'a, A> {
+    fn visit(&mut self) -> Result<()> {
+        self.visited_nodes += 1;
+        self.driver.report_progress(Progress::CompletionOps {
+            visited_nodes: self.visited_nodes,
+            total_nodes: self.total_nodes,
+        });
+        self.signal.err_if_aborted()
+    }
+}
+
 /// Recursively accumulates completion ops, starting at `merged_node` and
 /// drilling down into all its descendants.
 fn accumulate<'t, A: AbortSignal>(
-    signal: &A,
+    checkpoint: &mut OpsCheckpoint<'_, A>,
+    roots: &Roots,
     ops: &mut CompletionOps<'t>,
     merged_node: &'t MergedNode<'t>,
     level: usize,
     is_tagging: bool,
 ) -> Result<()> {
     for (position, merged_child_node) in merged_node.merged_children.iter().enumerate() {
-        signal.err_if_aborted()?;
-        let is_tagging = if merged_child_node.guid == TAGS_GUID {
+        checkpoint.visit()?;
+        let is_tagging = if roots.is_tags_root(&merged_child_node.guid) {
             true
         } else {
             is_tagging
@@ -2201,6 +3553,43 @@ fn accumulate<'t, A: AbortSignal>(
             };
             ops.apply_remote_items.push(apply_remote_item);
         }
+        if let (Some(merged_tags), Some(local_child_node)) = (
+            &merged_child_node.merged_tags,
+            merged_child_node.merge_state.local_node(),
+        ) {
+            // Applying a remote item replaces the local tags with the remote
+            // ones, so we compare the merged tags to those.
+            let applied_tags = match merged_child_node.merge_state.remote_node() {
+                Some(remote_child_node) if merged_child_node.merge_state.should_apply_item() => {
+                    &remote_child_node.tags
+                }
+                _ => &local_child_node.tags,
+            };
+            if merged_tags != applied_tags {
+                ops.apply_merged_tags.push(ApplyMergedTags {
+                    merged_node: merged_child_node,
+                });
+            }
+        }
+        if let Some(merged_content) = &merged_child_node.merged_content {
+            // As with tags, applying a remote item replaces the local content.
+            let applied_content = if merged_child_node.merge_state.should_apply_item() {
+                merged_child_node
+                    .merge_state
+                    .remote_node()
+                    .and_then(|node| node.content())
+            } else {
+                merged_child_node
+                    .merge_state
+                    .local_node()
+                    .and_then(|node| node.content())
+            };
+            if applied_content != Some(merged_content) {
+                ops.apply_merged_content.push(ApplyMergedContent {
+                    merged_node: merged_child_node,
+                });
+            }
+        }
         if merged_child_node.local_guid_changed() {
             let change_guid = ChangeGuid {
                 merged_node: merged_child_node,
@@ -2235,7 +3624,7 @@ fn accumulate<'t, A: AbortSignal>(
             .local_node()
             .map(|node| node.needs_merge)
             .unwrap_or(false);
-        let should_upload = merged_child_node.merge_state.should_upload();
+        let should_upload = merged_child_node.should_upload();
         match (local_needs_merge, should_upload) {
             (false, true) => {
                 // Local item isn't flagged for upload, but should be.
@@ -2271,7 +3660,14 @@ fn accumulate<'t, A: AbortSignal>(
                 ops.set_remote_merged.push(set_remote_merged);
             }
         }
-        accumulate(signal, ops, merged_child_node, level + 1, is_tagging)?;
+        accumulate(
+            checkpoint,
+            roots,
+            ops,
+            merged_child_node,
+            level + 1,
+            is_tagging,
+        )?;
     }
     Ok(())
 }
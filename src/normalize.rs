@@ -0,0 +1,210 @@
+// Copyright 2018-2019 Mozilla
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::tree::Content;
+
+/// Query parameters that only track where a link came from, and don't change
+/// the page that it points to. Parameters that start with `utm_` are also
+/// ignored.
+const TRACKING_PARAMS: &[&str] = &[
+    "fbclid", "gclid", "dclid", "msclkid", "yclid", "mc_cid", "mc_eid", "igshid", "_hsenc", "_hsmi",
+];
+
+/// Returns a copy of the content with a normalized URL and keyword, and
+/// trimmed title and description, for fuzzy deduping. The normalized content
+/// is only used to match dupes, and is never applied or uploaded.
+pub fn normalize_content(content: &Content) -> Content {
+    match content {
+        Content::Bookmark {
+            title,
+            url_href,
+            keyword,
+            description,
+        } => Content::Bookmark {
+            title: normalize_title(title).into(),
+            url_href: normalize_url(url_href),
+            keyword: keyword.as_deref().map(normalize_keyword),
+            description: description.as_deref().map(|d| normalize_title(d).into()),
+        },
+        Content::Query { title, url_href } => Content::Query {
+            title: normalize_title(title).into(),
+            url_href: normalize_url(url_href),
+        },
+        Content::Folder { title } => Content::Folder {
+            title: normalize_title(title).into(),
+        },
+        Content::Livemark {
+            title,
+            feed_url,
+            site_url,
+        } => Content::Livemark {
+            title: normalize_title(title).into(),
+            feed_url: normalize_url(feed_url),
+            site_url: site_url.as_deref().map(normalize_url),
+        },
+        Content::Separator => Content::Separator,
+    }
+}
+
+/// Trims leading and trailing whitespace from a title.
+#[inline]
+pub fn normalize_title(title: &str) -> &str {
+    title.trim()
+}
+
+/// Trims and lowercases a keyword. Keywords are case-insensitive, so `Foo`
+/// and `foo` refer to the same keyword.
+#[inline]
+pub fn normalize_keyword(keyword: &str) -> String {
+    keyword.trim().to_lowercase()
+}
+
+/// Normalizes a URL for fuzzy deduping, so that URLs that point to the same
+/// page compare equal. This lowercases the scheme and host, treats `http` and
+/// `https` as the same scheme, removes default ports, trailing slashes, and
+/// tracking query parameters, and uppercases percent-encoded octets.
+///
+/// URLs without an authority, like `place:` and `javascript:` URLs, only have
+/// their schemes lowercased. Strings that don't look like URLs are returned
+/// trimmed, but otherwise unchanged.
+pub fn normalize_url(url_href: &str) -> String {
+    let url_href = url_href.trim();
+    let (scheme, rest) = match split_scheme(url_href) {
+        Some(parts) => parts,
+        None => return url_href.into(),
+    };
+    let scheme = scheme.to_ascii_lowercase();
+    let rest = match rest.strip_prefix("//") {
+        Some(rest) => rest,
+        None => return format!("{}:{}", scheme, rest),
+    };
+
+    // Split off the fragment, then the query, then the path.
+    let (rest, fragment) = match rest.find('#') {
+        Some(index) => (&rest[..index], Some(&rest[index + 1..])),
+        None => (rest, None),
+    };
+    let (rest, query) = match rest.find('?') {
+        Some(index) => (&rest[..index], Some(&rest[index + 1..])),
+        None => (rest, None),
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, ""),
+    };
+
+    // Remove the default port for the original scheme before folding `https`
+    // into `http`, so that `http://example.com:443` keeps its port.
+    let authority = normalize_authority(&scheme, authority);
+    let scheme = match scheme.as_str() {
+        "https" => "http".into(),
+        _ => scheme,
+    };
+    let mut normalized = format!("{}://{}", scheme, authority);
+    push_percent_encoded(&mut normalized, path.trim_end_matches('/'));
+    if let Some(query) = query {
+        let params = query
+            .split('&')
+            .filter(|param| !param.is_empty() && !is_tracking_param(param))
+            .collect::<Vec<_>>();
+        if !params.is_empty() {
+            normalized.push('?');
+            push_percent_encoded(&mut normalized, &params.join("&"));
+        }
+    }
+    if let Some(fragment) = fragment {
+        if !fragment.is_empty() {
+            normalized.push('#');
+            push_percent_encoded(&mut normalized, fragment);
+        }
+    }
+    normalized
+}
+
+/// Splits a URL into its scheme and the remainder after the colon. Returns
+/// `None` if the URL doesn't start with a valid scheme.
+fn split_scheme(url_href: &str) -> Option<(&str, &str)> {
+    let index = url_href.find(':')?;
+    let scheme = &url_href[..index];
+    let mut chars = scheme.chars();
+    if !chars.next()?.is_ascii_alphabetic() {
+        return None;
+    }
+    if !chars.all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.') {
+        return None;
+    }
+    Some((scheme, &url_href[index + 1..]))
+}
+
+/// Lowercases the host, and removes the port if it's the default for the
+/// scheme. User info is kept as-is.
+fn normalize_authority(scheme: &str, authority: &str) -> String {
+    let (user_info, host_port) = match authority.rfind('@') {
+        Some(index) => (Some(&authority[..index]), &authority[index + 1..]),
+        None => (None, authority),
+    };
+    // Look for the port after the closing bracket of an IPv6 literal, so that
+    // we don't mistake its colons for a port separator.
+    let port_start = host_port.rfind(']').unwrap_or(0);
+    let (host, port) = match host_port[port_start..].rfind(':') {
+        Some(index) => (
+            &host_port[..port_start + index],
+            Some(&host_port[port_start + index + 1..]),
+        ),
+        None => (host_port, None),
+    };
+    let is_default_port = matches!(
+        (scheme, port),
+        (_, Some("")) | ("http", Some("80")) | ("https", Some("443")) | ("ftp", Some("21"))
+    );
+    let mut normalized = String::with_capacity(authority.len());
+    if let Some(user_info) = user_info {
+        normalized.push_str(user_info);
+        normalized.push('@');
+    }
+    normalized.push_str(&host.to_ascii_lowercase());
+    if let Some(port) = port {
+        if !is_default_port {
+            normalized.push(':');
+            normalized.push_str(port);
+        }
+    }
+    normalized
+}
+
+/// Indicates if a query parameter is only used for tracking.
+fn is_tracking_param(param: &str) -> bool {
+    let name = match param.find('=') {
+        Some(index) => &param[..index],
+        None => param,
+    };
+    let name = name.to_ascii_lowercase();
+    name.starts_with("utm_") || TRACKING_PARAMS.contains(&name.as_str())
+}
+
+/// Appends a URL component to a string, uppercasing the hex digits in
+/// percent-encoded octets.
+fn push_percent_encoded(normalized: &mut String, component: &str) {
+    let mut chars = component.chars();
+    while let Some(c) = chars.next() {
+        normalized.push(c);
+        if c == '%' {
+            let hex = chars.clone().take(2).collect::<String>();
+            if hex.len() == 2 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                normalized.push_str(&hex.to_ascii_uppercase());
+                chars.nth(1);
+            }
+        }
+    }
+}
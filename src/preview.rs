@@ -0,0 +1,229 @@
+// Copyright 2018-2019 Mozilla
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+
+use crate::driver::{AbortSignal, DefaultAbortSignal};
+use crate::error::Result;
+use crate::guid::Guid;
+use crate::merge::{CompletionOps, MergedRoot};
+
+/// A summary of the changes that applying a merged tree would make, without
+/// applying them. Unlike `CompletionOps`, a preview owns all its GUIDs, so it
+/// can outlive the local and remote trees.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MergePreview {
+    /// New remote items that would be inserted into the local tree.
+    pub additions: Vec<Guid>,
+    /// Existing remote items that would replace their local counterparts.
+    pub updates: Vec<Guid>,
+    /// Existing local items that would move to a new parent or position.
+    pub moves: Vec<Guid>,
+    /// Local items that would be deleted.
+    pub local_deletions: Vec<Guid>,
+    /// Items that would be uploaded to the server.
+    pub uploads: Vec<Guid>,
+    /// Tombstones that would be uploaded to the server.
+    pub remote_deletions: Vec<Guid>,
+    /// New local items that would be deduped to matching remote items.
+    pub dupes: Vec<DedupedItem>,
+    /// The number of completion ops in each list.
+    pub counts: CompletionOpCounts,
+}
+
+impl MergePreview {
+    /// Summarizes the completion ops for a merged root, without interruption.
+    #[inline]
+    pub fn new(merged_root: &MergedRoot<'_>) -> MergePreview {
+        MergePreview::with_signal(merged_root, &DefaultAbortSignal).unwrap()
+    }
+
+    /// Summarizes the completion ops for a merged root. The abort signal can
+    /// be used to interrupt building the preview. Building the ops reports
+    /// progress, and records telemetry unless the merged root is for a
+    /// preview, like `MergedRoot::completion_ops_with_signal`.
+    pub fn with_signal(
+        merged_root: &MergedRoot<'_>,
+        signal: &impl AbortSignal,
+    ) -> Result<MergePreview> {
+        let ops = merged_root.completion_ops_with_signal(signal)?;
+        let mut preview = MergePreview {
+            counts: CompletionOpCounts::from(&ops),
+            ..MergePreview::default()
+        };
+        for op in &ops.apply_remote_items {
+            if op.merged_node.merge_state.local_node().is_some() {
+                preview.updates.push(op.merged_node.guid.clone());
+            } else {
+                preview.additions.push(op.merged_node.guid.clone());
+            }
+        }
+        for op in &ops.apply_new_local_structure {
+            if op.merged_node.merge_state.local_node().is_some() {
+                preview.moves.push(op.merged_node.guid.clone());
+            }
+        }
+        for op in &ops.change_guids {
+            // Changing a local GUID to match an existing remote item dedupes
+            // it; changing it to a new GUID fixes an invalid one.
+            if let Some(remote_node) = op.merged_node.merge_state.remote_node() {
+                if remote_node.guid == op.merged_node.guid {
+                    preview.dupes.push(DedupedItem {
+                        local_guid: op.local_node().guid.clone(),
+                        remote_guid: remote_node.guid.clone(),
+                    });
+                }
+            }
+        }
+        preview.local_deletions.extend(
+            ops.delete_local_items
+                .iter()
+                .map(|op| op.local_node().guid.clone()),
+        );
+        preview.uploads.extend(
+            ops.upload_items
+                .iter()
+                .map(|op| op.merged_node.guid.clone()),
+        );
+        preview
+            .remote_deletions
+            .extend(ops.upload_tombstones.iter().map(|op| op.guid().clone()));
+        Ok(preview)
+    }
+
+    /// Returns `true` if applying the merged tree wouldn't change anything.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+}
+
+impl fmt::Display for MergePreview {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn write_guids(f: &mut fmt::Formatter<'_>, label: &str, guids: &[Guid]) -> fmt::Result {
+            writeln!(
+                f,
+                "{}: [{}]",
+                label,
+                guids
+                    .iter()
+                    .map(Guid::as_str)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        }
+        write_guids(f, "Add", &self.additions)?;
+        write_guids(f, "Update", &self.updates)?;
+        write_guids(f, "Move", &self.moves)?;
+        write_guids(f, "Delete locally", &self.local_deletions)?;
+        write_guids(f, "Upload", &self.uploads)?;
+        write_guids(f, "Delete remotely", &self.remote_deletions)?;
+        writeln!(
+            f,
+            "Dedupe: [{}]",
+            self.dupes
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )?;
+        write!(f, "{}", self.counts)
+    }
+}
+
+/// A new local item that would take the GUID of a remote item with similar
+/// contents.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct DedupedItem {
+    pub local_guid: Guid,
+    pub remote_guid: Guid,
+}
+
+impl fmt::Display for DedupedItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} as {}", self.local_guid, self.remote_guid)
+    }
+}
+
+/// Records the number of ops in each `CompletionOps` list.
+#[derive(Clone, Copy, Default, Debug, Eq, Hash, PartialEq)]
+pub struct CompletionOpCounts {
+    pub change_guids: usize,
+    pub apply_remote_items: usize,
+    pub apply_merged_tags: usize,
+    pub apply_merged_content: usize,
+    pub apply_new_local_structure: usize,
+    pub set_local_unmerged: usize,
+    pub set_local_merged: usize,
+    pub set_remote_merged: usize,
+    pub delete_local_tombstones: usize,
+    pub insert_local_tombstones: usize,
+    pub delete_local_items: usize,
+    pub upload_items: usize,
+    pub upload_tombstones: usize,
+}
+
+impl CompletionOpCounts {
+    /// Returns `true` if there are no completion ops.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        *self == CompletionOpCounts::default()
+    }
+}
+
+impl<'a, 't> From<&'a CompletionOps<'t>> for CompletionOpCounts {
+    fn from(ops: &'a CompletionOps<'t>) -> CompletionOpCounts {
+        CompletionOpCounts {
+            change_guids: ops.change_guids.len(),
+            apply_remote_items: ops.apply_remote_items.len(),
+            apply_merged_tags: ops.apply_merged_tags.len(),
+            apply_merged_content: ops.apply_merged_content.len(),
+            apply_new_local_structure: ops.apply_new_local_structure.len(),
+            set_local_unmerged: ops.set_local_unmerged.len(),
+            set_local_merged: ops.set_local_merged.len(),
+            set_remote_merged: ops.set_remote_merged.len(),
+            delete_local_tombstones: ops.delete_local_tombstones.len(),
+            insert_local_tombstones: ops.insert_local_tombstones.len(),
+            delete_local_items: ops.delete_local_items.len(),
+            upload_items: ops.upload_items.len(),
+            upload_tombstones: ops.upload_tombstones.len(),
+        }
+    }
+}
+
+impl fmt::Display for CompletionOpCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Ops: change GUIDs = {}, apply remote items = {}, apply merged tags = {}, \
+             apply merged content = {}, apply new local structure = {}, set local \
+             unmerged = {}, set local merged = {}, set remote merged = {}, delete local \
+             tombstones = {}, insert local tombstones = {}, delete local items = {}, \
+             upload items = {}, upload tombstones = {}",
+            self.change_guids,
+            self.apply_remote_items,
+            self.apply_merged_tags,
+            self.apply_merged_content,
+            self.apply_new_local_structure,
+            self.set_local_unmerged,
+            self.set_local_merged,
+            self.set_remote_merged,
+            self.delete_local_tombstones,
+            self.insert_local_tombstones,
+            self.delete_local_items,
+            self.upload_items,
+            self.upload_tombstones
+        )
+    }
+}
@@ -0,0 +1,194 @@
+// Copyright 2018-2019 Mozilla
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+};
+
+use crate::guid::Guid;
+use crate::merge::to_strings;
+use crate::tree::{DivergedParent, Node, Problem, Tree, Validity};
+
+/// Why an item needs to be reuploaded to repair a tree.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum RepairReason {
+    /// The item's `parentid` and its parents' `children` disagree, or it's
+    /// in multiple folders.
+    DivergedParent,
+    /// The item didn't have a parent, and was moved to the default folder.
+    Orphan,
+    /// The item is a user content root that wasn't a child of the Places
+    /// root.
+    MisparentedRoot,
+    /// The folder's `children` are wrong: they mention missing, deleted, or
+    /// misparented items, or don't mention items that were moved into the
+    /// folder.
+    Children,
+    /// The item is valid, but flagged for reupload.
+    Reupload,
+}
+
+impl fmt::Display for RepairReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// Records to upload to fix the structure of a tree built from the server,
+/// without merging it with a local tree.
+///
+/// The uploaded records should be built from the repaired tree, so that each
+/// item's `parentid` is its parent in the tree, and each folder's `children`
+/// are its children in the tree. Items that don't sync, like livemarks and
+/// orphaned queries, are zombies that shouldn't be on the server at all, so
+/// they're replaced with tombstones.
+///
+/// Invalid items that should be replaced with a local copy can't be repaired
+/// without a local tree, so they're left as-is.
+#[derive(Debug, Default)]
+pub struct RepairOps<'t> {
+    pub upload_items: Vec<RepairItem<'t>>,
+    pub upload_tombstones: Vec<RepairTombstone<'t>>,
+}
+
+impl<'t> RepairOps<'t> {
+    /// Finds the records to upload to repair a tree. Items are listed
+    /// parents first, in tree order.
+    pub fn new(tree: &'t Tree) -> RepairOps<'t> {
+        let mut reasons: HashMap<&'t Guid, BTreeSet<RepairReason>> = HashMap::new();
+        let mut note = |guid: &'t Guid, reason| {
+            reasons.entry(guid).or_default().insert(reason);
+        };
+
+        for summary in tree.problems().summarize() {
+            let guid = summary.guid();
+            let parents = match summary.problem() {
+                Problem::Orphan => {
+                    note(guid, RepairReason::Orphan);
+                    &[][..]
+                }
+                Problem::MisparentedRoot(parents) => {
+                    note(guid, RepairReason::MisparentedRoot);
+                    parents.as_slice()
+                }
+                Problem::DivergedParents(parents) => {
+                    note(guid, RepairReason::DivergedParent);
+                    parents.as_slice()
+                }
+                Problem::MissingChild { .. } | Problem::DeletedChild { .. } => {
+                    note(guid, RepairReason::Children);
+                    continue;
+                }
+                Problem::InvalidItem => continue,
+            };
+            // Folders that mention the item in their `children`, but aren't
+            // its parent in the tree, need new children.
+            for parent in parents {
+                if let DivergedParent::ByChildren(parent_guid) = parent {
+                    note(parent_guid, RepairReason::Children);
+                }
+            }
+            // So does the item's new parent.
+            if let Some(parent) = tree.node_for_guid(guid).and_then(|node| node.parent()) {
+                note(&parent.item().guid, RepairReason::Children);
+            }
+        }
+
+        let mut ops = RepairOps::default();
+        let mut stack = vec![tree.root()];
+        while let Some(node) = stack.pop() {
+            stack.extend(node.children().collect::<Vec<_>>().into_iter().rev());
+            if node.is_root() {
+                continue;
+            }
+            if !node.is_syncable() {
+                ops.upload_tombstones
+                    .push(RepairTombstone(&node.item().guid));
+                if let Some(parent) = node.parent().filter(|parent| parent.is_syncable()) {
+                    note(&parent.item().guid, RepairReason::Children);
+                }
+                continue;
+            }
+            if node.validity == Validity::Reupload {
+                note(&node.item().guid, RepairReason::Reupload);
+            }
+        }
+
+        // Now that we know all items to reupload, list them in tree order.
+        let mut stack = vec![tree.root()];
+        while let Some(node) = stack.pop() {
+            stack.extend(node.children().collect::<Vec<_>>().into_iter().rev());
+            if !node.is_syncable() {
+                continue;
+            }
+            if let Some(reasons) = reasons.remove(&node.guid) {
+                ops.upload_items.push(RepairItem { node, reasons });
+            }
+        }
+        ops
+    }
+
+    /// Returns `true` if the tree doesn't need repairs.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.upload_items.is_empty() && self.upload_tombstones.is_empty()
+    }
+
+    /// Returns a printable summary of all repair ops.
+    pub fn summarize(&self) -> Vec<String> {
+        to_strings(&self.upload_items)
+            .chain(to_strings(&self.upload_tombstones))
+            .collect()
+    }
+}
+
+/// A repair op to reupload an item.
+#[derive(Clone, Debug)]
+pub struct RepairItem<'t> {
+    pub node: Node<'t>,
+    /// Why the item needs to be reuploaded.
+    pub reasons: BTreeSet<RepairReason>,
+}
+
+impl<'t> fmt::Display for RepairItem<'t> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Upload item {} (", self.node.guid)?;
+        for (i, reason) in self.reasons.iter().enumerate() {
+            if i != 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}", reason)?;
+        }
+        f.write_str(")")
+    }
+}
+
+/// A repair op to upload a tombstone for a zombie item.
+#[derive(Clone, Copy, Debug)]
+pub struct RepairTombstone<'t>(&'t Guid);
+
+impl<'t> RepairTombstone<'t> {
+    /// Returns the GUID to use for the tombstone.
+    #[inline]
+    pub fn guid(self) -> &'t Guid {
+        self.0
+    }
+}
+
+impl<'t> fmt::Display for RepairTombstone<'t> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Upload tombstone {}", self.0)
+    }
+}
@@ -0,0 +1,113 @@
+// Copyright 2018-2019 Mozilla
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::guid::{Guid, MENU_GUID, MOBILE_GUID, TAGS_GUID, TOOLBAR_GUID, UNFILED_GUID};
+
+/// Describes the built-in top-level folders of a bookmark tree. Built-in roots
+/// must be children of the tree root, can't be deleted or deduped, and their
+/// values always come from the local tree.
+///
+/// The default roots match Places. Apps with different top-level folders can
+/// describe their own roots, and pass them to `Tree::with_roots`. The local
+/// and remote trees must have the same roots.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Roots {
+    built_in_guids: Vec<Guid>,
+    orphans_guid: Option<Guid>,
+    tags_guid: Option<Guid>,
+}
+
+impl Roots {
+    /// Returns an empty root set, without any built-in roots.
+    pub fn new() -> Roots {
+        Roots {
+            built_in_guids: Vec::new(),
+            orphans_guid: None,
+            tags_guid: None,
+        }
+    }
+
+    /// Returns the Places roots: the toolbar, menu, unfiled, and mobile user
+    /// content roots, and the tags root. Orphans are reparented to the tree
+    /// root, unless the tree builder says otherwise.
+    pub fn places() -> Roots {
+        let mut roots = Roots::new();
+        roots
+            .built_in_root(TOOLBAR_GUID)
+            .built_in_root(MENU_GUID)
+            .built_in_root(UNFILED_GUID)
+            .built_in_root(MOBILE_GUID)
+            .tags_root(TAGS_GUID);
+        roots
+    }
+
+    /// Adds a built-in root.
+    pub fn built_in_root(&mut self, guid: Guid) -> &mut Roots {
+        if !self.built_in_guids.contains(&guid) {
+            self.built_in_guids.push(guid);
+        }
+        self
+    }
+
+    /// Sets the built-in root for reparented orphans. Trees reparent orphans
+    /// to this root by default, unless `Builder::reparent_orphans_to` is set.
+    pub fn orphans_root(&mut self, guid: Guid) -> &mut Roots {
+        self.orphans_guid = Some(guid.clone());
+        self.built_in_root(guid)
+    }
+
+    /// Sets the built-in root that holds tag folders. Tag folders aren't
+    /// uploaded, and are used to migrate tags.
+    pub fn tags_root(&mut self, guid: Guid) -> &mut Roots {
+        self.tags_guid = Some(guid.clone());
+        self.built_in_root(guid)
+    }
+
+    /// Returns the GUIDs of all built-in roots, in the order they were added.
+    #[inline]
+    pub fn built_in_guids(&self) -> &[Guid] {
+        &self.built_in_guids
+    }
+
+    /// Returns the GUID of the root for reparented orphans, if set.
+    #[inline]
+    pub fn orphans_guid(&self) -> Option<&Guid> {
+        self.orphans_guid.as_ref()
+    }
+
+    /// Returns the GUID of the tags root, if set.
+    #[inline]
+    pub fn tags_guid(&self) -> Option<&Guid> {
+        self.tags_guid.as_ref()
+    }
+
+    /// Indicates if the GUID is a built-in root.
+    #[inline]
+    pub fn is_built_in_root(&self, guid: &Guid) -> bool {
+        self.built_in_guids.contains(guid)
+    }
+
+    /// Indicates if the GUID is the tags root.
+    #[inline]
+    pub fn is_tags_root(&self, guid: &Guid) -> bool {
+        self.tags_guid.as_ref() == Some(guid)
+    }
+}
+
+impl Default for Roots {
+    #[inline]
+    fn default() -> Roots {
+        Roots::places()
+    }
+}
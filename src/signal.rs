@@ -0,0 +1,162 @@
+// Copyright 2018-2019 Mozilla
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::driver::AbortSignal;
+
+impl<S: AbortSignal + ?Sized> AbortSignal for &S {
+    #[inline]
+    fn aborted(&self) -> bool {
+        (**self).aborted()
+    }
+}
+
+/// A thread-safe signal backed by an atomic flag. The signal hands out
+/// `AbortHandle`s, which can be cloned and sent to other threads to abort a
+/// merge that's running on this one.
+#[derive(Debug, Default)]
+pub struct AtomicAbortSignal {
+    aborted: Arc<AtomicBool>,
+}
+
+impl AtomicAbortSignal {
+    /// Creates a signal that hasn't been aborted.
+    #[inline]
+    pub fn new() -> AtomicAbortSignal {
+        AtomicAbortSignal::default()
+    }
+
+    /// Returns a handle for aborting this signal.
+    #[inline]
+    pub fn handle(&self) -> AbortHandle {
+        AbortHandle {
+            aborted: self.aborted.clone(),
+        }
+    }
+
+    /// Aborts the signal.
+    #[inline]
+    pub fn abort(&self) {
+        self.aborted.store(true, Ordering::Release);
+    }
+}
+
+impl AbortSignal for AtomicAbortSignal {
+    #[inline]
+    fn aborted(&self) -> bool {
+        self.aborted.load(Ordering::Acquire)
+    }
+}
+
+/// A handle that aborts an `AtomicAbortSignal`.
+#[derive(Clone, Debug)]
+pub struct AbortHandle {
+    aborted: Arc<AtomicBool>,
+}
+
+impl AbortHandle {
+    /// Aborts the signal for this handle.
+    #[inline]
+    pub fn abort(&self) {
+        self.aborted.store(true, Ordering::Release);
+    }
+
+    /// Indicates if the signal for this handle was aborted.
+    #[inline]
+    pub fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::Acquire)
+    }
+}
+
+/// A signal that aborts once a deadline passes.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct DeadlineSignal {
+    deadline: Instant,
+}
+
+impl DeadlineSignal {
+    /// Creates a signal that aborts at the given instant.
+    #[inline]
+    pub fn new(deadline: Instant) -> DeadlineSignal {
+        DeadlineSignal { deadline }
+    }
+
+    /// Creates a signal that aborts after the given time budget, starting
+    /// now.
+    #[inline]
+    pub fn after(budget: Duration) -> DeadlineSignal {
+        DeadlineSignal::new(Instant::now() + budget)
+    }
+
+    /// Returns the deadline for this signal.
+    #[inline]
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+}
+
+impl AbortSignal for DeadlineSignal {
+    #[inline]
+    fn aborted(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+}
+
+/// A signal that aborts when either of two signals aborts. For example,
+/// combining an `AtomicAbortSignal` and a `DeadlineSignal` gives a merge a
+/// time budget that the user can also cancel. Combinators can be nested to
+/// combine more than two signals.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct AnySignal<A, B>(pub A, pub B);
+
+impl<A, B> AnySignal<A, B> {
+    /// Creates a signal that aborts when either signal aborts.
+    #[inline]
+    pub fn new(first: A, second: B) -> AnySignal<A, B> {
+        AnySignal(first, second)
+    }
+}
+
+impl<A: AbortSignal, B: AbortSignal> AbortSignal for AnySignal<A, B> {
+    #[inline]
+    fn aborted(&self) -> bool {
+        self.0.aborted() || self.1.aborted()
+    }
+}
+
+/// A signal that aborts only when both of two signals abort.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct AllSignal<A, B>(pub A, pub B);
+
+impl<A, B> AllSignal<A, B> {
+    /// Creates a signal that aborts when both signals abort.
+    #[inline]
+    pub fn new(first: A, second: B) -> AllSignal<A, B> {
+        AllSignal(first, second)
+    }
+}
+
+impl<A: AbortSignal, B: AbortSignal> AbortSignal for AllSignal<A, B> {
+    #[inline]
+    fn aborted(&self) -> bool {
+        self.0.aborted() && self.1.aborted()
+    }
+}
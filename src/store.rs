@@ -20,6 +20,7 @@ use crate::driver::{
 use crate::error::Error;
 use crate::guid::Guid;
 use crate::merge::{MergedRoot, Merger};
+use crate::preview::MergePreview;
 use crate::tree::Tree;
 
 /// A store is the main interface to Dogear. It implements methods for building
@@ -59,56 +60,127 @@ pub trait Store {
         driver: &impl Driver,
         signal: &impl AbortSignal,
     ) -> Result<Self::Ok, Self::Error> {
+        let _sync_span = enter_span!("dogear.sync");
+        let (local_tree, remote_tree) = fetch_trees(self, driver, signal, true)?;
+        let merged_root = merge_trees(driver, signal, &local_tree, &remote_tree, true)?;
+
         signal.err_if_aborted()?;
-        debug!(driver, "Building local tree");
-        let (local_tree, time) = with_timing(|| self.fetch_local_tree())?;
+        debug!(driver, "Applying merged tree");
+        let _apply_span = enter_span!(
+            "dogear.apply",
+            merged_nodes = merged_root.counts().merged_nodes
+        );
+        let (result, time) = with_timing(|| self.apply(merged_root))?;
+        driver.record_telemetry_event(TelemetryEvent::Apply(time));
+
+        Ok(result)
+    }
+
+    /// Builds a merged tree using the default merge driver, and returns a
+    /// preview of its changes without applying it.
+    fn merge_preview(&self) -> Result<MergePreview, Self::Error> {
+        self.merge_preview_with_driver(&DefaultDriver, &DefaultAbortSignal)
+    }
+
+    /// Builds a complete merged tree from the local and remote trees using
+    /// the given driver, and returns a preview of its changes. Unlike
+    /// `merge_with_driver`, this method never calls `apply`, so the store
+    /// is left untouched. A preview isn't a sync, so it doesn't record any
+    /// telemetry events.
+    fn merge_preview_with_driver(
+        &self,
+        driver: &impl Driver,
+        signal: &impl AbortSignal,
+    ) -> Result<MergePreview, Self::Error> {
+        let _sync_span = enter_span!("dogear.preview");
+        let (local_tree, remote_tree) = fetch_trees(self, driver, signal, false)?;
+        let merged_root = merge_trees(driver, signal, &local_tree, &remote_tree, false)?;
+
+        signal.err_if_aborted()?;
+        debug!(driver, "Building merge preview");
+        Ok(MergePreview::with_signal(&merged_root, signal)?)
+    }
+}
+
+/// Builds the local and remote trees from the store, and optionally records
+/// telemetry events for each.
+fn fetch_trees<S: Store + ?Sized>(
+    store: &S,
+    driver: &impl Driver,
+    signal: &impl AbortSignal,
+    record_telemetry: bool,
+) -> Result<(Tree, Tree), S::Error> {
+    signal.err_if_aborted()?;
+    debug!(driver, "Building local tree");
+    let span = enter_span!("dogear.fetch_local_tree");
+    let (local_tree, time) = with_timing(|| store.fetch_local_tree())?;
+    drop(span);
+    if record_telemetry {
         driver.record_telemetry_event(TelemetryEvent::FetchLocalTree(TreeStats {
             items: local_tree.size(),
             deletions: local_tree.deletions().len(),
             problems: local_tree.problems().counts(),
             time,
         }));
-        trace!(driver, "Built local tree from mirror\n{}", local_tree);
+    }
+    trace!(driver, "Built local tree from mirror\n{}", local_tree);
 
-        signal.err_if_aborted()?;
-        debug!(driver, "Building remote tree");
-        let (remote_tree, time) = with_timing(|| self.fetch_remote_tree())?;
+    signal.err_if_aborted()?;
+    debug!(driver, "Building remote tree");
+    let span = enter_span!("dogear.fetch_remote_tree");
+    let (remote_tree, time) = with_timing(|| store.fetch_remote_tree())?;
+    drop(span);
+    if record_telemetry {
         driver.record_telemetry_event(TelemetryEvent::FetchRemoteTree(TreeStats {
             items: remote_tree.size(),
-            deletions: local_tree.deletions().len(),
+            deletions: remote_tree.deletions().len(),
             problems: remote_tree.problems().counts(),
             time,
         }));
-        trace!(driver, "Built remote tree from mirror\n{}", remote_tree);
-
-        signal.err_if_aborted()?;
-        debug!(driver, "Building merged tree");
-        let merger = Merger::with_driver(driver, signal, &local_tree, &remote_tree);
-        let (merged_root, time) = with_timing(|| merger.merge())?;
-        driver.record_telemetry_event(TelemetryEvent::Merge(time, *merged_root.counts()));
-        trace!(
-            driver,
-            "Built new merged tree\n{}\nDelete Locally: [{}]\nDelete Remotely: [{}]",
-            merged_root.node().to_ascii_string(),
-            merged_root
-                .local_deletions()
-                .map(Guid::as_str)
-                .collect::<Vec<_>>()
-                .join(", "),
-            merged_root
-                .remote_deletions()
-                .map(Guid::as_str)
-                .collect::<Vec<_>>()
-                .join(", ")
-        );
+    }
+    trace!(driver, "Built remote tree from mirror\n{}", remote_tree);
 
-        signal.err_if_aborted()?;
-        debug!(driver, "Applying merged tree");
-        let (result, time) = with_timing(|| self.apply(merged_root))?;
-        driver.record_telemetry_event(TelemetryEvent::Apply(time));
+    Ok((local_tree, remote_tree))
+}
 
-        Ok(result)
+/// Merges the local and remote trees, and optionally records telemetry
+/// events for the merge and each of its phases.
+fn merge_trees<'t, D: Driver, A: AbortSignal>(
+    driver: &'t D,
+    signal: &'t A,
+    local_tree: &'t Tree,
+    remote_tree: &'t Tree,
+    record_telemetry: bool,
+) -> Result<MergedRoot<'t>, Error> {
+    signal.err_if_aborted()?;
+    debug!(driver, "Building merged tree");
+    let _span = enter_span!(
+        "dogear.merge",
+        local_items = local_tree.size(),
+        remote_items = remote_tree.size()
+    );
+    let merger = Merger::with_driver(driver, signal, local_tree, remote_tree)
+        .record_telemetry(record_telemetry);
+    let (merged_root, time) = with_timing(|| merger.merge())?;
+    if record_telemetry {
+        driver.record_telemetry_event(TelemetryEvent::Merge(time, *merged_root.counts()));
     }
+    trace!(
+        driver,
+        "Built new merged tree\n{}\nDelete Locally: [{}]\nDelete Remotely: [{}]",
+        merged_root.node().to_ascii_string(),
+        merged_root
+            .local_deletions()
+            .map(Guid::as_str)
+            .collect::<Vec<_>>()
+            .join(", "),
+        merged_root
+            .remote_deletions()
+            .map(Guid::as_str)
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    Ok(merged_root)
 }
 
 fn with_timing<T, E>(run: impl FnOnce() -> Result<T, E>) -> Result<(T, Duration), E> {
@@ -0,0 +1,234 @@
+// Copyright 2018-2019 Mozilla
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::VecDeque, time::Duration};
+
+use crate::driver::{MergePhase, PhaseStats, TelemetryEvent, TreeStats};
+use crate::merge::StructureCounts;
+
+/// A report for one sync, built from the telemetry events that the merger
+/// and store record. Drivers can keep a report for the current sync, pass
+/// each event from `Driver::record_telemetry_event` to `SyncReport::record`,
+/// and submit the report when the sync finishes. With the `serde` feature,
+/// reports can be serialized.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SyncReport {
+    /// Stats for building the local tree.
+    pub local_tree: Option<TreeStats>,
+    /// Stats for building the remote tree.
+    pub remote_tree: Option<TreeStats>,
+    /// The time taken to merge the trees.
+    pub merge_time: Option<Duration>,
+    /// Structure change counts for the merged tree.
+    pub structure_counts: Option<StructureCounts>,
+    /// The time taken to apply the merged tree.
+    pub apply_time: Option<Duration>,
+    /// The number of conflicts where modification times and ages disagreed
+    /// about the newer side.
+    pub clock_skews: usize,
+    /// Timings for each phase of the merge, in the order they ran.
+    pub merge_phases: Vec<PhaseStats>,
+}
+
+impl SyncReport {
+    /// Creates an empty report.
+    #[inline]
+    pub fn new() -> SyncReport {
+        SyncReport::default()
+    }
+
+    /// Adds a telemetry event to the report.
+    pub fn record(&mut self, event: TelemetryEvent) {
+        match event {
+            TelemetryEvent::FetchLocalTree(stats) => self.local_tree = Some(stats),
+            TelemetryEvent::FetchRemoteTree(stats) => self.remote_tree = Some(stats),
+            TelemetryEvent::Merge(time, counts) => {
+                self.merge_time = Some(time);
+                self.structure_counts = Some(counts);
+            }
+            TelemetryEvent::Apply(time) => self.apply_time = Some(time),
+            TelemetryEvent::ClockSkew(_) => self.clock_skews += 1,
+            TelemetryEvent::MergePhase(stats) => self.merge_phases.push(stats),
+        }
+    }
+
+    /// Returns the stats for a merge phase, or `None` if the report doesn't
+    /// have them.
+    pub fn merge_phase(&self, phase: MergePhase) -> Option<&PhaseStats> {
+        self.merge_phases.iter().find(|stats| stats.phase == phase)
+    }
+
+    /// Returns the total time for all recorded steps.
+    pub fn total_time(&self) -> Duration {
+        self.local_tree
+            .iter()
+            .chain(self.remote_tree.iter())
+            .map(|stats| stats.time)
+            .chain(self.merge_time)
+            .chain(self.apply_time)
+            .sum()
+    }
+}
+
+/// A value that the aggregator can summarize across syncs. Times are in
+/// milliseconds.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Metric {
+    FetchLocalTreeTime,
+    FetchRemoteTreeTime,
+    MergeTime,
+    ApplyTime,
+    TotalTime,
+    LocalItems,
+    RemoteItems,
+    LocalProblems,
+    RemoteProblems,
+    MergedNodes,
+    Dupes,
+    ClockSkews,
+    PhaseTime(MergePhase),
+    PhaseNodes(MergePhase),
+}
+
+impl Metric {
+    /// Returns the value of this metric for a report, or `None` if the
+    /// report doesn't have the event for it.
+    pub fn value(self, report: &SyncReport) -> Option<u64> {
+        fn millis(time: Duration) -> u64 {
+            time.as_millis() as u64
+        }
+        match self {
+            Metric::FetchLocalTreeTime => report.local_tree.map(|stats| millis(stats.time)),
+            Metric::FetchRemoteTreeTime => report.remote_tree.map(|stats| millis(stats.time)),
+            Metric::MergeTime => report.merge_time.map(millis),
+            Metric::ApplyTime => report.apply_time.map(millis),
+            Metric::TotalTime => Some(millis(report.total_time())),
+            Metric::LocalItems => report.local_tree.map(|stats| stats.items as u64),
+            Metric::RemoteItems => report.remote_tree.map(|stats| stats.items as u64),
+            Metric::LocalProblems => report.local_tree.map(|stats| stats.problems.total() as u64),
+            Metric::RemoteProblems => report
+                .remote_tree
+                .map(|stats| stats.problems.total() as u64),
+            Metric::MergedNodes => report
+                .structure_counts
+                .map(|counts| counts.merged_nodes as u64),
+            Metric::Dupes => report.structure_counts.map(|counts| counts.dupes as u64),
+            Metric::ClockSkews => Some(report.clock_skews as u64),
+            Metric::PhaseTime(phase) => report.merge_phase(phase).map(|stats| millis(stats.time)),
+            Metric::PhaseNodes(phase) => report.merge_phase(phase).map(|stats| stats.nodes as u64),
+        }
+    }
+}
+
+/// Percentiles for a metric across the reports in an aggregator.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Percentiles {
+    /// The number of reports with a value for the metric.
+    pub count: usize,
+    pub min: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+/// Aggregates reports over a rolling window of recent syncs. Once the window
+/// is full, adding a report evicts the oldest one.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TelemetryAggregator {
+    capacity: usize,
+    reports: VecDeque<SyncReport>,
+}
+
+impl TelemetryAggregator {
+    /// Creates an aggregator that keeps the last `capacity` reports.
+    pub fn new(capacity: usize) -> TelemetryAggregator {
+        assert!(capacity > 0, "Aggregator must keep at least one report");
+        TelemetryAggregator {
+            capacity,
+            reports: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Adds a report for a finished sync.
+    pub fn add(&mut self, report: SyncReport) {
+        if self.reports.len() == self.capacity {
+            self.reports.pop_front();
+        }
+        self.reports.push_back(report);
+    }
+
+    /// Returns the number of reports in the window.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.reports.len()
+    }
+
+    /// Indicates if the aggregator doesn't have any reports.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.reports.is_empty()
+    }
+
+    /// Returns an iterator for all reports in the window, oldest first.
+    #[inline]
+    pub fn reports(&self) -> impl Iterator<Item = &SyncReport> {
+        self.reports.iter()
+    }
+
+    /// Returns the value of a metric at the given percentile, between 0 and
+    /// 100, using the nearest-rank method. Returns `None` if no reports have
+    /// a value for the metric.
+    pub fn percentile(&self, metric: Metric, percentile: f64) -> Option<u64> {
+        let values = self.sorted_values(metric);
+        nearest_rank(&values, percentile)
+    }
+
+    /// Returns common percentiles for a metric, or `None` if no reports have
+    /// a value for it.
+    pub fn percentiles(&self, metric: Metric) -> Option<Percentiles> {
+        let values = self.sorted_values(metric);
+        Some(Percentiles {
+            count: values.len(),
+            min: *values.first()?,
+            p50: nearest_rank(&values, 50.0)?,
+            p90: nearest_rank(&values, 90.0)?,
+            p99: nearest_rank(&values, 99.0)?,
+            max: *values.last()?,
+        })
+    }
+
+    fn sorted_values(&self, metric: Metric) -> Vec<u64> {
+        let mut values = self
+            .reports
+            .iter()
+            .filter_map(|report| metric.value(report))
+            .collect::<Vec<_>>();
+        values.sort_unstable();
+        values
+    }
+}
+
+/// Returns the nearest-rank percentile of sorted values.
+fn nearest_rank(values: &[u64], percentile: f64) -> Option<u64> {
+    if values.is_empty() {
+        return None;
+    }
+    let percentile = percentile.clamp(0.0, 100.0);
+    let rank = (percentile / 100.0 * values.len() as f64).ceil() as usize;
+    Some(values[rank.max(1) - 1])
+}
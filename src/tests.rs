@@ -13,21 +13,38 @@
 // limitations under the License.
 
 use std::{
-    cell::Cell,
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::{BTreeSet, HashMap, HashSet},
     convert::{TryFrom, TryInto},
     sync::Once,
+    thread,
+    time::{Duration, Instant},
 };
 
-use env_logger;
-
-use crate::driver::{DefaultAbortSignal, Driver};
+use crate::checkpoint::MergeCheckpoint;
+use crate::clock::{Causality, ConflictOrdering, VersionVector};
+use crate::driver::{
+    AbortSignal, DefaultAbortSignal, DefaultDriver, Driver, MergePhase, Progress, SeededGuidDriver,
+    TelemetryEvent, TreeStats,
+};
 use crate::error::{Error, ErrorKind, Result};
 use crate::guid::{Guid, ROOT_GUID, UNFILED_GUID};
-use crate::merge::{to_strings, Merger, StructureCounts};
+use crate::matcher::{CrossFolderDedupe, DupeKey, DupeMatcher};
+use crate::merge::{to_strings, Conflict, MergedRoot, Merger, Side, StructureCounts};
+use crate::normalize::normalize_url;
+use crate::preview::{CompletionOpCounts, DedupedItem, MergePreview};
+use crate::repair::{RepairOps, RepairReason};
+use crate::roots::Roots;
+use crate::signal::{AllSignal, AnySignal, AtomicAbortSignal, DeadlineSignal};
+use crate::store::Store;
+use crate::telemetry::{Metric, Percentiles, SyncReport, TelemetryAggregator};
 use crate::tree::{
     self, Builder, Content, DivergedParent, DivergedParentGuid, Item, Kind, MergeState, Problem,
     ProblemCounts, Problems, Tree, Validity,
 };
+use crate::undo::UndoJournal;
+use crate::validation::{ComparisonReport, ProblemKind, ProblemSample, Validator};
 
 #[derive(Debug)]
 struct Node {
@@ -60,7 +77,7 @@ impl TryFrom<Node> for Builder {
                     _ => return Err(err),
                 }
             }
-            b.parent_for(&guid).by_structure(&parent_guid)?;
+            b.parent_for(&guid).by_structure(parent_guid)?;
             for child in node.children {
                 inflate(b, &guid, child)?;
             }
@@ -139,6 +156,7 @@ impl MergedNode {
 }
 
 impl<'t> PartialEq<tree::MergedNode<'t>> for MergedNode {
+    #[allow(clippy::match_like_matches_macro)]
     fn eq(&self, other: &tree::MergedNode<'t>) -> bool {
         if self.guid != other.guid {
             return false;
@@ -773,6 +791,331 @@ fn value_structure_conflict() {
     assert_eq!(merged_root.counts(), &expected_telem);
 }
 
+#[test]
+fn driver_resolves_conflicts() {
+    before_each();
+
+    struct KeepLocal(RefCell<Vec<Guid>>);
+
+    impl Driver for KeepLocal {
+        fn resolve_conflict(&self, conflict: &Conflict<'_>) -> Option<Side> {
+            self.0.borrow_mut().push(conflict.local_node().guid.clone());
+            Some(Side::Local)
+        }
+    }
+
+    let local_tree = nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("bookmarkAAAA", Bookmark[needs_merge = true, age = 10]),
+            ("folderBBBBBB", Folder[needs_merge = true, age = 10], {
+                ("bookmarkCCCC", Bookmark[needs_merge = true, age = 10])
+            })
+        })
+    })
+    .into_tree()
+    .unwrap();
+
+    let remote_tree = nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("bookmarkAAAA", Bookmark[needs_merge = true, age = 5]),
+            ("folderBBBBBB", Folder[needs_merge = true, age = 5], {
+                ("bookmarkDDDD", Bookmark[needs_merge = true, age = 5])
+            })
+        })
+    })
+    .into_tree()
+    .unwrap();
+
+    // Without a driver, the newer remote side wins.
+    let merger = Merger::new(&local_tree, &remote_tree);
+    let merged_root = merger.merge().unwrap();
+
+    let expected_tree = merged_nodes!({
+        ("menu________", Local, {
+            ("bookmarkAAAA", Remote),
+            ("folderBBBBBB", RemoteWithNewRemoteStructure, {
+                ("bookmarkDDDD", Remote),
+                ("bookmarkCCCC", Local)
+            })
+        })
+    });
+
+    assert_eq!(&expected_tree, merged_root.node());
+    assert_eq!(
+        to_strings(merged_root.conflicts()).collect::<Vec<_>>(),
+        &[
+            "bookmarkAAAA (Bookmark; Age = 10ms; Unmerged) changed locally and remotely as \
+             bookmarkAAAA (Bookmark; Age = 5ms; Unmerged); taking Remote",
+            "folderBBBBBB (Folder; Age = 10ms; Unmerged) changed locally and remotely as \
+             folderBBBBBB (Folder; Age = 5ms; Unmerged); taking Remote",
+        ]
+    );
+    assert!(merged_root
+        .conflicts()
+        .iter()
+        .all(|resolved| !resolved.by_driver));
+
+    // With a driver, the chosen side wins, even though it's older.
+    let driver = KeepLocal(RefCell::new(Vec::new()));
+    let merger = Merger::with_driver(&driver, &DefaultAbortSignal, &local_tree, &remote_tree);
+    let merged_root = merger.merge().unwrap();
+
+    let expected_tree = merged_nodes!({
+        ("menu________", Local, {
+            ("bookmarkAAAA", Local),
+            ("folderBBBBBB", LocalWithNewLocalStructure, {
+                ("bookmarkCCCC", Local),
+                ("bookmarkDDDD", Remote)
+            })
+        })
+    });
+
+    assert_eq!(&expected_tree, merged_root.node());
+    assert_eq!(
+        *driver.0.borrow(),
+        vec![Guid::from("bookmarkAAAA"), Guid::from("folderBBBBBB")]
+    );
+    assert!(merged_root
+        .conflicts()
+        .iter()
+        .all(|resolved| resolved.by_driver && resolved.side == Side::Local));
+
+    assert_eq!(merged_root.deletions().count(), 0);
+
+    // The driver also picks sides for structure conflicts, where both sides
+    // move the same item into different folders. We see the conflict when we
+    // walk both parents, but should only ask the driver once.
+    let local_tree = nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("folderAAAAAA", Folder[needs_merge = true, age = 10]),
+            ("folderBBBBBB", Folder[needs_merge = true, age = 10], {
+                ("bookmarkCCCC", Bookmark[age = 10])
+            })
+        })
+    })
+    .into_tree()
+    .unwrap();
+
+    let remote_tree = nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("folderAAAAAA", Folder[needs_merge = true, age = 5], {
+                ("bookmarkCCCC", Bookmark[age = 5])
+            }),
+            ("folderBBBBBB", Folder[needs_merge = true, age = 5])
+        })
+    })
+    .into_tree()
+    .unwrap();
+
+    let driver = KeepLocal(RefCell::new(Vec::new()));
+    let merger = Merger::with_driver(&driver, &DefaultAbortSignal, &local_tree, &remote_tree);
+    let merged_root = merger.merge().unwrap();
+
+    let expected_tree = merged_nodes!({
+        ("menu________", Local, {
+            ("folderAAAAAA", Local),
+            ("folderBBBBBB", Local, {
+                ("bookmarkCCCC", Local)
+            })
+        })
+    });
+
+    assert_eq!(&expected_tree, merged_root.node());
+    let structure_conflicts = merged_root
+        .conflicts()
+        .iter()
+        .filter(|resolved| matches!(resolved.conflict, Conflict::Structure { .. }))
+        .collect::<Vec<_>>();
+    assert_eq!(structure_conflicts.len(), 1);
+    assert_eq!(
+        structure_conflicts[0].conflict.local_node().guid,
+        "bookmarkCCCC"
+    );
+    assert_eq!(structure_conflicts[0].side, Side::Local);
+    assert!(structure_conflicts[0].by_driver);
+    assert_eq!(
+        driver
+            .0
+            .borrow()
+            .iter()
+            .filter(|guid| *guid == "bookmarkCCCC")
+            .count(),
+        1
+    );
+    assert_eq!(merged_root.deletions().count(), 0);
+}
+
+#[test]
+fn modified_times() {
+    before_each();
+
+    struct RecordClockSkew(RefCell<Vec<Guid>>);
+
+    impl Driver for RecordClockSkew {
+        fn record_telemetry_event(&self, event: TelemetryEvent) {
+            if let TelemetryEvent::ClockSkew(skew) = event {
+                self.0.borrow_mut().push(skew.guid);
+            }
+        }
+    }
+
+    // The local clock is 500ms behind the server.
+    let mut local_builder = Builder::try_from(nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("bookmarkAAAA", Bookmark[needs_merge = true, age = 10, modified = Some(1000)]),
+            ("bookmarkBBBB", Bookmark[needs_merge = true, age = 10, modified = Some(600)]),
+            ("bookmarkCCCC", Bookmark[needs_merge = true, age = 3, modified = Some(2000)])
+        })
+    }))
+    .unwrap();
+    local_builder.clock_offset(500);
+    let local_tree = local_builder.into_tree().unwrap();
+
+    let remote_tree = nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("bookmarkAAAA", Bookmark[needs_merge = true, age = 5, modified = Some(1200)]),
+            ("bookmarkBBBB", Bookmark[needs_merge = true, age = 5, modified = Some(1300)]),
+            ("bookmarkCCCC", Bookmark[needs_merge = true, age = 7])
+        })
+    })
+    .into_tree()
+    .unwrap();
+
+    let driver = RecordClockSkew(RefCell::new(Vec::new()));
+    let merger = Merger::with_driver(&driver, &DefaultAbortSignal, &local_tree, &remote_tree);
+    let merged_root = merger.merge().unwrap();
+
+    // A is newer locally on the server clock, even though its local age is
+    // older. B is newer remotely by both measures. C doesn't have a remote
+    // modification time, so we fall back to comparing ages.
+    let expected_tree = merged_nodes!({
+        ("menu________", Local, {
+            ("bookmarkAAAA", Local),
+            ("bookmarkBBBB", Remote),
+            ("bookmarkCCCC", Local)
+        })
+    });
+
+    assert_eq!(&expected_tree, merged_root.node());
+    assert_eq!(*driver.0.borrow(), vec![Guid::from("bookmarkAAAA")]);
+
+    // Bogus times and offsets shouldn't overflow.
+    let mut builder = Builder::try_from(nodes!({
+        ("menu________", Folder, {
+            ("bookmarkAAAA", Bookmark[modified = Some(i64::MAX - 1)])
+        })
+    }))
+    .unwrap();
+    builder.clock_offset(500);
+    let tree = builder.into_tree().unwrap();
+    let server_modified = |guid: &str| {
+        tree.node_for_guid(&guid.into())
+            .and_then(|node| node.server_modified())
+    };
+    assert_eq!(server_modified("bookmarkAAAA"), Some(i64::MAX));
+}
+
+#[test]
+fn causal_conflict_ordering() {
+    before_each();
+
+    struct CausalDriver;
+
+    impl Driver for CausalDriver {
+        fn conflict_ordering(&self) -> ConflictOrdering {
+            ConflictOrdering::Causal
+        }
+    }
+
+    fn version(counters: &[(&str, u64)]) -> Option<VersionVector> {
+        Some(counters.iter().cloned().collect())
+    }
+
+    let phone_then_laptop = version(&[("phone", 1), ("laptop", 1)]);
+    let phone_twice = version(&[("phone", 2), ("laptop", 1)]);
+    let laptop_only = version(&[("laptop", 1)]);
+    let phone_only = version(&[("phone", 1)]);
+    assert_eq!(
+        phone_twice
+            .as_ref()
+            .unwrap()
+            .causality(phone_then_laptop.as_ref().unwrap()),
+        Causality::After
+    );
+    assert_eq!(
+        laptop_only
+            .as_ref()
+            .unwrap()
+            .causality(phone_only.as_ref().unwrap()),
+        Causality::Concurrent
+    );
+
+    // The local change to A saw the remote change, even though the local
+    // clock says it's older. The changes to B are concurrent.
+    let local_tree = nodes!({
+        ("menu________", Folder, {
+            ("bookmarkAAAA", Bookmark[needs_merge = true, age = 10, version = phone_twice]),
+            ("bookmarkBBBB", Bookmark[needs_merge = true, age = 10, version = laptop_only])
+        })
+    })
+    .into_tree()
+    .unwrap();
+
+    let remote_tree = nodes!({
+        ("menu________", Folder, {
+            ("bookmarkAAAA", Bookmark[needs_merge = true, age = 5, version = phone_then_laptop]),
+            ("bookmarkBBBB", Bookmark[needs_merge = true, age = 5, version = phone_only])
+        })
+    })
+    .into_tree()
+    .unwrap();
+
+    // By time, the remote side wins both conflicts.
+    let merger = Merger::new(&local_tree, &remote_tree);
+    let merged_root = merger.merge().unwrap();
+
+    let expected_tree = merged_nodes!({
+        ("menu________", Unchanged, {
+            ("bookmarkAAAA", Remote),
+            ("bookmarkBBBB", Remote)
+        })
+    });
+    assert_eq!(&expected_tree, merged_root.node());
+    assert_eq!(merged_root.conflicts().len(), 2);
+
+    // By causality, the local change to A wins without a conflict, and B is
+    // still a conflict that's broken by time.
+    let merger = Merger::with_driver(
+        &CausalDriver,
+        &DefaultAbortSignal,
+        &local_tree,
+        &remote_tree,
+    );
+    let merged_root = merger.merge().unwrap();
+
+    let expected_tree = merged_nodes!({
+        ("menu________", Unchanged, {
+            ("bookmarkAAAA", Local),
+            ("bookmarkBBBB", Remote)
+        })
+    });
+    assert_eq!(&expected_tree, merged_root.node());
+    assert_eq!(
+        merged_root
+            .conflicts()
+            .iter()
+            .map(|resolved| resolved.conflict.local_node().guid.clone())
+            .collect::<Vec<_>>(),
+        vec![Guid::from("bookmarkBBBB")]
+    );
+    assert_eq!(
+        merged_root.node().merged_children[0].merged_children[1]
+            .version()
+            .map(|version| version.to_string()),
+        Some("{laptop: 1, phone: 1}".to_string())
+    );
+}
+
 #[test]
 fn complex_move_with_additions() {
     before_each();
@@ -1403,18 +1746,24 @@ fn deduping_local_newer() {
         .content(Content::Bookmark {
             title: "A".into(),
             url_href: "http://example.com/a".into(),
+            keyword: None,
+            description: None,
         });
     local_tree_builder
         .mutate(&"bookmarkAAA2".into())
         .content(Content::Bookmark {
             title: "A".into(),
             url_href: "http://example.com/a".into(),
+            keyword: None,
+            description: None,
         });
     local_tree_builder
         .mutate(&"bookmarkAAA3".into())
         .content(Content::Bookmark {
             title: "A".into(),
             url_href: "http://example.com/a".into(),
+            keyword: None,
+            description: None,
         });
     let local_tree = local_tree_builder.into_tree().unwrap();
 
@@ -1431,12 +1780,16 @@ fn deduping_local_newer() {
         .content(Content::Bookmark {
             title: "A".into(),
             url_href: "http://example.com/a".into(),
+            keyword: None,
+            description: None,
         });
     remote_tree_builder
         .mutate(&"bookmarkAAA4".into())
         .content(Content::Bookmark {
             title: "A".into(),
             url_href: "http://example.com/a".into(),
+            keyword: None,
+            description: None,
         });
     let remote_tree = remote_tree_builder.into_tree().unwrap();
 
@@ -1503,6 +1856,8 @@ fn deduping_remote_newer() {
         .content(Content::Bookmark {
             title: "C".into(),
             url_href: "http://example.com/c".into(),
+            keyword: None,
+            description: None,
         });
     local_tree_builder
         .mutate(&"folderDDDDDD".into())
@@ -1512,6 +1867,8 @@ fn deduping_remote_newer() {
         .content(Content::Bookmark {
             title: "E".into(),
             url_href: "http://example.com/e".into(),
+            keyword: None,
+            description: None,
         });
     local_tree_builder
         .mutate(&"separatorFFF".into())
@@ -1524,12 +1881,16 @@ fn deduping_remote_newer() {
         .content(Content::Bookmark {
             title: "H".into(),
             url_href: "http://example.com/h".into(),
+            keyword: None,
+            description: None,
         });
     local_tree_builder
         .mutate(&"queryIIIIIII".into())
         .content(Content::Bookmark {
             title: "I".into(),
             url_href: "place:maxResults=10&sort=8".into(),
+            keyword: None,
+            description: None,
         });
     let local_tree = local_tree_builder.into_tree().unwrap();
 
@@ -1554,6 +1915,8 @@ fn deduping_remote_newer() {
         .content(Content::Bookmark {
             title: "C".into(),
             url_href: "http://example.com/c1".into(),
+            keyword: None,
+            description: None,
         });
     remote_tree_builder
         .mutate(&"folderDDDDD1".into())
@@ -1563,6 +1926,8 @@ fn deduping_remote_newer() {
         .content(Content::Bookmark {
             title: "E".into(),
             url_href: "http://example.com/e".into(),
+            keyword: None,
+            description: None,
         });
     remote_tree_builder
         .mutate(&"separatorFF1".into())
@@ -1575,12 +1940,16 @@ fn deduping_remote_newer() {
         .content(Content::Bookmark {
             title: "H".into(),
             url_href: "http://example.com/h".into(),
+            keyword: None,
+            description: None,
         });
     remote_tree_builder
         .mutate(&"queryIIIIII1".into())
         .content(Content::Bookmark {
             title: "I".into(),
             url_href: "place:maxResults=10&sort=8".into(),
+            keyword: None,
+            description: None,
         });
     let remote_tree = remote_tree_builder.into_tree().unwrap();
 
@@ -1643,12 +2012,16 @@ fn complex_deduping() {
         .content(Content::Bookmark {
             title: "B".into(),
             url_href: "http://example.com/b".into(),
+            keyword: None,
+            description: None,
         });
     local_tree_builder
         .mutate(&"bookmarkCCCC".into())
         .content(Content::Bookmark {
             title: "C".into(),
             url_href: "http://example.com/c".into(),
+            keyword: None,
+            description: None,
         });
     local_tree_builder
         .mutate(&"folderDDDDDD".into())
@@ -1658,6 +2031,8 @@ fn complex_deduping() {
         .content(Content::Bookmark {
             title: "E".into(),
             url_href: "http://example.com/e".into(),
+            keyword: None,
+            description: None,
         });
     local_tree_builder
         .mutate(&"folderFFFFFF".into())
@@ -1667,6 +2042,8 @@ fn complex_deduping() {
         .content(Content::Bookmark {
             title: "G".into(),
             url_href: "http://example.com/g".into(),
+            keyword: None,
+            description: None,
         });
     let local_tree = local_tree_builder.into_tree().unwrap();
 
@@ -1693,6 +2070,8 @@ fn complex_deduping() {
         .content(Content::Bookmark {
             title: "B".into(),
             url_href: "http://example.com/b".into(),
+            keyword: None,
+            description: None,
         });
     remote_tree_builder
         .mutate(&"folderDDDDD1".into())
@@ -1702,6 +2081,8 @@ fn complex_deduping() {
         .content(Content::Bookmark {
             title: "E".into(),
             url_href: "http://example.com/e".into(),
+            keyword: None,
+            description: None,
         });
     remote_tree_builder
         .mutate(&"folderFFFFF1".into())
@@ -1711,12 +2092,16 @@ fn complex_deduping() {
         .content(Content::Bookmark {
             title: "G".into(),
             url_href: "http://example.com/g".into(),
+            keyword: None,
+            description: None,
         });
     remote_tree_builder
         .mutate(&"bookmarkHHH1".into())
         .content(Content::Bookmark {
             title: "H".into(),
             url_href: "http://example.com/h".into(),
+            keyword: None,
+            description: None,
         });
     let remote_tree = remote_tree_builder.into_tree().unwrap();
 
@@ -2086,6 +2471,8 @@ fn deduping_ignores_parent_title() {
         .content(Content::Bookmark {
             title: "A".into(),
             url_href: "http://example.com/a".into(),
+            keyword: None,
+            description: None,
         });
     let local_tree = local_tree_builder.into_tree().unwrap();
 
@@ -2105,6 +2492,8 @@ fn deduping_ignores_parent_title() {
         .content(Content::Bookmark {
             title: "A".into(),
             url_href: "http://example.com/a".into(),
+            keyword: None,
+            description: None,
         });
     let remote_tree = remote_tree_builder.into_tree().unwrap();
 
@@ -2130,383 +2519,465 @@ fn deduping_ignores_parent_title() {
 }
 
 #[test]
-fn mismatched_compatible_bookmark_kinds() {
+fn normalize_urls() {
+    let cases = [
+        ("http://example.com/", "http://example.com"),
+        ("https://example.com", "http://example.com"),
+        ("HTTPS://Example.COM:443/Path/", "http://example.com/Path"),
+        ("http://example.com:80/a", "http://example.com/a"),
+        ("http://example.com:8080/a", "http://example.com:8080/a"),
+        ("http://example.com:443/a", "http://example.com:443/a"),
+        ("https://example.com:80/a", "http://example.com:80/a"),
+        ("http://user@[::1]:80/", "http://user@[::1]"),
+        (
+            "http://example.com/a?utm_source=feed&b=1&fbclid=2",
+            "http://example.com/a?b=1",
+        ),
+        ("http://example.com/a?utm_medium=x#", "http://example.com/a"),
+        (
+            "http://example.com/%e2%9c%93#top",
+            "http://example.com/%E2%9C%93#top",
+        ),
+        (
+            "  Place:sort=8&maxResults=10 ",
+            "place:sort=8&maxResults=10",
+        ),
+        ("not a url", "not a url"),
+    ];
+    for (url_href, expected) in &cases {
+        assert_eq!(
+            &normalize_url(url_href),
+            expected,
+            "Normalizing {}",
+            url_href
+        );
+    }
+}
+
+#[test]
+fn deduping_normalized_content() {
     before_each();
 
-    let local_tree = nodes!({
+    struct NormalizingDriver;
+
+    impl Driver for NormalizingDriver {
+        fn normalize_dupes(&self) -> bool {
+            true
+        }
+    }
+
+    let mut local_tree_builder = Builder::try_from(nodes!({
         ("menu________", Folder[needs_merge = true], {
-            ("queryAAAAAAA", Query[needs_merge = true]),
-            ("bookmarkBBBB", Bookmark[needs_merge = true, age = 5])
+            ("bookmarkAAA1", Bookmark[needs_merge = true]),
+            ("folderBBBBB1", Folder[needs_merge = true])
         })
-    })
-    .into_tree()
+    }))
     .unwrap();
+    local_tree_builder
+        .mutate(&"bookmarkAAA1".into())
+        .content(Content::Bookmark {
+            title: "A ".into(),
+            url_href: "http://example.com/a/".into(),
+            keyword: None,
+            description: None,
+        });
+    local_tree_builder
+        .mutate(&"folderBBBBB1".into())
+        .content(Content::Folder { title: "B".into() });
+    let local_tree = local_tree_builder.into_tree().unwrap();
 
-    let remote_tree = nodes!({
+    let mut remote_tree_builder = Builder::try_from(nodes!({
         ("menu________", Folder[needs_merge = true], {
-            ("queryAAAAAAA", Bookmark[needs_merge = true, age = 5]),
-            ("bookmarkBBBB", Query[needs_merge = true])
+            ("bookmarkAAAA", Bookmark[needs_merge = true]),
+            ("folderBBBBBB", Folder[needs_merge = true])
         })
-    })
-    .into_tree()
+    }))
     .unwrap();
+    remote_tree_builder
+        .mutate(&"bookmarkAAAA".into())
+        .content(Content::Bookmark {
+            title: "A".into(),
+            url_href: "https://EXAMPLE.com:443/a?utm_source=share".into(),
+            keyword: None,
+            description: None,
+        });
+    remote_tree_builder
+        .mutate(&"folderBBBBBB".into())
+        .content(Content::Folder {
+            title: "\tB".into(),
+        });
+    let remote_tree = remote_tree_builder.into_tree().unwrap();
 
+    // Without normalization, the items have different content, so we keep
+    // both.
     let merger = Merger::new(&local_tree, &remote_tree);
     let merged_root = merger.merge().unwrap();
+    assert_eq!(merged_root.counts().dupes, 0);
+    assert_eq!(merged_root.counts().merged_nodes, 5);
+
+    let driver = NormalizingDriver;
+    let merger = Merger::with_driver(&driver, &DefaultAbortSignal, &local_tree, &remote_tree);
+    let merged_root = merger.merge().unwrap();
 
     let expected_tree = merged_nodes!({
-        ("menu________", Local, {
-            ("queryAAAAAAA", Local),
-            ("bookmarkBBBB", Remote)
+        ("menu________", LocalWithNewLocalStructure, {
+            ("bookmarkAAAA", Remote),
+            ("folderBBBBBB", Remote)
         })
     });
     let expected_telem = StructureCounts {
+        dupes: 2,
         merged_nodes: 3,
         ..StructureCounts::default()
     };
 
     assert_eq!(&expected_tree, merged_root.node());
-
     assert_eq!(merged_root.deletions().count(), 0);
-
     assert_eq!(merged_root.counts(), &expected_telem);
 }
 
 #[test]
-fn mismatched_incompatible_bookmark_kinds() {
+fn custom_dupe_matcher() {
     before_each();
 
-    let local_tree = nodes!({
-        ("menu________", Folder[needs_merge = true], {
-            ("bookmarkAAAA", Bookmark[needs_merge = true])
-        })
-    })
-    .into_tree()
-    .unwrap();
-
-    let remote_tree = nodes!({
-        ("menu________", Folder[needs_merge = true], {
-            ("bookmarkAAAA", Folder[needs_merge = true, age = 5])
-        })
-    })
-    .into_tree()
-    .unwrap();
-
-    let merger = Merger::new(&local_tree, &remote_tree);
-    match merger.merge() {
-        Ok(_) => panic!("Should not merge trees with mismatched kinds"),
-        Err(err) => {
-            match err.kind() {
-                ErrorKind::MismatchedItemKind { .. } => {}
-                kind => panic!("Got {:?} merging trees with mismatched kinds", kind),
-            };
+    // Matches bookmarks by URL only, and never dedupes separators.
+    struct UrlOnlyMatcher;
+
+    impl DupeMatcher for UrlOnlyMatcher {
+        fn dupe_key<'t>(
+            &self,
+            _: tree::Node<'t>,
+            position: usize,
+            content: &'t Content,
+        ) -> Option<DupeKey<'t>> {
+            match content {
+                Content::Bookmark { url_href, .. } => {
+                    Some(DupeKey::WithoutPosition(Cow::Owned(Content::Bookmark {
+                        title: String::new(),
+                        url_href: url_href.clone(),
+                        keyword: None,
+                        description: None,
+                    })))
+                }
+                Content::Separator => None,
+                _ => Some(DupeKey::new(Cow::Borrowed(content), position)),
+            }
         }
-    };
-}
-
-#[test]
-fn invalid_guids() {
-    before_each();
+    }
 
-    #[derive(Default)]
-    struct GenerateNewGuid(Cell<usize>);
+    struct UrlOnlyDriver;
 
-    impl Driver for GenerateNewGuid {
-        fn generate_new_guid(&self, old_guid: &Guid) -> Result<Guid> {
-            let count = self.0.get();
-            self.0.set(count + 1);
-            assert!(
-                &[")(*&", "shortGUID", "loooooongGUID", "!@#$%^", ""].contains(&old_guid.as_str()),
-                "Didn't expect to generate new GUID for {}",
-                old_guid
-            );
-            Ok(format!("item{:0>8}", count).into())
+    impl Driver for UrlOnlyDriver {
+        fn dupe_matcher(&self) -> &dyn DupeMatcher {
+            &UrlOnlyMatcher
         }
     }
 
-    let local_tree = nodes!({
-        ("toolbar_____", Folder[needs_merge = true, age = 5], {
-            ("bookmarkAAAA", Bookmark[needs_merge = true, age = 5]),
-            ("bookmarkBBBB", Bookmark[needs_merge = true, age = 5]),
-            (")(*&", Bookmark[needs_merge = true, age = 5])
-        }),
+    let mut local_tree_builder = Builder::try_from(nodes!({
         ("menu________", Folder[needs_merge = true], {
-            ("shortGUID", Bookmark[needs_merge = true]),
-            ("loooooongGUID", Bookmark[needs_merge = true])
+            ("bookmarkAAA1", Bookmark[needs_merge = true]),
+            ("separatorBB1", Separator[needs_merge = true])
         })
-    })
-    .into_tree()
+    }))
     .unwrap();
+    local_tree_builder
+        .mutate(&"bookmarkAAA1".into())
+        .content(Content::Bookmark {
+            title: "Local title".into(),
+            url_href: "http://example.com/a".into(),
+            keyword: None,
+            description: None,
+        });
+    local_tree_builder
+        .mutate(&"separatorBB1".into())
+        .content(Content::Separator);
+    let local_tree = local_tree_builder.into_tree().unwrap();
 
-    let remote_tree = nodes!({
-        ("toolbar_____", Folder[needs_merge = true, age = 5], {
-            ("!@#$%^", Bookmark[needs_merge = true, age = 5]),
-            ("shortGUID", Bookmark[needs_merge = true, age = 5]),
-            ("", Bookmark[needs_merge = true, age = 5]),
-            ("loooooongGUID", Bookmark[needs_merge = true, age = 5])
-        }),
+    let mut remote_tree_builder = Builder::try_from(nodes!({
         ("menu________", Folder[needs_merge = true], {
             ("bookmarkAAAA", Bookmark[needs_merge = true]),
-            ("bookmarkBBBB", Bookmark[needs_merge = true])
+            ("separatorBBB", Separator[needs_merge = true])
         })
-    })
-    .into_tree()
+    }))
     .unwrap();
+    remote_tree_builder
+        .mutate(&"bookmarkAAAA".into())
+        .content(Content::Bookmark {
+            title: "Remote title".into(),
+            url_href: "http://example.com/a".into(),
+            keyword: None,
+            description: None,
+        });
+    remote_tree_builder
+        .mutate(&"separatorBBB".into())
+        .content(Content::Separator);
+    let remote_tree = remote_tree_builder.into_tree().unwrap();
 
-    let driver = GenerateNewGuid::default();
+    // The default matcher dedupes the separators, but not the bookmarks.
+    let merger = Merger::new(&local_tree, &remote_tree);
+    let merged_root = merger.merge().unwrap();
+    let expected_tree = merged_nodes!({
+        ("menu________", LocalWithNewLocalStructure, {
+            ("bookmarkAAAA", Remote),
+            ("separatorBBB", Remote),
+            ("bookmarkAAA1", Local)
+        })
+    });
+    assert_eq!(&expected_tree, merged_root.node());
+
+    let driver = UrlOnlyDriver;
     let merger = Merger::with_driver(&driver, &DefaultAbortSignal, &local_tree, &remote_tree);
     let merged_root = merger.merge().unwrap();
 
     let expected_tree = merged_nodes!({
-        ("toolbar_____", LocalWithNewLocalStructure, {
-            ("item00000000", RemoteWithNewRemoteStructure),
-            ("item00000001", RemoteWithNewRemoteStructure),
-            ("item00000002", LocalWithNewLocalStructure)
-        }),
         ("menu________", LocalWithNewLocalStructure, {
             ("bookmarkAAAA", Remote),
-            ("bookmarkBBBB", Remote),
-            ("item00000003", LocalWithNewLocalStructure),
-            ("item00000004", LocalWithNewLocalStructure)
+            ("separatorBBB", Remote),
+            ("separatorBB1", Local)
         })
     });
-    let expected_deletions = &["", "!@#$%^", "loooooongGUID", "shortGUID"];
     let expected_telem = StructureCounts {
-        merged_nodes: 9,
+        dupes: 1,
+        merged_nodes: 4,
         ..StructureCounts::default()
     };
 
     assert_eq!(&expected_tree, merged_root.node());
-
-    let mut deletions = merged_root.deletions().collect::<Vec<_>>();
-    deletions.sort();
-    assert_eq!(deletions, expected_deletions);
-
+    assert_eq!(merged_root.deletions().count(), 0);
     assert_eq!(merged_root.counts(), &expected_telem);
 }
 
 #[test]
-fn multiple_parents() {
+fn cross_folder_dupes() {
     before_each();
 
-    let local_tree = Tree::with_root(Item::new(ROOT_GUID, Kind::Folder))
-        .into_tree()
-        .unwrap();
+    struct CrossFolderDriver(CrossFolderDedupe);
 
-    let remote_tree = nodes!({
-        ("toolbar_____", Folder[age = 5], {
-            ("bookmarkAAAA", Bookmark),
-            ("bookmarkBBBB", Bookmark),
-            ("folderCCCCCC", Folder, {
-                ("bookmarkDDDD", Bookmark),
-                ("bookmarkEEEE", Bookmark),
-                ("bookmarkFFFF", Bookmark)
-            })
-        }),
-        ("menu________", Folder, {
-            ("bookmarkGGGG", Bookmark),
-            ("bookmarkAAAA", Bookmark),
-            ("folderCCCCCC", Folder, {
-                ("bookmarkHHHH", Bookmark),
-                ("bookmarkDDDD", Bookmark)
+    impl Driver for CrossFolderDriver {
+        fn cross_folder_dedupe(&self) -> CrossFolderDedupe {
+            self.0
+        }
+    }
+
+    let mut local_tree_builder = Builder::try_from(nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("folderLLLLLL", Folder[needs_merge = true], {
+                ("bookmarkAAA1", Bookmark[needs_merge = true]),
+                ("separatorBB1", Separator[needs_merge = true])
             })
         })
-    })
-    .into_tree()
+    }))
     .unwrap();
+    local_tree_builder
+        .mutate(&"bookmarkAAA1".into())
+        .content(Content::Bookmark {
+            title: "A".into(),
+            url_href: "http://example.com/a".into(),
+            keyword: None,
+            description: None,
+        });
+    local_tree_builder
+        .mutate(&"separatorBB1".into())
+        .content(Content::Separator);
+    let local_tree = local_tree_builder.into_tree().unwrap();
 
-    let merger = Merger::new(&local_tree, &remote_tree);
+    let mut remote_tree_builder = Builder::try_from(nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("bookmarkAAAA", Bookmark[needs_merge = true]),
+            ("separatorBBB", Separator[needs_merge = true])
+        })
+    }))
+    .unwrap();
+    remote_tree_builder
+        .mutate(&"bookmarkAAAA".into())
+        .content(Content::Bookmark {
+            title: "A".into(),
+            url_href: "http://example.com/a".into(),
+            keyword: None,
+            description: None,
+        });
+    remote_tree_builder
+        .mutate(&"separatorBBB".into())
+        .content(Content::Separator);
+    let remote_tree = remote_tree_builder.into_tree().unwrap();
+
+    // By default, we only dedupe items in matching folders.
+    let driver = CrossFolderDriver(CrossFolderDedupe::Off);
+    let merger = Merger::with_driver(&driver, &DefaultAbortSignal, &local_tree, &remote_tree);
     let merged_root = merger.merge().unwrap();
 
-    let expected_tree = merged_nodes!(ROOT_GUID, UnchangedWithNewLocalStructure, {
-        ("toolbar_____", RemoteWithNewRemoteStructure, {
-            ("bookmarkBBBB", Remote)
-        }),
-        ("menu________", RemoteWithNewRemoteStructure, {
-            ("bookmarkGGGG", Remote),
-            ("bookmarkAAAA", RemoteWithNewRemoteStructure),
-            ("folderCCCCCC", RemoteWithNewRemoteStructure, {
-                ("bookmarkDDDD", RemoteWithNewRemoteStructure),
-                ("bookmarkEEEE", Remote),
-                ("bookmarkFFFF", Remote),
-                ("bookmarkHHHH", Remote)
+    let expected_tree = merged_nodes!({
+        ("menu________", LocalWithNewLocalStructure, {
+            ("bookmarkAAAA", Remote),
+            ("separatorBBB", Remote),
+            ("folderLLLLLL", Local, {
+                ("bookmarkAAA1", Local),
+                ("separatorBB1", Local)
+            })
+        })
+    });
+
+    assert_eq!(&expected_tree, merged_root.node());
+    assert!(merged_root.cross_folder_dupes().is_empty());
+    assert_eq!(merged_root.counts().dupes, 0);
+
+    // Reporting finds the bookmarks, but keeps both.
+    let driver = CrossFolderDriver(CrossFolderDedupe::Report);
+    let merger = Merger::with_driver(&driver, &DefaultAbortSignal, &local_tree, &remote_tree);
+    let merged_root = merger.merge().unwrap();
+
+    assert_eq!(&expected_tree, merged_root.node());
+    assert_eq!(
+        to_strings(merged_root.cross_folder_dupes()).collect::<Vec<_>>(),
+        &[
+            "Local bookmarkAAA1 in folderLLLLLL matches remote bookmarkAAAA in menu________; \
+           reported"
+        ]
+    );
+    assert_eq!(
+        merged_root.counts(),
+        &StructureCounts {
+            cross_folder_dupes: 1,
+            merged_nodes: 6,
+            ..StructureCounts::default()
+        }
+    );
+
+    // Merging dedupes the bookmarks, but not the separators, since they
+    // match by position.
+    let driver = CrossFolderDriver(CrossFolderDedupe::Merge);
+    let merger = Merger::with_driver(&driver, &DefaultAbortSignal, &local_tree, &remote_tree);
+    let merged_root = merger.merge().unwrap();
+
+    let expected_tree = merged_nodes!({
+        ("menu________", LocalWithNewLocalStructure, {
+            ("bookmarkAAAA", Remote),
+            ("separatorBBB", Remote),
+            ("folderLLLLLL", LocalWithNewLocalStructure, {
+                ("separatorBB1", Local)
             })
         })
     });
     let expected_telem = StructureCounts {
-        merged_nodes: 10,
+        dupes: 1,
+        cross_folder_dupes: 1,
+        merged_nodes: 5,
         ..StructureCounts::default()
     };
 
     assert_eq!(&expected_tree, merged_root.node());
-
+    assert_eq!(
+        to_strings(merged_root.cross_folder_dupes()).collect::<Vec<_>>(),
+        &[
+            "Local bookmarkAAA1 in folderLLLLLL matches remote bookmarkAAAA in menu________; \
+           deduped"
+        ]
+    );
     assert_eq!(merged_root.deletions().count(), 0);
-
     assert_eq!(merged_root.counts(), &expected_telem);
-}
 
-#[test]
-fn reparent_orphans() {
-    before_each();
+    let ops = merged_root.completion_ops();
+    assert_eq!(
+        to_strings(&ops.change_guids).collect::<Vec<_>>(),
+        &["Change bookmarkAAA1 to bookmarkAAAA"]
+    );
 
-    let local_tree = nodes!({
-        ("toolbar_____", Folder, {
-            ("bookmarkAAAA", Bookmark),
-            ("bookmarkBBBB", Bookmark)
+    // If we walk the local bookmark first, the remote match moves into the
+    // local folder.
+    let mut local_tree_builder = Builder::try_from(nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("bookmarkCCC1", Bookmark[needs_merge = true])
         }),
-        ("unfiled_____", Folder, {
-            ("bookmarkCCCC", Bookmark)
-        })
-    })
-    .into_tree()
+        ("toolbar_____", Folder)
+    }))
     .unwrap();
+    local_tree_builder
+        .mutate(&"bookmarkCCC1".into())
+        .content(Content::Bookmark {
+            title: "C".into(),
+            url_href: "http://example.com/c".into(),
+            keyword: None,
+            description: None,
+        });
+    let local_tree = local_tree_builder.into_tree().unwrap();
 
-    let mut remote_tree_builder: Builder = nodes!({
+    let mut remote_tree_builder = Builder::try_from(nodes!({
+        ("menu________", Folder),
         ("toolbar_____", Folder[needs_merge = true], {
-            ("bookmarkBBBB", Bookmark),
-            ("bookmarkAAAA", Bookmark)
-        }),
-        ("unfiled_____", Folder[needs_merge = true], {
-            ("bookmarkDDDD", Bookmark[needs_merge = true]),
-            ("bookmarkCCCC", Bookmark)
+            ("bookmarkCCCC", Bookmark[needs_merge = true])
         })
-    })
-    .try_into()
+    }))
     .unwrap();
     remote_tree_builder
-        .item(Item {
-            guid: "bookmarkEEEE".into(),
-            kind: Kind::Bookmark,
-            age: 0,
-            needs_merge: true,
-            validity: Validity::Valid,
-        })
-        .and_then(|p| p.by_parent_guid("toolbar_____".into()))
-        .expect("Should insert orphan E");
-    remote_tree_builder
-        .item(Item {
-            guid: "bookmarkFFFF".into(),
-            kind: Kind::Bookmark,
-            age: 0,
-            needs_merge: true,
-            validity: Validity::Valid,
-        })
-        .and_then(|p| p.by_parent_guid("nonexistent".into()))
-        .expect("Should insert orphan F");
+        .mutate(&"bookmarkCCCC".into())
+        .content(Content::Bookmark {
+            title: "C".into(),
+            url_href: "http://example.com/c".into(),
+            keyword: None,
+            description: None,
+        });
     let remote_tree = remote_tree_builder.into_tree().unwrap();
 
-    let merger = Merger::new(&local_tree, &remote_tree);
+    let merger = Merger::with_driver(&driver, &DefaultAbortSignal, &local_tree, &remote_tree);
     let merged_root = merger.merge().unwrap();
 
     let expected_tree = merged_nodes!({
-        ("toolbar_____", LocalWithNewLocalStructure, {
-            ("bookmarkBBBB", Unchanged),
-            ("bookmarkAAAA", Unchanged),
-            ("bookmarkEEEE", RemoteWithNewRemoteStructure)
+        ("menu________", LocalWithNewLocalStructure, {
+            ("bookmarkCCCC", RemoteWithNewRemoteStructure)
         }),
-        ("unfiled_____", LocalWithNewLocalStructure, {
-            ("bookmarkDDDD", Remote),
-            ("bookmarkCCCC", Unchanged),
-            ("bookmarkFFFF", RemoteWithNewRemoteStructure)
-        })
+        ("toolbar_____", Local)
     });
-    let expected_telem = StructureCounts {
-        merged_nodes: 8,
-        ..StructureCounts::default()
-    };
-
     assert_eq!(&expected_tree, merged_root.node());
+    assert_eq!(
+        to_strings(merged_root.cross_folder_dupes()).collect::<Vec<_>>(),
+        &[
+            "Local bookmarkCCC1 in menu________ matches remote bookmarkCCCC in toolbar_____; \
+           deduped"
+        ]
+    );
+    assert_eq!(merged_root.counts().dupes, 1);
+    assert_eq!(merged_root.counts().cross_folder_dupes, 1);
 
-    assert_eq!(merged_root.deletions().count(), 0);
-
-    assert_eq!(merged_root.counts(), &expected_telem);
+    let ops = merged_root.completion_ops();
+    assert_eq!(
+        to_strings(&ops.change_guids).collect::<Vec<_>>(),
+        &["Change bookmarkCCC1 to bookmarkCCCC"]
+    );
+    assert_eq!(
+        to_strings(&ops.upload_items).collect::<Vec<_>>(),
+        &[
+            "Upload item menu________",
+            "Upload item bookmarkCCCC",
+            "Upload item toolbar_____",
+        ]
+    );
 }
 
 #[test]
-fn deleted_user_content_roots() {
+fn merge_tags() {
     before_each();
 
-    let mut local_tree_builder = Builder::try_from(nodes!({
-        ("unfiled_____", Folder[needs_merge = true], {
-            ("bookmarkAAAA", Bookmark[needs_merge = true])
-        })
-    }))
-    .unwrap();
-    local_tree_builder
-        .deletion("mobile______".into())
-        .deletion("toolbar_____".into());
-    let local_tree = local_tree_builder.into_tree().unwrap();
-
-    let mut remote_tree_builder = Builder::try_from(nodes!({
-        ("mobile______", Folder[needs_merge = true], {
-            ("bookmarkBBBB", Bookmark[needs_merge = true])
-        })
-    }))
-    .unwrap();
-    remote_tree_builder
-        .deletion("unfiled_____".into())
-        .deletion("toolbar_____".into());
-    let remote_tree = remote_tree_builder.into_tree().unwrap();
-
-    let merger = Merger::new(&local_tree, &remote_tree);
-    let merged_root = merger.merge().unwrap();
-
-    let expected_tree = merged_nodes!(ROOT_GUID, LocalWithNewLocalStructure, {
-        ("unfiled_____", Local, {
-            ("bookmarkAAAA", Local)
-        }),
-        ("mobile______", Remote, {
-            ("bookmarkBBBB", Remote)
-        })
-    });
-    let expected_telem = StructureCounts {
-        merged_nodes: 4,
-        ..StructureCounts::default()
+    let tags = |tags: &[&str]| {
+        tags.iter()
+            .map(|&tag| tag.to_owned())
+            .collect::<BTreeSet<_>>()
     };
 
-    assert_eq!(&expected_tree, merged_root.node());
-
-    assert_eq!(merged_root.deletions().count(), 1);
-
-    assert_eq!(merged_root.counts(), &expected_telem);
-}
-
-#[test]
-fn moved_user_content_roots() {
-    before_each();
-
     let local_tree = nodes!({
-        ("unfiled_____", Folder[needs_merge = true], {
-            ("bookmarkAAAA", Bookmark[needs_merge = true]),
-            ("menu________", Folder[needs_merge = true], {
-                ("bookmarkBBBB", Bookmark[needs_merge = true]),
-                ("folderCCCCCC", Folder, {
-                    ("bookmarkDDDD", Bookmark),
-                    ("toolbar_____", Folder, {
-                        ("bookmarkEEEE", Bookmark)
-                    })
-                })
-            })
-        }),
-        ("mobile______", Folder, {
-            ("bookmarkFFFF", Bookmark)
+        ("menu________", Folder[needs_merge = true], {
+            ("bookmarkAAAA", Bookmark[needs_merge = true, tags = tags(&["a", "b"])]),
+            ("bookmarkBBBB", Bookmark[needs_merge = true, tags = tags(&["c"])]),
+            ("bookmarkCCCC", Bookmark[tags = tags(&["e"])])
         })
     })
     .into_tree()
     .unwrap();
 
     let remote_tree = nodes!({
-        ("mobile______", Folder[needs_merge = true], {
-            ("toolbar_____", Folder[needs_merge = true], {
-                ("bookmarkGGGG", Bookmark[needs_merge = true]),
-                ("bookmarkEEEE", Bookmark)
-            })
-        }),
         ("menu________", Folder[needs_merge = true], {
-            ("bookmarkHHHH", Bookmark[needs_merge = true]),
-            ("unfiled_____", Folder[needs_merge = true], {
-                ("bookmarkIIII", Bookmark[needs_merge = true])
-            })
+            ("bookmarkAAAA", Bookmark[needs_merge = true, tags = tags(&["b", "c"])]),
+            ("bookmarkBBBB", Bookmark[needs_merge = true, tags = tags(&["c", "d"])]),
+            ("bookmarkCCCC", Bookmark[needs_merge = true, tags = tags(&["f"])])
         })
     })
     .into_tree()
@@ -2515,415 +2986,3104 @@ fn moved_user_content_roots() {
     let merger = Merger::new(&local_tree, &remote_tree);
     let merged_root = merger.merge().unwrap();
 
-    let expected_tree = merged_nodes!(ROOT_GUID, LocalWithNewLocalStructure, {
-        ("unfiled_____", LocalWithNewLocalStructure, {
-            ("bookmarkIIII", Remote),
-            ("bookmarkAAAA", Local)
-        }),
-        ("mobile______", Local, {
-            ("bookmarkFFFF", Local)
-        }),
-        ("menu________", LocalWithNewLocalStructure, {
-           ("bookmarkHHHH", Remote),
-           ("bookmarkBBBB", Local),
-           ("folderCCCCCC", LocalWithNewLocalStructure, {
-               ("bookmarkDDDD", Local)
-           })
-        }),
-        ("toolbar_____", LocalWithNewLocalStructure, {
-            ("bookmarkGGGG", Remote),
-            ("bookmarkEEEE", Unchanged)
+    // A's tags changed on both sides, so we take the union, and reupload it.
+    // B's remote tags are a superset of its local tags, so we can apply them
+    // as-is. C's tags only changed remotely, so we take the remote side.
+    let expected_tree = merged_nodes!({
+        ("menu________", Local, {
+            ("bookmarkAAAA", Remote),
+            ("bookmarkBBBB", Remote),
+            ("bookmarkCCCC", Remote)
         })
     });
-    let expected_telem = StructureCounts {
-        merged_nodes: 13,
-        ..StructureCounts::default()
-    };
-
     assert_eq!(&expected_tree, merged_root.node());
 
-    assert_eq!(merged_root.deletions().count(), 0);
+    let merged_tags = |guid: &str| {
+        merged_root.node().merged_children[0]
+            .merged_children
+            .iter()
+            .find(|node| node.guid == guid)
+            .map(|node| node.tags().iter().cloned().collect::<Vec<_>>())
+            .unwrap()
+    };
+    assert_eq!(merged_tags("bookmarkAAAA"), &["a", "b", "c"]);
+    assert_eq!(merged_tags("bookmarkBBBB"), &["c", "d"]);
+    assert_eq!(merged_tags("bookmarkCCCC"), &["f"]);
+
+    // Merging tags doesn't change A's structure, but A still needs to be
+    // reuploaded.
+    let needs_reupload = merged_root.node().merged_children[0]
+        .merged_children
+        .iter()
+        .map(|node| node.needs_reupload)
+        .collect::<Vec<_>>();
+    assert_eq!(needs_reupload, &[true, false, false]);
 
-    assert_eq!(merged_root.counts(), &expected_telem);
+    let ops = merged_root.completion_ops();
+    assert_eq!(
+        to_strings(&ops.apply_merged_tags).collect::<Vec<_>>(),
+        &["Apply merged tags [a, b, c] to bookmarkAAAA"]
+    );
+    assert_eq!(
+        to_strings(&ops.upload_items).collect::<Vec<_>>(),
+        &["Upload item menu________", "Upload item bookmarkAAAA"]
+    );
 }
 
 #[test]
-fn cycle() {
+fn migrate_tag_folders() {
     before_each();
 
-    // Try to create a cycle: move A into B, and B into the menu, but keep
-    // B's parent by children as A.
-    let mut b: Builder = nodes!({ ("menu________", Folder) }).try_into().unwrap();
-
-    b.item(Item::new("folderAAAAAA".into(), Kind::Folder))
-        .and_then(|p| p.by_parent_guid("folderBBBBBB".into()))
-        .expect("Should insert A");
-
-    b.item(Item::new("folderBBBBBB".into(), Kind::Folder))
-        .and_then(|p| p.by_parent_guid("menu________".into()))
-        .and_then(|b| {
-            b.parent_for(&"folderBBBBBB".into())
-                .by_children(&"folderAAAAAA".into())
+    let mut builder = Builder::try_from(nodes!({
+        ("menu________", Folder, {
+            ("bookmarkAAAA", Bookmark),
+            ("bookmarkBBBB", Bookmark)
+        }),
+        ("tags________", Folder, {
+            ("folderTAG111", Folder, {
+                ("bookmarkTA11", Bookmark),
+                ("bookmarkTB11", Bookmark)
+            }),
+            ("folderTAG222", Folder, {
+                ("bookmarkTA22", Bookmark)
+            })
         })
-        .expect("Should insert B");
-
-    match b
-        .into_tree()
-        .expect_err("Should not build tree with cycles")
-        .kind()
-    {
-        ErrorKind::Cycle(guid) => assert_eq!(guid, &Guid::from("folderAAAAAA")),
-        err => panic!("Wrong error kind for cycle: {:?}", err),
+    }))
+    .unwrap();
+    for (guid, url_href) in &[
+        ("bookmarkAAAA", "http://example.com/a"),
+        ("bookmarkBBBB", "http://example.com/b"),
+        ("bookmarkTA11", "http://example.com/a"),
+        ("bookmarkTB11", "http://example.com/b"),
+        ("bookmarkTA22", "http://example.com/a"),
+    ] {
+        builder.mutate(&(*guid).into()).content(Content::Bookmark {
+            title: "".into(),
+            url_href: (*url_href).into(),
+            keyword: None,
+            description: None,
+        });
     }
+    builder
+        .mutate(&"folderTAG111".into())
+        .content(Content::Folder {
+            title: "one".into(),
+        });
+    builder
+        .mutate(&"folderTAG222".into())
+        .content(Content::Folder {
+            title: "two".into(),
+        });
+    builder.migrate_tag_folders();
+    let tree = builder.into_tree().unwrap();
+
+    let tags = |guid: &str| {
+        tree.node_for_guid(&guid.into())
+            .map(|node| node.item().tags.iter().cloned().collect::<Vec<_>>())
+            .unwrap()
+    };
+    assert_eq!(tags("bookmarkAAAA"), &["one", "two"]);
+    assert_eq!(tags("bookmarkBBBB"), &["one"]);
+    assert!(tags("bookmarkTA11").is_empty());
+
+    // Bookmarks with new tags should be uploaded.
+    let needs_merge = |guid: &str| tree.node_for_guid(&guid.into()).unwrap().needs_merge;
+    assert!(needs_merge("bookmarkAAAA"));
+    assert!(needs_merge("bookmarkBBBB"));
+    assert!(!needs_merge("bookmarkTA11"));
 }
 
 #[test]
-fn reupload_replace() {
+fn keyword_clashes() {
     before_each();
 
     let mut local_tree_builder = Builder::try_from(nodes!({
         ("menu________", Folder, {
-            ("bookmarkAAAA", Bookmark)
-        }),
-        ("toolbar_____", Folder, {
-            ("folderBBBBBB", Folder, {
-                ("bookmarkCCCC", Bookmark[validity = Validity::Replace])
-            }),
-            ("folderDDDDDD", Folder, {
-                ("bookmarkEEEE", Bookmark[validity = Validity::Replace])
-            })
-        }),
-        ("unfiled_____", Folder),
-        ("mobile______", Folder, {
-            ("bookmarkFFFF", Bookmark[validity = Validity::Replace]),
-            ("folderGGGGGG", Folder),
-            ("bookmarkHHHH", Bookmark[validity = Validity::Replace])
+            ("bookmarkAAAA", Bookmark[needs_merge = true, age = 5]),
+            ("bookmarkCCCC", Bookmark)
         })
     }))
     .unwrap();
-    local_tree_builder.deletion("bookmarkIIII".into());
+    local_tree_builder
+        .mutate(&"bookmarkAAAA".into())
+        .content(Content::Bookmark {
+            title: "A".into(),
+            url_href: "http://example.com/a".into(),
+            keyword: Some("news".into()),
+            description: None,
+        });
+    local_tree_builder
+        .mutate(&"bookmarkCCCC".into())
+        .content(Content::Bookmark {
+            title: "C".into(),
+            url_href: "http://example.com/c".into(),
+            keyword: Some("mail".into()),
+            description: Some("Mail".into()),
+        });
     let local_tree = local_tree_builder.into_tree().unwrap();
 
     let mut remote_tree_builder = Builder::try_from(nodes!({
-        ("menu________", Folder, {
-            ("bookmarkAAAA", Bookmark[validity = Validity::Replace])
-        }),
-        ("toolbar_____", Folder, {
-            ("bookmarkJJJJ", Bookmark[validity = Validity::Replace]),
-            ("folderBBBBBB", Folder, {
-                ("bookmarkCCCC", Bookmark[validity = Validity::Replace])
-            }),
-            ("folderDDDDDD", Folder)
-        }),
-        ("unfiled_____", Folder, {
-            ("bookmarkKKKK", Bookmark[validity = Validity::Reupload])
-        }),
-        ("mobile______", Folder, {
-            ("bookmarkFFFF", Bookmark),
-            ("folderGGGGGG", Folder, {
-                ("bookmarkIIII", Bookmark[validity = Validity::Replace])
-            })
+        ("menu________", Folder[needs_merge = true], {
+            ("bookmarkAAAA", Bookmark),
+            ("bookmarkBBBB", Bookmark[needs_merge = true]),
+            ("bookmarkCCCC", Bookmark)
         })
     }))
     .unwrap();
-    remote_tree_builder.deletion("bookmarkEEEE".into());
-    let remote_tree = remote_tree_builder.into_tree().unwrap();
-
-    let merger = Merger::new(&local_tree, &remote_tree);
-    let merged_root = merger.merge().unwrap();
-
-    let expected_tree = merged_nodes!({
-        ("menu________", Unchanged, {
-            // A is invalid remotely and valid locally, so replace.
+    remote_tree_builder
+        .mutate(&"bookmarkAAAA".into())
+        .content(Content::Bookmark {
+            title: "A".into(),
+            url_href: "http://example.com/a".into(),
+            keyword: None,
+            description: None,
+        });
+    remote_tree_builder
+        .mutate(&"bookmarkBBBB".into())
+        .content(Content::Bookmark {
+            title: "B".into(),
+            url_href: "http://example.com/b".into(),
+            keyword: Some("News".into()),
+            description: None,
+        });
+    remote_tree_builder
+        .mutate(&"bookmarkCCCC".into())
+        .content(Content::Bookmark {
+            title: "C".into(),
+            url_href: "http://example.com/c".into(),
+            keyword: Some("mail".into()),
+            description: Some("Mail".into()),
+        });
+    let remote_tree = remote_tree_builder.into_tree().unwrap();
+
+    let merger = Merger::new(&local_tree, &remote_tree);
+    let merged_root = merger.merge().unwrap();
+
+    let expected_tree = merged_nodes!({
+        ("menu________", UnchangedWithNewLocalStructure, {
+            ("bookmarkAAAA", Local),
+            ("bookmarkBBBB", Remote),
+            ("bookmarkCCCC", Unchanged)
+        })
+    });
+    assert_eq!(&expected_tree, merged_root.node());
+
+    // A and B have the same keyword, but B changed more recently, so A loses
+    // its keyword.
+    assert_eq!(
+        to_strings(merged_root.keyword_clashes()).collect::<Vec<_>>(),
+        &[
+            "Keyword news points to http://example.com/b for bookmarkBBBB; removed from \
+           [bookmarkAAAA]"
+        ]
+    );
+    let merged_keywords = merged_root.node().merged_children[0]
+        .merged_children
+        .iter()
+        .map(|node| match node.content() {
+            Some(Content::Bookmark { keyword, .. }) => keyword.as_deref(),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(merged_keywords, &[None, Some("News"), Some("mail")]);
+
+    let ops = merged_root.completion_ops();
+    assert_eq!(
+        to_strings(&ops.apply_merged_content).collect::<Vec<_>>(),
+        &["Apply merged content to bookmarkAAAA"]
+    );
+    assert_eq!(
+        to_strings(&ops.upload_items).collect::<Vec<_>>(),
+        &["Upload item bookmarkAAAA"]
+    );
+
+    // An unchanged bookmark that loses its keyword keeps its merge state, but
+    // still needs to be reuploaded.
+    let bookmark = |keyword: &str, url_href: &str| Content::Bookmark {
+        title: "".into(),
+        url_href: url_href.into(),
+        keyword: Some(keyword.into()),
+        description: None,
+    };
+
+    let mut local_tree_builder = Builder::try_from(nodes!({
+        ("menu________", Folder, {
+            ("bookmarkCCCC", Bookmark[age = 10])
+        })
+    }))
+    .unwrap();
+    local_tree_builder
+        .mutate(&"bookmarkCCCC".into())
+        .content(bookmark("mail", "http://example.com/c"));
+    let local_tree = local_tree_builder.into_tree().unwrap();
+
+    let mut remote_tree_builder = Builder::try_from(nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("bookmarkCCCC", Bookmark[age = 10]),
+            ("bookmarkDDDD", Bookmark[needs_merge = true])
+        })
+    }))
+    .unwrap();
+    remote_tree_builder
+        .mutate(&"bookmarkCCCC".into())
+        .content(bookmark("mail", "http://example.com/c"));
+    remote_tree_builder
+        .mutate(&"bookmarkDDDD".into())
+        .content(bookmark("mail", "http://example.com/d"));
+    let remote_tree = remote_tree_builder.into_tree().unwrap();
+
+    let merger = Merger::new(&local_tree, &remote_tree);
+    let merged_root = merger.merge().unwrap();
+
+    let expected_tree = merged_nodes!({
+        ("menu________", UnchangedWithNewLocalStructure, {
+            ("bookmarkCCCC", Unchanged),
+            ("bookmarkDDDD", Remote)
+        })
+    });
+    assert_eq!(&expected_tree, merged_root.node());
+
+    let needs_reupload = merged_root.node().merged_children[0]
+        .merged_children
+        .iter()
+        .map(|node| node.needs_reupload)
+        .collect::<Vec<_>>();
+    assert_eq!(needs_reupload, &[true, false]);
+
+    let ops = merged_root.completion_ops();
+    assert_eq!(
+        to_strings(&ops.apply_merged_content).collect::<Vec<_>>(),
+        &["Apply merged content to bookmarkCCCC"]
+    );
+    assert_eq!(
+        to_strings(&ops.upload_items).collect::<Vec<_>>(),
+        &["Upload item bookmarkCCCC"]
+    );
+
+    // Keywords and descriptions aren't part of the dupe key, so new bookmarks
+    // with the same title and URL still dedupe.
+    let mut local_tree_builder = Builder::try_from(nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("bookmarkAAA1", Bookmark[needs_merge = true])
+        })
+    }))
+    .unwrap();
+    local_tree_builder
+        .mutate(&"bookmarkAAA1".into())
+        .content(bookmark("news", "http://example.com/a"));
+    let local_tree = local_tree_builder.into_tree().unwrap();
+
+    let mut remote_tree_builder = Builder::try_from(nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("bookmarkAAAA", Bookmark[needs_merge = true])
+        })
+    }))
+    .unwrap();
+    remote_tree_builder
+        .mutate(&"bookmarkAAAA".into())
+        .content(Content::Bookmark {
+            title: "".into(),
+            url_href: "http://example.com/a".into(),
+            keyword: None,
+            description: Some("A".into()),
+        });
+    let remote_tree = remote_tree_builder.into_tree().unwrap();
+
+    let merger = Merger::new(&local_tree, &remote_tree);
+    let merged_root = merger.merge().unwrap();
+
+    let expected_tree = merged_nodes!({
+        ("menu________", LocalWithNewLocalStructure, {
+            ("bookmarkAAAA", Remote)
+        })
+    });
+    assert_eq!(&expected_tree, merged_root.node());
+    assert_eq!(merged_root.counts().dupes, 1);
+}
+
+#[test]
+fn query_and_livemark_content() {
+    before_each();
+
+    let mut local_tree_builder = Builder::try_from(nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("queryAAAAAAA", Query[needs_merge = true]),
+            ("livemarkBBBB", Livemark[needs_merge = true])
+        })
+    }))
+    .unwrap();
+    local_tree_builder
+        .mutate(&"queryAAAAAAA".into())
+        .content(Content::Query {
+            title: "Recent".into(),
+            url_href: "place:sort=8&maxResults=10".into(),
+        });
+    local_tree_builder
+        .mutate(&"livemarkBBBB".into())
+        .content(Content::Livemark {
+            title: "News".into(),
+            feed_url: "http://example.com/feed".into(),
+            site_url: Some("http://example.com".into()),
+        });
+    let local_tree = local_tree_builder.into_tree().unwrap();
+
+    let mut remote_tree_builder = Builder::try_from(nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("queryAAAAAA1", Query[needs_merge = true])
+        })
+    }))
+    .unwrap();
+    remote_tree_builder
+        .mutate(&"queryAAAAAA1".into())
+        .content(Content::Query {
+            title: "Recent".into(),
+            url_href: "place:sort=8&maxResults=10".into(),
+        });
+    let remote_tree = remote_tree_builder.into_tree().unwrap();
+
+    let merger = Merger::new(&local_tree, &remote_tree);
+    let merged_root = merger.merge().unwrap();
+
+    // The queries have the same content, so we dedupe them. The livemark
+    // isn't syncable, so we delete it.
+    let expected_tree = merged_nodes!({
+        ("menu________", LocalWithNewLocalStructure, {
+            ("queryAAAAAA1", Remote)
+        })
+    });
+    assert_eq!(&expected_tree, merged_root.node());
+    assert_eq!(merged_root.counts().dupes, 1);
+    assert_eq!(
+        merged_root
+            .deletions()
+            .map(Guid::as_str)
+            .collect::<Vec<_>>(),
+        &["livemarkBBBB"]
+    );
+
+    // The undo journal keeps the livemark's content, so that it can be
+    // restored.
+    let journal = UndoJournal::new(&merged_root);
+    let restored_livemark = journal
+        .restore_items
+        .iter()
+        .find(|op| op.item.guid == "livemarkBBBB")
+        .unwrap();
+    assert_eq!(
+        restored_livemark.content.as_ref().map(Content::kind),
+        Some(Kind::Livemark)
+    );
+    assert_eq!(
+        restored_livemark.content.as_ref().and_then(Content::title),
+        Some("News")
+    );
+}
+
+#[test]
+fn custom_roots() {
+    before_each();
+
+    let mut roots = Roots::new();
+    roots
+        .built_in_root("favorites___".into())
+        .orphans_root("otherBkmarks".into());
+
+    let mut local_tree_builder =
+        Tree::with_roots(Item::new(ROOT_GUID, Kind::Folder), roots.clone());
+    for (guid, kind, parent_guid) in &[
+        ("favorites___", Kind::Folder, ROOT_GUID),
+        ("otherBkmarks", Kind::Folder, ROOT_GUID),
+        ("bookmarkAAAA", Kind::Bookmark, "favorites___".into()),
+        ("toolbar_____", Kind::Folder, "favorites___".into()),
+    ] {
+        local_tree_builder
+            .item(Item::new((*guid).into(), *kind))
+            .and_then(|p| p.by_structure(parent_guid))
+            .expect("Should insert local item");
+    }
+    local_tree_builder
+        .item(Item::new("bookmarkBBBB".into(), Kind::Bookmark))
+        .and_then(|p| p.by_parent_guid("nonexistent".into()))
+        .expect("Should insert orphan B");
+    let local_tree = local_tree_builder.into_tree().unwrap();
+
+    // Orphans go to the orphans root, and the Places toolbar is an ordinary
+    // folder.
+    let parent_guid = |guid: &str| {
+        local_tree
+            .node_for_guid(&guid.into())
+            .and_then(|node| node.parent())
+            .map(|node| node.item().guid.clone())
+            .unwrap()
+    };
+    assert_eq!(parent_guid("bookmarkBBBB"), "otherBkmarks");
+    assert_eq!(parent_guid("toolbar_____"), "favorites___");
+    assert!(local_tree
+        .node_for_guid(&"favorites___".into())
+        .unwrap()
+        .is_built_in_root());
+    assert!(!local_tree
+        .node_for_guid(&"toolbar_____".into())
+        .unwrap()
+        .is_built_in_root());
+
+    let mut remote_tree_builder = Tree::with_roots(Item::new(ROOT_GUID, Kind::Folder), roots);
+    for (guid, kind, parent_guid) in &[
+        ("favorites___", Kind::Folder, ROOT_GUID),
+        ("otherBkmarks", Kind::Folder, ROOT_GUID),
+        ("bookmarkCCCC", Kind::Bookmark, "favorites___".into()),
+    ] {
+        let mut item = Item::new((*guid).into(), *kind);
+        item.needs_merge = true;
+        remote_tree_builder
+            .item(item)
+            .and_then(|p| p.by_structure(parent_guid))
+            .expect("Should insert remote item");
+    }
+    let remote_tree = remote_tree_builder.into_tree().unwrap();
+
+    let merger = Merger::new(&local_tree, &remote_tree);
+    let merged_root = merger.merge().unwrap();
+
+    let expected_tree = merged_nodes!({
+        ("favorites___", LocalWithNewLocalStructure, {
+            ("bookmarkCCCC", Remote),
+            ("bookmarkAAAA", Local),
+            ("toolbar_____", Local)
+        }),
+        ("otherBkmarks", LocalWithNewLocalStructure, {
+            ("bookmarkBBBB", LocalWithNewLocalStructure)
+        })
+    });
+    assert_eq!(&expected_tree, merged_root.node());
+
+    // Both trees must have the same roots.
+    let places_tree = nodes!({ ("menu________", Folder) }).into_tree().unwrap();
+    let merger = Merger::new(&local_tree, &places_tree);
+    match merger.merge() {
+        Ok(_) => panic!("Should not merge trees with different roots"),
+        Err(err) => match err.kind() {
+            ErrorKind::MismatchedRoots => {}
+            kind => panic!("Got {:?} merging trees with different roots", kind),
+        },
+    }
+}
+
+#[test]
+fn mismatched_compatible_bookmark_kinds() {
+    before_each();
+
+    let local_tree = nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("queryAAAAAAA", Query[needs_merge = true]),
+            ("bookmarkBBBB", Bookmark[needs_merge = true, age = 5])
+        })
+    })
+    .into_tree()
+    .unwrap();
+
+    let remote_tree = nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("queryAAAAAAA", Bookmark[needs_merge = true, age = 5]),
+            ("bookmarkBBBB", Query[needs_merge = true])
+        })
+    })
+    .into_tree()
+    .unwrap();
+
+    let merger = Merger::new(&local_tree, &remote_tree);
+    let merged_root = merger.merge().unwrap();
+
+    let expected_tree = merged_nodes!({
+        ("menu________", Local, {
+            ("queryAAAAAAA", Local),
+            ("bookmarkBBBB", Remote)
+        })
+    });
+    let expected_telem = StructureCounts {
+        merged_nodes: 3,
+        ..StructureCounts::default()
+    };
+
+    assert_eq!(&expected_tree, merged_root.node());
+
+    assert_eq!(merged_root.deletions().count(), 0);
+
+    assert_eq!(merged_root.counts(), &expected_telem);
+}
+
+#[test]
+fn mismatched_incompatible_bookmark_kinds() {
+    before_each();
+
+    let local_tree = nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("bookmarkAAAA", Bookmark[needs_merge = true])
+        })
+    })
+    .into_tree()
+    .unwrap();
+
+    let remote_tree = nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("bookmarkAAAA", Folder[needs_merge = true, age = 5])
+        })
+    })
+    .into_tree()
+    .unwrap();
+
+    let merger = Merger::new(&local_tree, &remote_tree);
+    match merger.merge() {
+        Ok(_) => panic!("Should not merge trees with mismatched kinds"),
+        Err(err) => {
+            match err.kind() {
+                ErrorKind::MismatchedItemKind { .. } => {}
+                kind => panic!("Got {:?} merging trees with mismatched kinds", kind),
+            };
+        }
+    };
+}
+
+#[test]
+fn invalid_guids() {
+    before_each();
+
+    #[derive(Default)]
+    struct GenerateNewGuid(Cell<usize>);
+
+    impl Driver for GenerateNewGuid {
+        fn generate_new_guid(&self, old_guid: &Guid) -> Result<Guid> {
+            let count = self.0.get();
+            self.0.set(count + 1);
+            assert!(
+                &[")(*&", "shortGUID", "loooooongGUID", "!@#$%^", ""].contains(&old_guid.as_str()),
+                "Didn't expect to generate new GUID for {}",
+                old_guid
+            );
+            Ok(format!("item{:0>8}", count).into())
+        }
+    }
+
+    let local_tree = nodes!({
+        ("toolbar_____", Folder[needs_merge = true, age = 5], {
+            ("bookmarkAAAA", Bookmark[needs_merge = true, age = 5]),
+            ("bookmarkBBBB", Bookmark[needs_merge = true, age = 5]),
+            (")(*&", Bookmark[needs_merge = true, age = 5])
+        }),
+        ("menu________", Folder[needs_merge = true], {
+            ("shortGUID", Bookmark[needs_merge = true]),
+            ("loooooongGUID", Bookmark[needs_merge = true])
+        })
+    })
+    .into_tree()
+    .unwrap();
+
+    let remote_tree = nodes!({
+        ("toolbar_____", Folder[needs_merge = true, age = 5], {
+            ("!@#$%^", Bookmark[needs_merge = true, age = 5]),
+            ("shortGUID", Bookmark[needs_merge = true, age = 5]),
+            ("", Bookmark[needs_merge = true, age = 5]),
+            ("loooooongGUID", Bookmark[needs_merge = true, age = 5])
+        }),
+        ("menu________", Folder[needs_merge = true], {
+            ("bookmarkAAAA", Bookmark[needs_merge = true]),
+            ("bookmarkBBBB", Bookmark[needs_merge = true])
+        })
+    })
+    .into_tree()
+    .unwrap();
+
+    let driver = GenerateNewGuid::default();
+    let merger = Merger::with_driver(&driver, &DefaultAbortSignal, &local_tree, &remote_tree);
+    let merged_root = merger.merge().unwrap();
+
+    let expected_tree = merged_nodes!({
+        ("toolbar_____", LocalWithNewLocalStructure, {
+            ("item00000000", RemoteWithNewRemoteStructure),
+            ("item00000001", RemoteWithNewRemoteStructure),
+            ("item00000002", LocalWithNewLocalStructure)
+        }),
+        ("menu________", LocalWithNewLocalStructure, {
+            ("bookmarkAAAA", Remote),
+            ("bookmarkBBBB", Remote),
+            ("item00000003", LocalWithNewLocalStructure),
+            ("item00000004", LocalWithNewLocalStructure)
+        })
+    });
+    let expected_deletions = &["", "!@#$%^", "loooooongGUID", "shortGUID"];
+    let expected_telem = StructureCounts {
+        merged_nodes: 9,
+        ..StructureCounts::default()
+    };
+
+    assert_eq!(&expected_tree, merged_root.node());
+
+    let mut deletions = merged_root.deletions().collect::<Vec<_>>();
+    deletions.sort();
+    assert_eq!(deletions, expected_deletions);
+
+    assert_eq!(merged_root.counts(), &expected_telem);
+}
+
+#[test]
+fn track_guids() {
+    before_each();
+
+    struct GenerateNewGuid;
+
+    impl Driver for GenerateNewGuid {
+        fn generate_new_guid(&self, old_guid: &Guid) -> Result<Guid> {
+            assert_eq!(old_guid, "b@dgu1d!");
+            Ok("bookmarkNNNN".into())
+        }
+    }
+
+    // Local items and tombstones use indices in the local tree, remote-only
+    // ones use indices in the remote tree, and tombstones for items that
+    // aren't in either tree, or new GUIDs, are interned.
+    let mut local_tree_builder = Builder::try_from(nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("bookmarkAAAA", Bookmark),
+            ("bookmarkBBBB", Bookmark)
+        })
+    }))
+    .unwrap();
+    local_tree_builder
+        .deletion("bookmarkCCCC".into())
+        .deletion("bookmarkXXXX".into());
+    let local_tree = local_tree_builder.into_tree().unwrap();
+
+    let mut remote_tree_builder = Builder::try_from(nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("bookmarkAAAA", Bookmark),
+            ("bookmarkCCCC", Bookmark),
+            ("b@dgu1d!", Bookmark[needs_merge = true])
+        })
+    }))
+    .unwrap();
+    remote_tree_builder
+        .deletion("bookmarkBBBB".into())
+        .deletion("bookmarkXXXX".into())
+        .deletion("bookmarkYYYY".into());
+    let remote_tree = remote_tree_builder.into_tree().unwrap();
+
+    let merger = Merger::with_driver(
+        &GenerateNewGuid,
+        &DefaultAbortSignal,
+        &local_tree,
+        &remote_tree,
+    );
+    let merged_root = merger.merge().unwrap();
+
+    let expected_tree = merged_nodes!({
+        ("menu________", LocalWithNewLocalStructure, {
+            ("bookmarkAAAA", Unchanged),
+            ("bookmarkNNNN", RemoteWithNewRemoteStructure)
+        })
+    });
+    assert_eq!(&expected_tree, merged_root.node());
+
+    let sorted = |guids: Vec<&Guid>| {
+        let mut guids = guids
+            .into_iter()
+            .map(|guid| guid.as_str().to_owned())
+            .collect::<Vec<_>>();
+        guids.sort_unstable();
+        guids
+    };
+    assert_eq!(
+        sorted(merged_root.local_deletions().collect()),
+        &["bookmarkBBBB", "bookmarkYYYY"]
+    );
+    assert_eq!(
+        sorted(merged_root.remote_deletions().collect()),
+        &["b@dgu1d!", "bookmarkCCCC", "bookmarkXXXX"]
+    );
+    assert_eq!(
+        sorted(merged_root.deletions().collect()),
+        &[
+            "b@dgu1d!",
+            "bookmarkBBBB",
+            "bookmarkCCCC",
+            "bookmarkXXXX",
+            "bookmarkYYYY"
+        ]
+    );
+}
+
+#[cfg(feature = "rand")]
+#[test]
+fn random_guids() {
+    use crate::driver::RandomGuidDriver;
+    use crate::guid::IsValidGuid;
+
+    before_each();
+
+    let guid = Guid::random();
+    assert_eq!(guid.as_str().len(), 12);
+    assert!(guid.is_valid_guid());
+    assert_ne!(guid, Guid::random());
+
+    let local_tree = nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("shortGUID", Bookmark[needs_merge = true])
+        })
+    })
+    .into_tree()
+    .unwrap();
+    let remote_tree = nodes!({ ("menu________", Folder) }).into_tree().unwrap();
+
+    let merger = Merger::with_driver(
+        &RandomGuidDriver,
+        &DefaultAbortSignal,
+        &local_tree,
+        &remote_tree,
+    );
+    let merged_root = merger.merge().unwrap();
+
+    let merged_menu_node = &merged_root.node().merged_children[0];
+    assert_eq!(merged_menu_node.merged_children.len(), 1);
+    let merged_guid = &merged_menu_node.merged_children[0].guid;
+    assert!(merged_guid.is_valid_guid());
+    assert_ne!(merged_guid, "shortGUID");
+}
+
+#[test]
+fn seeded_guids() {
+    before_each();
+
+    let local_tree = nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("shortGUID", Bookmark[needs_merge = true]),
+            ("loooooongGUID", Bookmark[needs_merge = true])
+        })
+    })
+    .into_tree()
+    .unwrap();
+    let remote_tree = nodes!({ ("menu________", Folder) }).into_tree().unwrap();
+
+    let merge = |seed| {
+        let driver = SeededGuidDriver::new(seed);
+        let merger = Merger::with_driver(&driver, &DefaultAbortSignal, &local_tree, &remote_tree);
+        let merged_root = merger.merge().unwrap();
+        let ops = merged_root.completion_ops();
+        (
+            merged_root.node().to_ascii_string(),
+            to_strings(&ops.change_guids).collect::<Vec<_>>(),
+        )
+    };
+
+    // The same seed always generates the same GUIDs.
+    let (merged_tree, change_guids) = merge(1);
+    assert_eq!(merge(1), (merged_tree.clone(), change_guids.clone()));
+    assert_ne!(merge(2).1, change_guids);
+    assert_eq!(
+        change_guids,
+        &[
+            "Change shortGUID to wVwCiewtCpFn",
+            "Change loooooongGUID to XlUy--6ik_gL"
+        ]
+    );
+    assert!(merged_tree.contains("wVwCiewtCpFn"));
+}
+
+#[test]
+fn multiple_parents() {
+    before_each();
+
+    let local_tree = Tree::with_root(Item::new(ROOT_GUID, Kind::Folder))
+        .into_tree()
+        .unwrap();
+
+    let remote_tree = nodes!({
+        ("toolbar_____", Folder[age = 5], {
+            ("bookmarkAAAA", Bookmark),
+            ("bookmarkBBBB", Bookmark),
+            ("folderCCCCCC", Folder, {
+                ("bookmarkDDDD", Bookmark),
+                ("bookmarkEEEE", Bookmark),
+                ("bookmarkFFFF", Bookmark)
+            })
+        }),
+        ("menu________", Folder, {
+            ("bookmarkGGGG", Bookmark),
+            ("bookmarkAAAA", Bookmark),
+            ("folderCCCCCC", Folder, {
+                ("bookmarkHHHH", Bookmark),
+                ("bookmarkDDDD", Bookmark)
+            })
+        })
+    })
+    .into_tree()
+    .unwrap();
+
+    let merger = Merger::new(&local_tree, &remote_tree);
+    let merged_root = merger.merge().unwrap();
+
+    let expected_tree = merged_nodes!(ROOT_GUID, UnchangedWithNewLocalStructure, {
+        ("toolbar_____", RemoteWithNewRemoteStructure, {
+            ("bookmarkBBBB", Remote)
+        }),
+        ("menu________", RemoteWithNewRemoteStructure, {
+            ("bookmarkGGGG", Remote),
+            ("bookmarkAAAA", RemoteWithNewRemoteStructure),
+            ("folderCCCCCC", RemoteWithNewRemoteStructure, {
+                ("bookmarkDDDD", RemoteWithNewRemoteStructure),
+                ("bookmarkEEEE", Remote),
+                ("bookmarkFFFF", Remote),
+                ("bookmarkHHHH", Remote)
+            })
+        })
+    });
+    let expected_telem = StructureCounts {
+        merged_nodes: 10,
+        ..StructureCounts::default()
+    };
+
+    assert_eq!(&expected_tree, merged_root.node());
+
+    assert_eq!(merged_root.deletions().count(), 0);
+
+    assert_eq!(merged_root.counts(), &expected_telem);
+}
+
+#[test]
+fn reparent_orphans() {
+    before_each();
+
+    let local_tree = nodes!({
+        ("toolbar_____", Folder, {
+            ("bookmarkAAAA", Bookmark),
+            ("bookmarkBBBB", Bookmark)
+        }),
+        ("unfiled_____", Folder, {
+            ("bookmarkCCCC", Bookmark)
+        })
+    })
+    .into_tree()
+    .unwrap();
+
+    let mut remote_tree_builder: Builder = nodes!({
+        ("toolbar_____", Folder[needs_merge = true], {
+            ("bookmarkBBBB", Bookmark),
+            ("bookmarkAAAA", Bookmark)
+        }),
+        ("unfiled_____", Folder[needs_merge = true], {
+            ("bookmarkDDDD", Bookmark[needs_merge = true]),
+            ("bookmarkCCCC", Bookmark)
+        })
+    })
+    .try_into()
+    .unwrap();
+    remote_tree_builder
+        .item(Item {
+            guid: "bookmarkEEEE".into(),
+            kind: Kind::Bookmark,
+            age: 0,
+            modified: None,
+            version: None,
+            needs_merge: true,
+            validity: Validity::Valid,
+            tags: BTreeSet::new(),
+        })
+        .and_then(|p| p.by_parent_guid("toolbar_____".into()))
+        .expect("Should insert orphan E");
+    remote_tree_builder
+        .item(Item {
+            guid: "bookmarkFFFF".into(),
+            kind: Kind::Bookmark,
+            age: 0,
+            modified: None,
+            version: None,
+            needs_merge: true,
+            validity: Validity::Valid,
+            tags: BTreeSet::new(),
+        })
+        .and_then(|p| p.by_parent_guid("nonexistent".into()))
+        .expect("Should insert orphan F");
+    let remote_tree = remote_tree_builder.into_tree().unwrap();
+
+    let merger = Merger::new(&local_tree, &remote_tree);
+    let merged_root = merger.merge().unwrap();
+
+    let expected_tree = merged_nodes!({
+        ("toolbar_____", LocalWithNewLocalStructure, {
+            ("bookmarkBBBB", Unchanged),
+            ("bookmarkAAAA", Unchanged),
+            ("bookmarkEEEE", RemoteWithNewRemoteStructure)
+        }),
+        ("unfiled_____", LocalWithNewLocalStructure, {
+            ("bookmarkDDDD", Remote),
+            ("bookmarkCCCC", Unchanged),
+            ("bookmarkFFFF", RemoteWithNewRemoteStructure)
+        })
+    });
+    let expected_telem = StructureCounts {
+        merged_nodes: 8,
+        ..StructureCounts::default()
+    };
+
+    assert_eq!(&expected_tree, merged_root.node());
+
+    assert_eq!(merged_root.deletions().count(), 0);
+
+    assert_eq!(merged_root.counts(), &expected_telem);
+}
+
+#[test]
+fn deleted_user_content_roots() {
+    before_each();
+
+    let mut local_tree_builder = Builder::try_from(nodes!({
+        ("unfiled_____", Folder[needs_merge = true], {
+            ("bookmarkAAAA", Bookmark[needs_merge = true])
+        })
+    }))
+    .unwrap();
+    local_tree_builder
+        .deletion("mobile______".into())
+        .deletion("toolbar_____".into());
+    let local_tree = local_tree_builder.into_tree().unwrap();
+
+    let mut remote_tree_builder = Builder::try_from(nodes!({
+        ("mobile______", Folder[needs_merge = true], {
+            ("bookmarkBBBB", Bookmark[needs_merge = true])
+        })
+    }))
+    .unwrap();
+    remote_tree_builder
+        .deletion("unfiled_____".into())
+        .deletion("toolbar_____".into());
+    let remote_tree = remote_tree_builder.into_tree().unwrap();
+
+    let merger = Merger::new(&local_tree, &remote_tree);
+    let merged_root = merger.merge().unwrap();
+
+    let expected_tree = merged_nodes!(ROOT_GUID, LocalWithNewLocalStructure, {
+        ("unfiled_____", Local, {
+            ("bookmarkAAAA", Local)
+        }),
+        ("mobile______", Remote, {
+            ("bookmarkBBBB", Remote)
+        })
+    });
+    let expected_telem = StructureCounts {
+        merged_nodes: 4,
+        ..StructureCounts::default()
+    };
+
+    assert_eq!(&expected_tree, merged_root.node());
+
+    assert_eq!(merged_root.deletions().count(), 1);
+
+    assert_eq!(merged_root.counts(), &expected_telem);
+}
+
+#[test]
+fn moved_user_content_roots() {
+    before_each();
+
+    let local_tree = nodes!({
+        ("unfiled_____", Folder[needs_merge = true], {
+            ("bookmarkAAAA", Bookmark[needs_merge = true]),
+            ("menu________", Folder[needs_merge = true], {
+                ("bookmarkBBBB", Bookmark[needs_merge = true]),
+                ("folderCCCCCC", Folder, {
+                    ("bookmarkDDDD", Bookmark),
+                    ("toolbar_____", Folder, {
+                        ("bookmarkEEEE", Bookmark)
+                    })
+                })
+            })
+        }),
+        ("mobile______", Folder, {
+            ("bookmarkFFFF", Bookmark)
+        })
+    })
+    .into_tree()
+    .unwrap();
+
+    let remote_tree = nodes!({
+        ("mobile______", Folder[needs_merge = true], {
+            ("toolbar_____", Folder[needs_merge = true], {
+                ("bookmarkGGGG", Bookmark[needs_merge = true]),
+                ("bookmarkEEEE", Bookmark)
+            })
+        }),
+        ("menu________", Folder[needs_merge = true], {
+            ("bookmarkHHHH", Bookmark[needs_merge = true]),
+            ("unfiled_____", Folder[needs_merge = true], {
+                ("bookmarkIIII", Bookmark[needs_merge = true])
+            })
+        })
+    })
+    .into_tree()
+    .unwrap();
+
+    let merger = Merger::new(&local_tree, &remote_tree);
+    let merged_root = merger.merge().unwrap();
+
+    let expected_tree = merged_nodes!(ROOT_GUID, LocalWithNewLocalStructure, {
+        ("unfiled_____", LocalWithNewLocalStructure, {
+            ("bookmarkIIII", Remote),
+            ("bookmarkAAAA", Local)
+        }),
+        ("mobile______", Local, {
+            ("bookmarkFFFF", Local)
+        }),
+        ("menu________", LocalWithNewLocalStructure, {
+           ("bookmarkHHHH", Remote),
+           ("bookmarkBBBB", Local),
+           ("folderCCCCCC", LocalWithNewLocalStructure, {
+               ("bookmarkDDDD", Local)
+           })
+        }),
+        ("toolbar_____", LocalWithNewLocalStructure, {
+            ("bookmarkGGGG", Remote),
+            ("bookmarkEEEE", Unchanged)
+        })
+    });
+    let expected_telem = StructureCounts {
+        merged_nodes: 13,
+        ..StructureCounts::default()
+    };
+
+    assert_eq!(&expected_tree, merged_root.node());
+
+    assert_eq!(merged_root.deletions().count(), 0);
+
+    assert_eq!(merged_root.counts(), &expected_telem);
+}
+
+#[test]
+fn cycle() {
+    before_each();
+
+    // Try to create a cycle: move A into B, and B into the menu, but keep
+    // B's parent by children as A.
+    let mut b: Builder = nodes!({ ("menu________", Folder) }).try_into().unwrap();
+
+    b.item(Item::new("folderAAAAAA".into(), Kind::Folder))
+        .and_then(|p| p.by_parent_guid("folderBBBBBB".into()))
+        .expect("Should insert A");
+
+    b.item(Item::new("folderBBBBBB".into(), Kind::Folder))
+        .and_then(|p| p.by_parent_guid("menu________".into()))
+        .and_then(|b| {
+            b.parent_for(&"folderBBBBBB".into())
+                .by_children(&"folderAAAAAA".into())
+        })
+        .expect("Should insert B");
+
+    match b
+        .into_tree()
+        .expect_err("Should not build tree with cycles")
+        .kind()
+    {
+        ErrorKind::Cycle(guid) => assert_eq!(guid, &Guid::from("folderAAAAAA")),
+        err => panic!("Wrong error kind for cycle: {:?}", err),
+    }
+}
+
+#[test]
+fn reupload_replace() {
+    before_each();
+
+    let mut local_tree_builder = Builder::try_from(nodes!({
+        ("menu________", Folder, {
+            ("bookmarkAAAA", Bookmark)
+        }),
+        ("toolbar_____", Folder, {
+            ("folderBBBBBB", Folder, {
+                ("bookmarkCCCC", Bookmark[validity = Validity::Replace])
+            }),
+            ("folderDDDDDD", Folder, {
+                ("bookmarkEEEE", Bookmark[validity = Validity::Replace])
+            })
+        }),
+        ("unfiled_____", Folder),
+        ("mobile______", Folder, {
+            ("bookmarkFFFF", Bookmark[validity = Validity::Replace]),
+            ("folderGGGGGG", Folder),
+            ("bookmarkHHHH", Bookmark[validity = Validity::Replace])
+        })
+    }))
+    .unwrap();
+    local_tree_builder.deletion("bookmarkIIII".into());
+    let local_tree = local_tree_builder.into_tree().unwrap();
+
+    let mut remote_tree_builder = Builder::try_from(nodes!({
+        ("menu________", Folder, {
+            ("bookmarkAAAA", Bookmark[validity = Validity::Replace])
+        }),
+        ("toolbar_____", Folder, {
+            ("bookmarkJJJJ", Bookmark[validity = Validity::Replace]),
+            ("folderBBBBBB", Folder, {
+                ("bookmarkCCCC", Bookmark[validity = Validity::Replace])
+            }),
+            ("folderDDDDDD", Folder)
+        }),
+        ("unfiled_____", Folder, {
+            ("bookmarkKKKK", Bookmark[validity = Validity::Reupload])
+        }),
+        ("mobile______", Folder, {
+            ("bookmarkFFFF", Bookmark),
+            ("folderGGGGGG", Folder, {
+                ("bookmarkIIII", Bookmark[validity = Validity::Replace])
+            })
+        })
+    }))
+    .unwrap();
+    remote_tree_builder.deletion("bookmarkEEEE".into());
+    let remote_tree = remote_tree_builder.into_tree().unwrap();
+
+    let merger = Merger::new(&local_tree, &remote_tree);
+    let merged_root = merger.merge().unwrap();
+
+    let expected_tree = merged_nodes!({
+        ("menu________", Unchanged, {
+            // A is invalid remotely and valid locally, so replace.
             ("bookmarkAAAA", Local)
         }),
-        // Toolbar has new children.
-        ("toolbar_____", LocalWithNewLocalStructure, {
-            // B has new children.
-            ("folderBBBBBB", LocalWithNewLocalStructure),
-            ("folderDDDDDD", UnchangedWithNewLocalStructure)
+        // Toolbar has new children.
+        ("toolbar_____", LocalWithNewLocalStructure, {
+            // B has new children.
+            ("folderBBBBBB", LocalWithNewLocalStructure),
+            ("folderDDDDDD", UnchangedWithNewLocalStructure)
+        }),
+        ("unfiled_____", UnchangedWithNewLocalStructure, {
+            // K was flagged for reupload.
+           ("bookmarkKKKK", RemoteWithNewRemoteStructure)
+        }),
+        ("mobile______", UnchangedWithNewLocalStructure, {
+            // F is invalid locally, so replace with remote. This isn't
+            // possible in Firefox Desktop or Rust Places, where the local
+            // tree is always valid, but we handle it for symmetry.
+            ("bookmarkFFFF", Remote),
+            ("folderGGGGGG", Local)
+        })
+    });
+    let expected_deletions = &[
+        // C is invalid on both sides, so we need to upload a tombstone.
+        "bookmarkCCCC",
+        // E is invalid locally and deleted remotely, so doesn't need a
+        // tombstone.
+        "bookmarkEEEE",
+        // H is invalid locally and doesn't exist remotely, so doesn't need a
+        // tombstone.
+        "bookmarkHHHH",
+        // I is deleted locally and invalid remotely, so needs a tombstone.
+        "bookmarkIIII",
+        // J doesn't exist locally and invalid remotely, so needs a tombstone.
+        "bookmarkJJJJ",
+    ];
+    let expected_telem = StructureCounts {
+        merged_nodes: 10,
+        ..StructureCounts::default()
+    };
+
+    assert_eq!(&expected_tree, merged_root.node());
+
+    let mut deletions = merged_root.deletions().collect::<Vec<_>>();
+    deletions.sort();
+    assert_eq!(deletions, expected_deletions);
+
+    let ops = merged_root.completion_ops();
+    let mut summary = ops.summarize();
+    summary.sort();
+    assert_eq!(
+        summary,
+        &[
+            "Apply remote bookmarkFFFF",
+            "Apply remote bookmarkKKKK",
+            "Delete local item bookmarkCCCC",
+            "Delete local item bookmarkEEEE",
+            "Delete local item bookmarkHHHH",
+            "Flag local bookmarkAAAA as unmerged",
+            "Flag local bookmarkKKKK as unmerged",
+            "Flag local folderBBBBBB as unmerged",
+            "Flag local folderGGGGGG as unmerged",
+            "Flag local toolbar_____ as unmerged",
+            "Flag remote bookmarkEEEE as merged",
+            "Insert local tombstone bookmarkCCCC",
+            "Insert local tombstone bookmarkJJJJ",
+            "Move bookmarkKKKK into unfiled_____ at 0",
+            "Upload item bookmarkAAAA",
+            "Upload item bookmarkKKKK",
+            "Upload item folderBBBBBB",
+            "Upload item folderGGGGGG",
+            "Upload item toolbar_____",
+            "Upload tombstone bookmarkCCCC",
+            "Upload tombstone bookmarkIIII",
+            "Upload tombstone bookmarkJJJJ",
+        ]
+    );
+
+    assert_eq!(merged_root.counts(), &expected_telem);
+}
+
+#[test]
+fn completion_ops() {
+    let mut local_tree_builder = Builder::try_from(nodes!({
+        ("menu________", Folder, {
+            ("bookmarkAAAA", Bookmark),
+            ("bookmarkBBBB", Bookmark),
+            ("bookmarkCCCC", Bookmark),
+            ("bookmarkDDDD", Bookmark)
+        }),
+        ("toolbar_____", Folder, {
+            ("bookmarkEEEE", Bookmark)
+        }),
+        ("unfiled_____", Folder[needs_merge = true], {
+            ("bookmarkIIII", Bookmark)
+        }),
+        ("mobile______", Folder, {
+            ("bookmarkFFFF", Bookmark[needs_merge = true, age = 10])
+        })
+    }))
+    .unwrap();
+    local_tree_builder.deletion("bookmarkJJJJ".into());
+    let local_tree = local_tree_builder.into_tree().unwrap();
+
+    let mut remote_tree_builder = Builder::try_from(nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("bookmarkAAAA", Bookmark),
+            ("bookmarkDDDD", Bookmark),
+            ("bookmarkCCCC", Bookmark),
+            ("bookmarkBBBB", Bookmark),
+            ("bookmarkEEEE", Bookmark[needs_merge = true])
+        }),
+        ("toolbar_____", Folder[needs_merge = true], {
+            ("bookmarkGGGG", Bookmark[needs_merge = true])
+        }),
+        ("unfiled_____", Folder[needs_merge = true], {
+            ("bookmarkHHHH", Bookmark[needs_merge = true]),
+            ("bookmarkJJJJ", Bookmark)
+        }),
+        ("mobile______", Folder, {
+            ("bookmarkFFFF", Bookmark[needs_merge = true, age = 5])
+        })
+    }))
+    .unwrap();
+    remote_tree_builder.deletion("bookmarkIIII".into());
+    let remote_tree = remote_tree_builder.into_tree().unwrap();
+
+    let merger = Merger::new(&local_tree, &remote_tree);
+    let merged_root = merger.merge().unwrap();
+
+    let expected_tree = merged_nodes!({
+        ("menu________", UnchangedWithNewLocalStructure, {
+            ("bookmarkAAAA", Unchanged),
+            ("bookmarkDDDD", Unchanged),
+            ("bookmarkCCCC", Unchanged),
+            ("bookmarkBBBB", Unchanged),
+            ("bookmarkEEEE", Remote)
+        }),
+        ("toolbar_____", UnchangedWithNewLocalStructure, {
+            ("bookmarkGGGG", Remote)
+        }),
+        ("unfiled_____", LocalWithNewLocalStructure, {
+           ("bookmarkHHHH", Remote)
+        }),
+        ("mobile______", Unchanged, {
+            ("bookmarkFFFF", Remote)
+        })
+    });
+
+    assert_eq!(&expected_tree, merged_root.node());
+
+    let ops = merged_root.completion_ops();
+    assert!(ops.change_guids.is_empty());
+    assert_eq!(
+        to_strings(&ops.apply_remote_items).collect::<Vec<_>>(),
+        &[
+            "Apply remote bookmarkEEEE",
+            "Apply remote bookmarkGGGG",
+            "Apply remote bookmarkHHHH",
+            "Apply remote bookmarkFFFF",
+        ]
+    );
+    assert_eq!(
+        to_strings(&ops.apply_new_local_structure).collect::<Vec<_>>(),
+        &[
+            "Move bookmarkDDDD into menu________ at 1",
+            "Move bookmarkBBBB into menu________ at 3",
+            "Move bookmarkEEEE into menu________ at 4",
+            "Move bookmarkGGGG into toolbar_____ at 0",
+            "Move bookmarkHHHH into unfiled_____ at 0",
+        ]
+    );
+    assert!(ops.set_local_unmerged.is_empty());
+    assert_eq!(
+        to_strings(&ops.set_local_merged).collect::<Vec<_>>(),
+        &["Flag local bookmarkFFFF as merged"]
+    );
+    assert_eq!(
+        to_strings(&ops.set_remote_merged).collect::<Vec<_>>(),
+        &[
+            "Flag remote menu________ as merged",
+            "Flag remote bookmarkEEEE as merged",
+            "Flag remote toolbar_____ as merged",
+            "Flag remote bookmarkGGGG as merged",
+            "Flag remote bookmarkHHHH as merged",
+            "Flag remote bookmarkFFFF as merged",
+            "Flag remote bookmarkIIII as merged",
+        ]
+    );
+    let mut delete_local_items = to_strings(&ops.delete_local_items).collect::<Vec<_>>();
+    delete_local_items.sort();
+    assert_eq!(delete_local_items, &["Delete local item bookmarkIIII"]);
+    assert!(ops.insert_local_tombstones.is_empty());
+    assert_eq!(
+        to_strings(&ops.upload_items).collect::<Vec<_>>(),
+        &["Upload item unfiled_____"]
+    );
+    let mut upload_tombstones = to_strings(&ops.upload_tombstones).collect::<Vec<_>>();
+    upload_tombstones.sort();
+    assert_eq!(upload_tombstones, &["Upload tombstone bookmarkJJJJ"]);
+}
+
+#[test]
+fn merge_preview() {
+    before_each();
+
+    fn local_tree() -> Tree {
+        let mut local_tree_builder = Builder::try_from(nodes!({
+            ("menu________", Folder[needs_merge = true], {
+                ("bookmarkBBBB", Bookmark),
+                ("bookmarkAAA1", Bookmark[needs_merge = true])
+            }),
+            ("toolbar_____", Folder, {
+                ("bookmarkCCCC", Bookmark)
+            })
+        }))
+        .unwrap();
+        local_tree_builder
+            .mutate(&"bookmarkAAA1".into())
+            .content(Content::Bookmark {
+                title: "A".into(),
+                url_href: "http://example.com/a".into(),
+                keyword: None,
+                description: None,
+            });
+        local_tree_builder.into_tree().unwrap()
+    }
+
+    fn remote_tree() -> Tree {
+        let mut remote_tree_builder = Builder::try_from(nodes!({
+            ("menu________", Folder[needs_merge = true], {
+                ("bookmarkAAAA", Bookmark[needs_merge = true]),
+                ("bookmarkDDDD", Bookmark[needs_merge = true]),
+                ("bookmarkBBBB", Bookmark)
+            }),
+            ("toolbar_____", Folder[needs_merge = true])
+        }))
+        .unwrap();
+        remote_tree_builder
+            .mutate(&"bookmarkAAAA".into())
+            .content(Content::Bookmark {
+                title: "A".into(),
+                url_href: "http://example.com/a".into(),
+                keyword: None,
+                description: None,
+            });
+        remote_tree_builder.deletion("bookmarkCCCC".into());
+        remote_tree_builder.into_tree().unwrap()
+    }
+
+    struct PreviewStore;
+
+    impl Store for PreviewStore {
+        type Ok = ();
+        type Error = Error;
+
+        fn fetch_local_tree(&self) -> Result<Tree> {
+            Ok(local_tree())
+        }
+
+        fn fetch_remote_tree(&self) -> Result<Tree> {
+            Ok(remote_tree())
+        }
+
+        fn apply(&mut self, _: MergedRoot<'_>) -> Result<()> {
+            panic!("Previewing a merge shouldn't apply it");
+        }
+    }
+
+    let preview = PreviewStore.merge_preview().unwrap();
+
+    let expected_preview = MergePreview {
+        additions: vec!["bookmarkDDDD".into()],
+        updates: vec!["bookmarkAAAA".into()],
+        moves: vec!["bookmarkAAAA".into(), "bookmarkBBBB".into()],
+        local_deletions: vec!["bookmarkCCCC".into()],
+        uploads: vec!["menu________".into()],
+        remote_deletions: Vec::new(),
+        dupes: vec![DedupedItem {
+            local_guid: "bookmarkAAA1".into(),
+            remote_guid: "bookmarkAAAA".into(),
+        }],
+        counts: CompletionOpCounts {
+            change_guids: 1,
+            apply_remote_items: 2,
+            apply_new_local_structure: 3,
+            set_local_merged: 1,
+            set_remote_merged: 4,
+            delete_local_items: 1,
+            upload_items: 1,
+            ..CompletionOpCounts::default()
+        },
+    };
+    assert_eq!(preview, expected_preview);
+    assert!(!preview.is_empty());
+
+    let (local_tree, remote_tree) = (local_tree(), remote_tree());
+    let merger = Merger::new(&local_tree, &remote_tree);
+    assert_eq!(merger.preview().unwrap(), preview);
+
+    // Previews aren't syncs, so they shouldn't record telemetry, but they
+    // should still use the rest of the driver.
+    #[derive(Default)]
+    struct CountEvents {
+        events: Cell<usize>,
+        progress: Cell<usize>,
+    }
+
+    impl Driver for CountEvents {
+        fn report_progress(&self, _: Progress) {
+            self.progress.set(self.progress.get() + 1);
+        }
+
+        fn record_telemetry_event(&self, _: TelemetryEvent) {
+            self.events.set(self.events.get() + 1);
+        }
+    }
+
+    let driver = CountEvents::default();
+    PreviewStore
+        .merge_preview_with_driver(&driver, &DefaultAbortSignal)
+        .unwrap();
+    assert_eq!(driver.events.get(), 0);
+    assert!(driver.progress.get() > 0);
+
+    let driver = CountEvents::default();
+    let merger = Merger::with_driver(&driver, &DefaultAbortSignal, &local_tree, &remote_tree);
+    assert_eq!(merger.preview().unwrap(), preview);
+    assert_eq!(driver.events.get(), 0);
+}
+
+/// A minimal local store for applying merged trees and undo journals.
+struct LocalStore {
+    items: HashMap<Guid, (Item, Option<Content>)>,
+    children: HashMap<Guid, Vec<Guid>>,
+    tombstones: HashSet<Guid>,
+}
+
+impl LocalStore {
+    fn new(local_tree: &Tree) -> LocalStore {
+        let mut store = LocalStore {
+            items: HashMap::new(),
+            children: HashMap::new(),
+            tombstones: local_tree.deletions().clone(),
+        };
+        let mut stack = vec![local_tree.root()];
+        while let Some(node) = stack.pop() {
+            store.items.insert(
+                node.guid.clone(),
+                (node.item().clone(), node.content().cloned()),
+            );
+            if node.is_folder() {
+                store.children.insert(
+                    node.guid.clone(),
+                    node.children().map(|child| child.guid.clone()).collect(),
+                );
+            }
+            stack.extend(node.children());
+        }
+        store
+    }
+
+    /// Applies completion ops for a merged tree, like `Store::apply`.
+    fn apply(&mut self, merged_root: &MergedRoot<'_>) {
+        let ops = merged_root.completion_ops();
+        for op in &ops.change_guids {
+            self.rename(&op.local_node().guid, &op.merged_node.guid);
+        }
+        for op in &ops.apply_remote_items {
+            let remote_node = op.remote_node();
+            let mut item = remote_node.item().clone();
+            item.guid = op.merged_node.guid.clone();
+            self.items
+                .insert(item.guid.clone(), (item, remote_node.content().cloned()));
+        }
+        for op in &ops.apply_merged_tags {
+            self.items.get_mut(&op.merged_node.guid).unwrap().0.tags = op.tags().clone();
+        }
+        for op in &ops.apply_merged_content {
+            self.items.get_mut(&op.merged_node.guid).unwrap().1 = op.content().cloned();
+        }
+        for op in &ops.delete_local_items {
+            self.remove(&op.local_node().guid);
+        }
+        let mut stack = vec![merged_root.node()];
+        while let Some(merged_node) = stack.pop() {
+            if !merged_node.merged_children.is_empty()
+                || self.children.contains_key(&merged_node.guid)
+            {
+                self.children.insert(
+                    merged_node.guid.clone(),
+                    merged_node
+                        .merged_children
+                        .iter()
+                        .map(|child| child.guid.clone())
+                        .collect(),
+                );
+            }
+            stack.extend(merged_node.merged_children.iter());
+        }
+        for op in &ops.set_local_unmerged {
+            self.items
+                .get_mut(&op.merged_node.guid)
+                .unwrap()
+                .0
+                .needs_merge = true;
+        }
+        for op in &ops.set_local_merged {
+            self.items
+                .get_mut(&op.merged_node.guid)
+                .unwrap()
+                .0
+                .needs_merge = false;
+        }
+        for op in &ops.insert_local_tombstones {
+            self.tombstones.insert(op.remote_node().guid.clone());
+        }
+        for op in &ops.delete_local_tombstones {
+            self.tombstones.remove(op.guid());
+        }
+    }
+
+    /// Applies an undo journal, in field order.
+    fn undo(&mut self, journal: &UndoJournal) {
+        for op in &journal.restore_guids {
+            self.rename(&op.merged_guid, &op.local_guid);
+        }
+        for op in &journal.delete_items {
+            self.remove(op.guid());
+        }
+        for op in &journal.restore_items {
+            let guid = op.item.guid.clone();
+            if op.item.is_folder() {
+                self.children.entry(guid.clone()).or_default();
+            }
+            self.items
+                .insert(guid.clone(), (op.item.clone(), op.content.clone()));
+            if let Some(parent_guid) = &op.parent_guid {
+                for child_guids in self.children.values_mut() {
+                    child_guids.retain(|child_guid| *child_guid != guid);
+                }
+                let child_guids = self.children.get_mut(parent_guid).unwrap();
+                child_guids.insert(op.position.min(child_guids.len()), guid);
+            }
+        }
+        for op in &journal.restore_children {
+            self.children
+                .insert(op.parent_guid.clone(), op.child_guids.clone());
+        }
+        for op in &journal.restore_needs_merge {
+            self.items.get_mut(&op.guid).unwrap().0.needs_merge = op.needs_merge;
+        }
+        for op in &journal.delete_tombstones {
+            self.tombstones.remove(op.guid());
+        }
+        for op in &journal.restore_tombstones {
+            self.tombstones.insert(op.guid().clone());
+        }
+    }
+
+    fn rename(&mut self, old_guid: &Guid, new_guid: &Guid) {
+        let (mut item, content) = self.items.remove(old_guid).unwrap();
+        item.guid = new_guid.clone();
+        self.items.insert(new_guid.clone(), (item, content));
+        if let Some(child_guids) = self.children.remove(old_guid) {
+            self.children.insert(new_guid.clone(), child_guids);
+        }
+        for child_guids in self.children.values_mut() {
+            for child_guid in child_guids.iter_mut() {
+                if child_guid == old_guid {
+                    *child_guid = new_guid.clone();
+                }
+            }
+        }
+    }
+
+    fn remove(&mut self, guid: &Guid) {
+        self.items.remove(guid);
+        self.children.remove(guid);
+        for child_guids in self.children.values_mut() {
+            child_guids.retain(|child_guid| child_guid != guid);
+        }
+    }
+
+    fn into_tree(self) -> Tree {
+        let (root, _) = self.items[&ROOT_GUID].clone();
+        let mut builder = Tree::with_root(root);
+        let mut stack = vec![ROOT_GUID];
+        while let Some(parent_guid) = stack.pop() {
+            for child_guid in self.children.get(&parent_guid).into_iter().flatten() {
+                let (item, content) = self.items[child_guid].clone();
+                let mut item_builder = builder.item(item).unwrap();
+                if let Some(content) = content {
+                    item_builder.content(content);
+                }
+                item_builder.by_structure(&parent_guid).unwrap();
+                stack.push(child_guid.clone());
+            }
+        }
+        for guid in self.tombstones {
+            builder.deletion(guid);
+        }
+        builder.into_tree().unwrap()
+    }
+}
+
+#[test]
+fn undo_journal() {
+    before_each();
+
+    let mut local_tree_builder = Builder::try_from(nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("bookmarkBBBB", Bookmark),
+            ("bookmarkAAA1", Bookmark[needs_merge = true])
+        }),
+        ("toolbar_____", Folder, {
+            ("bookmarkCCCC", Bookmark)
+        }),
+        ("unfiled_____", Folder)
+    }))
+    .unwrap();
+    local_tree_builder
+        .mutate(&"bookmarkAAA1".into())
+        .content(Content::Bookmark {
+            title: "A".into(),
+            url_href: "http://example.com/a".into(),
+            keyword: None,
+            description: None,
+        });
+    local_tree_builder.deletion("bookmarkEEEE".into());
+    let local_tree = local_tree_builder.into_tree().unwrap();
+
+    let mut remote_tree_builder = Builder::try_from(nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("bookmarkAAAA", Bookmark[needs_merge = true]),
+            ("bookmarkDDDD", Bookmark[needs_merge = true]),
+            ("bookmarkBBBB", Bookmark)
+        }),
+        ("toolbar_____", Folder[needs_merge = true]),
+        ("unfiled_____", Folder[needs_merge = true], {
+            ("bookmarkEEEE", Bookmark[needs_merge = true])
+        })
+    }))
+    .unwrap();
+    remote_tree_builder
+        .mutate(&"bookmarkAAAA".into())
+        .content(Content::Bookmark {
+            title: "A".into(),
+            url_href: "http://example.com/a".into(),
+            keyword: None,
+            description: None,
+        });
+    remote_tree_builder.deletion("bookmarkCCCC".into());
+    let remote_tree = remote_tree_builder.into_tree().unwrap();
+
+    let merger = Merger::new(&local_tree, &remote_tree);
+    let merged_root = merger.merge().unwrap();
+    let journal = UndoJournal::new(&merged_root);
+
+    assert_eq!(
+        journal.summarize(),
+        &[
+            "Change bookmarkAAAA back to bookmarkAAA1",
+            "Delete inserted item bookmarkDDDD",
+            "Delete inserted item bookmarkEEEE",
+            "Restore local bookmarkAAA1 into menu________ at 1",
+            "Restore local bookmarkBBBB into menu________ at 0",
+            "Restore local bookmarkCCCC into toolbar_____ at 0",
+            "Restore children of menu________ as [bookmarkBBBB, bookmarkAAA1]",
+            "Restore children of unfiled_____ as []",
+            "Restore children of toolbar_____ as [bookmarkCCCC]",
+            "Flag local bookmarkAAA1 as unmerged",
+            "Restore local tombstone bookmarkEEEE",
+        ]
+    );
+
+    let restore_item = journal
+        .restore_items
+        .iter()
+        .find(|op| op.item.guid == "bookmarkCCCC")
+        .unwrap();
+    assert_eq!(
+        &restore_item.item,
+        local_tree
+            .node_for_guid(&"bookmarkCCCC".into())
+            .unwrap()
+            .item()
+    );
+    assert_eq!(restore_item.level, 2);
+
+    // Applying the merged tree, then the journal, should restore the local
+    // tree exactly.
+    let mut store = LocalStore::new(&local_tree);
+    store.apply(&merged_root);
+    store.undo(&journal);
+    let restored_tree = store.into_tree();
+    assert_eq!(
+        restored_tree.root().to_ascii_string(),
+        local_tree.root().to_ascii_string()
+    );
+    for guid in local_tree.guids() {
+        assert_eq!(
+            restored_tree
+                .node_for_guid(guid)
+                .and_then(|node| node.content()),
+            local_tree
+                .node_for_guid(guid)
+                .and_then(|node| node.content()),
+        );
+    }
+    assert_eq!(restored_tree.deletions(), local_tree.deletions());
+
+    // Merging unchanged trees doesn't change anything, so there's nothing to
+    // undo.
+    let local_tree = nodes!({ ("menu________", Folder, { ("bookmarkAAAA", Bookmark) }) })
+        .into_tree()
+        .unwrap();
+    let remote_tree = nodes!({ ("menu________", Folder, { ("bookmarkAAAA", Bookmark) }) })
+        .into_tree()
+        .unwrap();
+    let merger = Merger::new(&local_tree, &remote_tree);
+    let merged_root = merger.merge().unwrap();
+    assert!(UndoJournal::new(&merged_root).is_empty());
+}
+
+#[test]
+fn problems() {
+    let mut problems = Problems::default();
+
+    problems
+        .note(&"bookmarkAAAA".into(), Problem::Orphan)
+        .note(
+            &"menu________".into(),
+            Problem::MisparentedRoot(vec![DivergedParent::ByChildren("unfiled_____".into())]),
+        )
+        .note(&"toolbar_____".into(), Problem::MisparentedRoot(Vec::new()))
+        .note(
+            &"bookmarkBBBB".into(),
+            Problem::DivergedParents(vec![
+                DivergedParent::ByChildren("folderCCCCCC".into()),
+                DivergedParentGuid::Folder("folderDDDDDD".into()).into(),
+            ]),
+        )
+        .note(
+            &"bookmarkEEEE".into(),
+            Problem::DivergedParents(vec![
+                DivergedParent::ByChildren("folderFFFFFF".into()),
+                DivergedParentGuid::NonFolder("bookmarkGGGG".into()).into(),
+            ]),
+        )
+        .note(&"bookmarkRRRR".into(), Problem::InvalidItem)
+        .note(
+            &"bookmarkHHHH".into(),
+            Problem::DivergedParents(vec![
+                DivergedParent::ByChildren("folderIIIIII".into()),
+                DivergedParent::ByChildren("folderJJJJJJ".into()),
+                DivergedParentGuid::Missing("folderKKKKKK".into()).into(),
+            ]),
+        )
+        .note(&"bookmarkLLLL".into(), Problem::DivergedParents(Vec::new()))
+        .note(
+            &"folderMMMMMM".into(),
+            Problem::MissingChild {
+                child_guid: "bookmarkNNNN".into(),
+            },
+        )
+        .note(
+            &"folderMMMMMM".into(),
+            Problem::MissingChild {
+                child_guid: "bookmarkOOOO".into(),
+            },
+        )
+        .note(
+            &"bookmarkPPPP".into(),
+            Problem::DivergedParents(vec![
+                DivergedParentGuid::Deleted("folderQQQQQQ".into()).into()
+            ]),
+        )
+        .note(&"bookmarkQQQQ".into(), Problem::InvalidItem);
+
+    let mut summary = problems.summarize().collect::<Vec<_>>();
+    summary.sort_by(|a, b| a.guid().cmp(b.guid()));
+    assert_eq!(
+        to_strings(&summary).collect::<Vec<_>>(),
+        &[
+            "bookmarkAAAA is an orphan",
+            "bookmarkBBBB is in children of folderCCCCCC and has parent folderDDDDDD",
+            "bookmarkEEEE is in children of folderFFFFFF and has non-folder parent bookmarkGGGG",
+            "bookmarkHHHH is in children of folderIIIIII, is in children of folderJJJJJJ, and has \
+             nonexistent parent folderKKKKKK",
+            "bookmarkLLLL has diverged parents",
+            "bookmarkPPPP has deleted parent folderQQQQQQ",
+            "bookmarkQQQQ is invalid",
+            "bookmarkRRRR is invalid",
+            "folderMMMMMM has nonexistent child bookmarkNNNN",
+            "folderMMMMMM has nonexistent child bookmarkOOOO",
+            "menu________ is a user content root, but is in children of unfiled_____",
+            "toolbar_____ is a user content root",
+        ]
+    );
+
+    assert_eq!(
+        problems.counts(),
+        ProblemCounts {
+            orphans: 1,
+            misparented_roots: 2,
+            multiple_parents_by_children: 3,
+            deleted_parent_guids: 1,
+            missing_parent_guids: 1,
+            non_folder_parent_guids: 1,
+            parent_child_disagreements: 7,
+            deleted_children: 0,
+            missing_children: 2,
+            invalid_items: 2,
+        }
+    );
+}
+
+#[test]
+fn telemetry_reports() {
+    before_each();
+
+    fn report(merge_millis: u64, problems: ProblemCounts) -> SyncReport {
+        let mut report = SyncReport::new();
+        report.record(TelemetryEvent::FetchLocalTree(TreeStats {
+            time: Duration::from_millis(10),
+            items: 5,
+            deletions: 1,
+            problems,
+        }));
+        report.record(TelemetryEvent::Merge(
+            Duration::from_millis(merge_millis),
+            StructureCounts {
+                merged_nodes: 4,
+                ..StructureCounts::default()
+            },
+        ));
+        report
+    }
+
+    let mut aggregator = TelemetryAggregator::new(4);
+    for merge_millis in &[100, 1, 2, 3, 4] {
+        aggregator.add(report(
+            *merge_millis,
+            ProblemCounts {
+                orphans: 1,
+                missing_children: 2,
+                ..ProblemCounts::default()
+            },
+        ));
+    }
+
+    // The oldest report falls out of the window.
+    assert_eq!(aggregator.len(), 4);
+    assert_eq!(aggregator.percentile(Metric::MergeTime, 50.0), Some(2));
+    assert_eq!(
+        aggregator.percentiles(Metric::TotalTime),
+        Some(Percentiles {
+            count: 4,
+            min: 11,
+            p50: 12,
+            p90: 14,
+            p99: 14,
+            max: 14,
+        })
+    );
+    assert_eq!(aggregator.percentile(Metric::LocalProblems, 90.0), Some(3));
+    assert_eq!(aggregator.percentile(Metric::ApplyTime, 50.0), None);
+
+    #[cfg(feature = "serde")]
+    {
+        let report = aggregator.reports().last().unwrap();
+        let json = serde_json::to_string(report).unwrap();
+        let deserialized: SyncReport = serde_json::from_str(&json).unwrap();
+        assert_eq!(&deserialized, report);
+    }
+
+    // A store records stats for each tree when it syncs. The trees have
+    // different numbers of deletions, so that mixing them up fails.
+    struct RecordReport(RefCell<SyncReport>);
+
+    impl Driver for RecordReport {
+        fn record_telemetry_event(&self, event: TelemetryEvent) {
+            self.0.borrow_mut().record(event);
+        }
+    }
+
+    struct ReportStore;
+
+    impl Store for ReportStore {
+        type Ok = ();
+        type Error = Error;
+
+        fn fetch_local_tree(&self) -> Result<Tree> {
+            let mut builder = Builder::try_from(nodes!({
+                ("menu________", Folder, { ("bookmarkAAAA", Bookmark) })
+            }))
+            .unwrap();
+            builder.deletion("bookmarkBBBB".into());
+            builder.into_tree()
+        }
+
+        fn fetch_remote_tree(&self) -> Result<Tree> {
+            let mut builder = Builder::try_from(nodes!({
+                ("menu________", Folder, { ("bookmarkAAAA", Bookmark) })
+            }))
+            .unwrap();
+            builder
+                .deletion("bookmarkCCCC".into())
+                .deletion("bookmarkDDDD".into())
+                .deletion("bookmarkEEEE".into());
+            builder.into_tree()
+        }
+
+        fn apply(&mut self, root: MergedRoot<'_>) -> Result<()> {
+            root.completion_ops_with_signal(&DefaultAbortSignal)?;
+            Ok(())
+        }
+    }
+
+    let driver = RecordReport(RefCell::new(SyncReport::new()));
+    ReportStore
+        .merge_with_driver(&driver, &DefaultAbortSignal)
+        .unwrap();
+
+    let report = driver.0.into_inner();
+    assert_eq!(report.local_tree.map(|stats| stats.deletions), Some(1));
+    assert_eq!(report.remote_tree.map(|stats| stats.deletions), Some(3));
+    assert!(report.apply_time.is_some());
+
+    // Building completion ops in `apply` records a phase event with the
+    // driver that the store passed to the merger.
+    assert_eq!(
+        report
+            .merge_phase(MergePhase::CompletionOps)
+            .map(|stats| stats.nodes),
+        Some(2)
+    );
+}
+
+#[test]
+fn merge_phase_telemetry() {
+    before_each();
+
+    struct RecordReport(RefCell<SyncReport>);
+
+    impl Driver for RecordReport {
+        fn record_telemetry_event(&self, event: TelemetryEvent) {
+            self.0.borrow_mut().record(event);
+        }
+    }
+
+    let local_tree = nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("bookmarkAAA1", Bookmark[needs_merge = true]),
+            ("bookmarkBBBB", Bookmark)
+        })
+    })
+    .into_tree()
+    .unwrap();
+    let mut remote_tree_builder = Builder::try_from(nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("bookmarkAAAA", Bookmark[needs_merge = true]),
+            ("bookmarkBBBB", Bookmark)
+        })
+    }))
+    .unwrap();
+    remote_tree_builder.deletion("bookmarkCCCC".into());
+    let remote_tree = remote_tree_builder.into_tree().unwrap();
+
+    let driver = RecordReport(RefCell::new(SyncReport::new()));
+    let merger = Merger::with_driver(&driver, &DefaultAbortSignal, &local_tree, &remote_tree);
+    let merged_root = merger.merge().unwrap();
+    merged_root.completion_ops();
+
+    let report = driver.0.borrow();
+    assert_eq!(
+        report
+            .merge_phases
+            .iter()
+            .map(|stats| (stats.phase, stats.nodes))
+            .collect::<Vec<_>>(),
+        vec![
+            (MergePhase::TwoWayMerge, 4),
+            (MergePhase::Dedupe, 4),
+            (MergePhase::UniqueKeywords, 5),
+            (MergePhase::Deletions, 1),
+            (MergePhase::Completeness, 9),
+            (MergePhase::CompletionOps, 4),
+        ]
+    );
+
+    // Indexing a tree for cross-folder dupes counts toward the dedupe phase.
+    struct CrossFolderReport(RefCell<SyncReport>);
+
+    impl Driver for CrossFolderReport {
+        fn cross_folder_dedupe(&self) -> CrossFolderDedupe {
+            CrossFolderDedupe::Merge
+        }
+
+        fn record_telemetry_event(&self, event: TelemetryEvent) {
+            self.0.borrow_mut().record(event);
+        }
+    }
+
+    let content = Content::Bookmark {
+        title: "A".into(),
+        url_href: "http://example.com/a".into(),
+        keyword: None,
+        description: None,
+    };
+    let mut local_tree_builder = Builder::try_from(nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("bookmarkAAA1", Bookmark[needs_merge = true])
+        }),
+        ("toolbar_____", Folder)
+    }))
+    .unwrap();
+    local_tree_builder
+        .mutate(&"bookmarkAAA1".into())
+        .content(content.clone());
+    let local_tree = local_tree_builder.into_tree().unwrap();
+    let mut remote_tree_builder = Builder::try_from(nodes!({
+        ("menu________", Folder),
+        ("toolbar_____", Folder[needs_merge = true], {
+            ("bookmarkAAAA", Bookmark[needs_merge = true])
+        })
+    }))
+    .unwrap();
+    remote_tree_builder
+        .mutate(&"bookmarkAAAA".into())
+        .content(content);
+    let remote_tree = remote_tree_builder.into_tree().unwrap();
+
+    let driver = CrossFolderReport(RefCell::new(SyncReport::new()));
+    let merger = Merger::with_driver(&driver, &DefaultAbortSignal, &local_tree, &remote_tree);
+    let merged_root = merger.merge().unwrap();
+    assert_eq!(merged_root.counts().cross_folder_dupes, 1);
+
+    // The merger looks for dupes of A in its own folder, then indexes the
+    // entire remote tree.
+    let report = driver.0.borrow();
+    assert_eq!(
+        report
+            .merge_phase(MergePhase::Dedupe)
+            .map(|stats| stats.nodes),
+        Some(1 + remote_tree.size())
+    );
+}
+
+#[test]
+fn progress() {
+    before_each();
+
+    struct RecordProgress(RefCell<Vec<Progress>>);
+
+    impl Driver for RecordProgress {
+        fn report_progress(&self, progress: Progress) {
+            self.0.borrow_mut().push(progress);
+        }
+    }
+
+    fn local_tree() -> Tree {
+        nodes!({
+            ("menu________", Folder[needs_merge = true], {
+                ("bookmarkAAAA", Bookmark[needs_merge = true]),
+                ("bookmarkBBBB", Bookmark)
+            })
+        })
+        .into_tree()
+        .unwrap()
+    }
+
+    fn remote_tree() -> Tree {
+        let mut remote_tree_builder = Builder::try_from(nodes!({
+            ("menu________", Folder[needs_merge = true], {
+                ("bookmarkCCCC", Bookmark[needs_merge = true])
+            })
+        }))
+        .unwrap();
+        remote_tree_builder.deletion("bookmarkBBBB".into());
+        remote_tree_builder.into_tree().unwrap()
+    }
+
+    // A store that builds completion ops in `apply`, like a real store.
+    struct ProgressStore;
+
+    impl Store for ProgressStore {
+        type Ok = usize;
+        type Error = Error;
+
+        fn fetch_local_tree(&self) -> Result<Tree> {
+            Ok(local_tree())
+        }
+
+        fn fetch_remote_tree(&self) -> Result<Tree> {
+            Ok(remote_tree())
+        }
+
+        fn apply(&mut self, root: MergedRoot<'_>) -> Result<usize> {
+            let ops = root.completion_ops_with_signal(&DefaultAbortSignal)?;
+            Ok(ops.upload_items.len())
+        }
+    }
+
+    let (local_tree, remote_tree) = (local_tree(), remote_tree());
+    let driver = RecordProgress(RefCell::new(Vec::new()));
+    let merger = Merger::with_driver(&driver, &DefaultAbortSignal, &local_tree, &remote_tree);
+    let merged_root = merger.merge().unwrap();
+    let merged_nodes = merged_root.counts().merged_nodes;
+
+    let merge_progress = driver.0.replace(Vec::new());
+    assert!(!merge_progress.is_empty());
+    assert!(merge_progress
+        .windows(2)
+        .all(|pair| pair[0].fraction() <= pair[1].fraction()));
+    assert_eq!(
+        merge_progress.last(),
+        Some(&Progress::Merge {
+            merged_nodes,
+            local_nodes: 4,
+            remote_nodes: 3,
+        })
+    );
+
+    merged_root.completion_ops();
+    let ops_progress = driver.0.replace(Vec::new());
+    assert_eq!(
+        ops_progress.last(),
+        Some(&Progress::CompletionOps {
+            visited_nodes: 5,
+            total_nodes: 5,
+        })
+    );
+    assert_eq!(ops_progress.last().map(Progress::fraction), Some(1.0));
+
+    // Syncing with the store reports progress for the merge and the
+    // completion ops to the same driver.
+    ProgressStore
+        .merge_with_driver(&driver, &DefaultAbortSignal)
+        .unwrap();
+    let sync_progress = driver.0.into_inner();
+    let first_ops_progress = sync_progress
+        .iter()
+        .position(|progress| matches!(progress, Progress::CompletionOps { .. }))
+        .expect("Should report progress for completion ops");
+    assert!(sync_progress[..first_ops_progress]
+        .iter()
+        .all(|progress| matches!(progress, Progress::Merge { .. })));
+    assert_eq!(&sync_progress[first_ops_progress..], &ops_progress[..]);
+}
+
+#[test]
+fn abort_signals() {
+    before_each();
+
+    let signal = AtomicAbortSignal::new();
+    let handle = signal.handle();
+    assert!(!signal.aborted());
+    thread::spawn(move || handle.abort()).join().unwrap();
+    assert!(signal.aborted());
+    assert!(signal.handle().is_aborted());
+
+    let expired = DeadlineSignal::new(Instant::now());
+    let budget = DeadlineSignal::after(Duration::from_secs(60));
+    assert!(expired.aborted());
+    assert!(!budget.aborted());
+
+    // A merge with a time budget that the user can also cancel.
+    let cancel = AtomicAbortSignal::new();
+    let any = AnySignal::new(&cancel, budget);
+    assert!(!any.aborted());
+    assert!(!AllSignal::new(&cancel, expired).aborted());
+    assert!(AnySignal::new(&cancel, expired).aborted());
+
+    let local_tree = nodes!({ ("menu________", Folder) }).into_tree().unwrap();
+    let remote_tree = nodes!({ ("menu________", Folder) }).into_tree().unwrap();
+    assert!(
+        Merger::with_driver(&DefaultDriver, &any, &local_tree, &remote_tree)
+            .merge()
+            .is_ok()
+    );
+
+    cancel.handle().abort();
+    assert!(AllSignal::new(&cancel, expired).aborted());
+    match Merger::with_driver(&DefaultDriver, &any, &local_tree, &remote_tree).merge() {
+        Err(err) => assert!(matches!(err.kind(), ErrorKind::Abort)),
+        Ok(_) => panic!("Merge should abort"),
+    }
+}
+
+#[test]
+fn resume_merge_from_checkpoint() {
+    before_each();
+
+    /// Aborts after a number of checks.
+    struct AbortAfter(Cell<usize>);
+
+    impl AbortSignal for AbortAfter {
+        fn aborted(&self) -> bool {
+            let remaining = self.0.get();
+            self.0.set(remaining.saturating_sub(1));
+            remaining == 0
+        }
+    }
+
+    let bookmark = || Content::Bookmark {
+        title: "A".into(),
+        url_href: "http://example.com/a".into(),
+        keyword: None,
+        description: None,
+    };
+
+    let mut local_tree_builder = Builder::try_from(nodes!({
+        ("menu________", Folder[needs_merge = true], {
+            ("bookmarkAAA1", Bookmark[needs_merge = true]),
+            ("folderBBBBBB", Folder[needs_merge = true], {
+                ("bookmarkCCCC", Bookmark[needs_merge = true])
+            })
         }),
-        ("unfiled_____", UnchangedWithNewLocalStructure, {
-            // K was flagged for reupload.
-           ("bookmarkKKKK", RemoteWithNewRemoteStructure)
+        ("toolbar_____", Folder, {
+            ("bookmarkDDDD", Bookmark)
+        })
+    }))
+    .unwrap();
+    local_tree_builder
+        .mutate(&"bookmarkAAA1".into())
+        .content(bookmark());
+    local_tree_builder.deletion("bookmarkEEEE".into());
+    let local_tree = local_tree_builder.into_tree().unwrap();
+
+    let mut remote_tree_builder = Builder::try_from(nodes!({
+        ("menu________", Folder[needs_merge = true, age = 5], {
+            ("bookmarkAAAA", Bookmark[needs_merge = true, age = 5]),
+            ("folderBBBBBB", Folder[needs_merge = true, age = 5], {
+                ("bookmarkFFFF", Bookmark[needs_merge = true, age = 5])
+            })
         }),
-        ("mobile______", UnchangedWithNewLocalStructure, {
-            // F is invalid locally, so replace with remote. This isn't
-            // possible in Firefox Desktop or Rust Places, where the local
-            // tree is always valid, but we handle it for symmetry.
-            ("bookmarkFFFF", Remote),
-            ("folderGGGGGG", Local)
+        ("toolbar_____", Folder[needs_merge = true, age = 5])
+    }))
+    .unwrap();
+    remote_tree_builder
+        .mutate(&"bookmarkAAAA".into())
+        .content(bookmark());
+    remote_tree_builder.deletion("bookmarkDDDD".into());
+    let remote_tree = remote_tree_builder.into_tree().unwrap();
+
+    let expected_root = Merger::new(&local_tree, &remote_tree).merge().unwrap();
+
+    // Abort each attempt a little later than the last, until the merge
+    // finishes.
+    let mut checkpoint = MergeCheckpoint::new();
+    let mut phases = Vec::new();
+    let mut attempts = 0;
+    let signal = AbortAfter(Cell::new(0));
+    let merged_root = loop {
+        signal.0.set(attempts);
+        attempts += 1;
+        let merger = Merger::with_driver(&DefaultDriver, &signal, &local_tree, &remote_tree);
+        match merger.merge_with_checkpoint(&mut checkpoint) {
+            Ok(merged_root) => break merged_root,
+            Err(err) => {
+                assert!(matches!(err.kind(), ErrorKind::Abort));
+                assert!(checkpoint.matches(&local_tree, &remote_tree));
+                phases.push(checkpoint.phase());
+            }
+        }
+    };
+    assert!(checkpoint.is_empty());
+    assert!(phases.contains(&None));
+    assert!(phases.contains(&Some(MergePhase::TwoWayMerge)));
+    assert!(phases.contains(&Some(MergePhase::Deletions)));
+
+    assert_eq!(
+        merged_root.node().to_ascii_string(),
+        expected_root.node().to_ascii_string()
+    );
+    assert_eq!(merged_root.counts(), expected_root.counts());
+    assert_eq!(
+        merged_root.deletions().collect::<BTreeSet<_>>(),
+        expected_root.deletions().collect::<BTreeSet<_>>()
+    );
+    assert_eq!(
+        merged_root.completion_ops().summarize(),
+        expected_root.completion_ops().summarize()
+    );
+
+    // A resumed attempt skips the phases that finished, and the dupes that
+    // the merger already matched. We count the nodes that each phase visits
+    // to check that.
+    struct RecordPhases(RefCell<Vec<(MergePhase, usize)>>);
+
+    impl Driver for RecordPhases {
+        fn record_telemetry_event(&self, event: TelemetryEvent) {
+            if let TelemetryEvent::MergePhase(stats) = event {
+                self.0.borrow_mut().push((stats.phase, stats.nodes));
+            }
+        }
+    }
+
+    let driver = RecordPhases(RefCell::new(Vec::new()));
+    Merger::with_driver(&driver, &DefaultAbortSignal, &local_tree, &remote_tree)
+        .merge_with_checkpoint(&mut MergeCheckpoint::new())
+        .unwrap();
+    let all_phases = driver.0.replace(Vec::new());
+    let dedupe_nodes = |phases: &[(MergePhase, usize)]| {
+        phases
+            .iter()
+            .find(|(phase, _)| *phase == MergePhase::Dedupe)
+            .map(|(_, nodes)| *nodes)
+    };
+
+    // Returns the checkpoint from the first attempt that aborts with the
+    // checkpoint in the given state.
+    let abort_until = |state: &dyn Fn(&MergeCheckpoint) -> bool| {
+        (0..)
+            .map(|checks| {
+                let mut checkpoint = MergeCheckpoint::new();
+                let signal = AbortAfter(Cell::new(checks));
+                let merger =
+                    Merger::with_driver(&DefaultDriver, &signal, &local_tree, &remote_tree);
+                assert!(merger.merge_with_checkpoint(&mut checkpoint).is_err());
+                checkpoint
+            })
+            .find(|checkpoint| state(checkpoint))
+            .unwrap()
+    };
+
+    // Aborting during the two-way merge saves the dupes that the merger
+    // matched, so the next attempt runs the two-way merge again, but doesn't
+    // rescan those folders.
+    let mut checkpoint =
+        abort_until(&|checkpoint| checkpoint.phase().is_none() && !checkpoint.dupes.is_empty());
+    Merger::with_driver(&driver, &DefaultAbortSignal, &local_tree, &remote_tree)
+        .merge_with_checkpoint(&mut checkpoint)
+        .unwrap();
+    let resumed_phases = driver.0.replace(Vec::new());
+    assert_eq!(
+        resumed_phases
+            .iter()
+            .map(|(phase, _)| *phase)
+            .collect::<Vec<_>>(),
+        all_phases
+            .iter()
+            .map(|(phase, _)| *phase)
+            .collect::<Vec<_>>()
+    );
+    assert!(dedupe_nodes(&resumed_phases) < dedupe_nodes(&all_phases));
+
+    // Aborting after the two-way merge saves the merged tree, so the next
+    // attempt doesn't run the two-way merge or dedupe at all.
+    let mut checkpoint = abort_until(&|checkpoint| checkpoint.phase().is_some());
+    Merger::with_driver(&driver, &DefaultAbortSignal, &local_tree, &remote_tree)
+        .merge_with_checkpoint(&mut checkpoint)
+        .unwrap();
+    let resumed_phases = driver.0.replace(Vec::new());
+    assert_eq!(
+        resumed_phases
+            .iter()
+            .map(|(phase, _)| *phase)
+            .collect::<Vec<_>>(),
+        &[
+            MergePhase::UniqueKeywords,
+            MergePhase::Deletions,
+            MergePhase::Completeness,
+        ]
+    );
+
+    // If a tree changes after an abort, the merger starts over.
+    signal.0.set(0);
+    let merger = Merger::with_driver(&DefaultDriver, &signal, &local_tree, &remote_tree);
+    assert!(merger.merge_with_checkpoint(&mut checkpoint).is_err());
+    assert!(!checkpoint.is_empty());
+
+    let new_remote_tree = nodes!({
+        ("menu________", Folder[needs_merge = true, age = 5], {
+            ("bookmarkGGGG", Bookmark[needs_merge = true, age = 5])
         })
-    });
-    let expected_deletions = &[
-        // C is invalid on both sides, so we need to upload a tombstone.
-        "bookmarkCCCC",
-        // E is invalid locally and deleted remotely, so doesn't need a
-        // tombstone.
-        "bookmarkEEEE",
-        // H is invalid locally and doesn't exist remotely, so doesn't need a
-        // tombstone.
-        "bookmarkHHHH",
-        // I is deleted locally and invalid remotely, so needs a tombstone.
-        "bookmarkIIII",
-        // J doesn't exist locally and invalid remotely, so needs a tombstone.
-        "bookmarkJJJJ",
-    ];
-    let expected_telem = StructureCounts {
-        merged_nodes: 10,
-        ..StructureCounts::default()
+    })
+    .into_tree()
+    .unwrap();
+    assert!(!checkpoint.matches(&local_tree, &new_remote_tree));
+    let merged_root = Merger::new(&local_tree, &new_remote_tree)
+        .merge_with_checkpoint(&mut checkpoint)
+        .unwrap();
+    let expected_root = Merger::new(&local_tree, &new_remote_tree).merge().unwrap();
+    assert_eq!(
+        merged_root.node().to_ascii_string(),
+        expected_root.node().to_ascii_string()
+    );
+    assert!(checkpoint.is_empty());
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn tracing_spans() {
+    use std::{
+        fmt,
+        sync::{Arc, Mutex},
+    };
+    use tracing::{
+        field::{Field, Visit},
+        span, Event, Metadata, Subscriber,
     };
 
-    assert_eq!(&expected_tree, merged_root.node());
+    before_each();
 
-    let mut deletions = merged_root.deletions().collect::<Vec<_>>();
-    deletions.sort();
-    assert_eq!(deletions, expected_deletions);
+    /// Records span names, and messages and GUIDs for events.
+    #[derive(Default)]
+    struct Recorder {
+        spans: Mutex<Vec<&'static str>>,
+        events: Mutex<Vec<(String, String)>>,
+    }
+
+    #[derive(Default)]
+    struct EventVisitor {
+        message: String,
+        guid: String,
+    }
+
+    impl Visit for EventVisitor {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            match field.name() {
+                "message" => self.message = format!("{:?}", value),
+                "guid" => self.guid = format!("{:?}", value),
+                _ => {}
+            }
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, attrs: &span::Attributes<'_>) -> span::Id {
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(attrs.metadata().name());
+            span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut visitor = EventVisitor::default();
+            event.record(&mut visitor);
+            self.events
+                .lock()
+                .unwrap()
+                .push((visitor.message, visitor.guid));
+        }
+
+        fn enter(&self, _: &span::Id) {}
+
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    struct TracingStore;
+
+    impl Store for TracingStore {
+        type Ok = ();
+        type Error = Error;
+
+        fn fetch_local_tree(&self) -> Result<Tree> {
+            nodes!({
+                ("menu________", Folder[needs_merge = true], {
+                    ("bookmarkAAAA", Bookmark[needs_merge = true])
+                })
+            })
+            .into_tree()
+        }
+
+        fn fetch_remote_tree(&self) -> Result<Tree> {
+            let mut builder = Builder::try_from(nodes!({ ("menu________", Folder) })).unwrap();
+            builder.deletion("bookmarkBBBB".into());
+            builder.into_tree()
+        }
+
+        fn apply(&mut self, _: MergedRoot<'_>) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    let recorder = Arc::new(Recorder::default());
+    tracing::subscriber::with_default(recorder.clone(), || TracingStore.merge().unwrap());
 
-    let ops = merged_root.completion_ops();
-    let mut summary = ops.summarize();
-    summary.sort();
     assert_eq!(
-        summary,
+        *recorder.spans.lock().unwrap(),
         &[
-            "Apply remote bookmarkFFFF",
-            "Apply remote bookmarkKKKK",
-            "Delete local item bookmarkCCCC",
-            "Delete local item bookmarkEEEE",
-            "Delete local item bookmarkHHHH",
-            "Flag local bookmarkAAAA as unmerged",
-            "Flag local bookmarkKKKK as unmerged",
-            "Flag local folderBBBBBB as unmerged",
-            "Flag local folderGGGGGG as unmerged",
-            "Flag local toolbar_____ as unmerged",
-            "Flag remote bookmarkEEEE as merged",
-            "Insert local tombstone bookmarkCCCC",
-            "Insert local tombstone bookmarkJJJJ",
-            "Move bookmarkKKKK into unfiled_____ at 0",
-            "Upload item bookmarkAAAA",
-            "Upload item bookmarkKKKK",
-            "Upload item folderBBBBBB",
-            "Upload item folderGGGGGG",
-            "Upload item toolbar_____",
-            "Upload tombstone bookmarkCCCC",
-            "Upload tombstone bookmarkIIII",
-            "Upload tombstone bookmarkJJJJ",
+            "dogear.sync",
+            "dogear.fetch_local_tree",
+            "dogear.fetch_remote_tree",
+            "dogear.merge",
+            "dogear.apply",
         ]
     );
+    let events = recorder.events.lock().unwrap();
+    assert!(events.contains(&("Merged local-only node".into(), "bookmarkAAAA".into())));
+    assert!(events.contains(&("Merged node".into(), "menu________".into())));
+    assert!(events.contains(&("Deleting item locally".into(), "bookmarkBBBB".into())));
+}
 
-    assert_eq!(merged_root.counts(), &expected_telem);
+#[test]
+fn validation_report() {
+    before_each();
+
+    let mut builder = Builder::try_from(nodes!({
+        ("menu________", Folder, {
+            ("folderAAAAAA", Folder, {
+                ("bookmarkBBBB", Bookmark)
+            })
+        }),
+        ("toolbar_____", Folder, {
+            ("bookmarkCCCC", Bookmark[validity = Validity::Replace])
+        })
+    }))
+    .unwrap();
+    // D and E have nonexistent parents.
+    builder
+        .item(Item::new("bookmarkDDDD".into(), Kind::Bookmark))
+        .and_then(|p| p.by_parent_guid("nonexistent".into()))
+        .unwrap();
+    builder
+        .item(Item::new("bookmarkEEEE".into(), Kind::Bookmark))
+        .and_then(|p| p.by_parent_guid("nonexistent".into()))
+        .unwrap();
+    // F is in the toolbar's children, but its parent is A.
+    builder
+        .item(Item::new("bookmarkFFFF".into(), Kind::Bookmark))
+        .and_then(|p| p.by_parent_guid("folderAAAAAA".into()))
+        .and_then(|b| {
+            b.parent_for(&"bookmarkFFFF".into())
+                .by_children(&"toolbar_____".into())
+        })
+        .unwrap();
+    // A mentions a nonexistent child G, and a deleted child H.
+    builder
+        .parent_for(&"bookmarkGGGG".into())
+        .by_children(&"folderAAAAAA".into())
+        .unwrap();
+    builder
+        .parent_for(&"bookmarkHHHH".into())
+        .by_children(&"folderAAAAAA".into())
+        .unwrap();
+    builder.deletion("bookmarkHHHH".into());
+    let tree = builder.into_tree().unwrap();
+
+    let validator = Validator::new(1, "salt");
+    let report = validator.validate(&tree);
+    #[cfg(feature = "hmac-sha256")]
+    let hash = |guid: &str| validator.hash_guid(&guid.into());
+    #[cfg(feature = "hmac-sha256")]
+    let hashes = |guid: &str| vec![hash(guid)];
+    #[cfg(not(feature = "hmac-sha256"))]
+    let hashes = |_: &str| Vec::<String>::new();
+
+    assert_eq!(report.counts, tree.problems().counts());
+    assert_eq!(report.counts.missing_parent_guids, 2);
+    assert_eq!(
+        report.samples(ProblemKind::MissingParentGuid),
+        &[ProblemSample {
+            guid: "bookmarkDDDD".into(),
+            parent_hashes: hashes("nonexistent"),
+        }]
+    );
+    assert_eq!(
+        report.samples(ProblemKind::ParentChildDisagreement),
+        &[ProblemSample {
+            guid: "bookmarkDDDD".into(),
+            parent_hashes: hashes("nonexistent"),
+        }]
+    );
+    assert_eq!(
+        report.samples(ProblemKind::MissingChild),
+        &[ProblemSample {
+            guid: "bookmarkGGGG".into(),
+            parent_hashes: hashes("folderAAAAAA"),
+        }]
+    );
+    assert_eq!(
+        report.samples(ProblemKind::DeletedChild),
+        &[ProblemSample {
+            guid: "bookmarkHHHH".into(),
+            parent_hashes: hashes("folderAAAAAA"),
+        }]
+    );
+    assert_eq!(
+        report.samples(ProblemKind::InvalidItem),
+        &[ProblemSample {
+            guid: "bookmarkCCCC".into(),
+            parent_hashes: hashes("toolbar_____"),
+        }]
+    );
+    assert!(report.samples(ProblemKind::Orphan).is_empty());
+
+    // Parent GUIDs are hashed with HMAC-SHA256, so different keys hash the
+    // same GUID differently.
+    #[cfg(feature = "hmac-sha256")]
+    {
+        assert_eq!(
+            hash("folderAAAAAA"),
+            "e71f7871167cacfb9b8663a33e95fd77b60ae9484bf745513f8418c9b1d3d044"
+        );
+        assert_ne!(
+            hash("folderAAAAAA"),
+            Validator::new(1, "pepper").hash_guid(&"folderAAAAAA".into())
+        );
+    }
+
+    // With more samples, we keep all items with the problem.
+    let report = Validator::new(5, "salt").validate(&tree);
+    assert_eq!(
+        report
+            .samples(ProblemKind::MissingParentGuid)
+            .iter()
+            .map(|sample| sample.guid.as_str())
+            .collect::<Vec<_>>(),
+        &["bookmarkDDDD", "bookmarkEEEE"]
+    );
+    assert_eq!(
+        report
+            .samples(ProblemKind::ParentChildDisagreement)
+            .iter()
+            .map(|sample| sample.guid.as_str())
+            .collect::<Vec<_>>(),
+        &["bookmarkDDDD", "bookmarkEEEE", "bookmarkFFFF"]
+    );
 }
 
 #[test]
-fn completion_ops() {
+fn compare_trees() {
+    before_each();
+
     let mut local_tree_builder = Builder::try_from(nodes!({
         ("menu________", Folder, {
             ("bookmarkAAAA", Bookmark),
             ("bookmarkBBBB", Bookmark),
-            ("bookmarkCCCC", Bookmark),
-            ("bookmarkDDDD", Bookmark)
+            ("folderCCCCCC", Folder, {
+                ("bookmarkDDDD", Bookmark)
+            })
         }),
         ("toolbar_____", Folder, {
-            ("bookmarkEEEE", Bookmark)
+            ("bookmarkEEEE", Bookmark),
+            ("bookmarkFFFF", Bookmark),
+            ("bookmarkGGGG", Bookmark[needs_merge = true])
         }),
-        ("unfiled_____", Folder[needs_merge = true], {
+        ("unfiled_____", Folder, {
+            ("bookmarkHHHH", Bookmark),
             ("bookmarkIIII", Bookmark)
-        }),
-        ("mobile______", Folder, {
-            ("bookmarkFFFF", Bookmark[needs_merge = true, age = 10])
         })
     }))
     .unwrap();
+    local_tree_builder
+        .mutate(&"bookmarkAAAA".into())
+        .content(Content::Bookmark {
+            title: "A".into(),
+            url_href: "http://example.com/a".into(),
+            keyword: None,
+            description: None,
+        });
     local_tree_builder.deletion("bookmarkJJJJ".into());
     let local_tree = local_tree_builder.into_tree().unwrap();
 
     let mut remote_tree_builder = Builder::try_from(nodes!({
-        ("menu________", Folder[needs_merge = true], {
+        ("menu________", Folder, {
             ("bookmarkAAAA", Bookmark),
-            ("bookmarkDDDD", Bookmark),
-            ("bookmarkCCCC", Bookmark),
-            ("bookmarkBBBB", Bookmark),
-            ("bookmarkEEEE", Bookmark[needs_merge = true])
-        }),
-        ("toolbar_____", Folder[needs_merge = true], {
-            ("bookmarkGGGG", Bookmark[needs_merge = true])
+            ("folderCCCCCC", Folder, {
+                ("bookmarkDDDD", Bookmark),
+                ("bookmarkBBBB", Bookmark)
+            })
         }),
-        ("unfiled_____", Folder[needs_merge = true], {
-            ("bookmarkHHHH", Bookmark[needs_merge = true]),
-            ("bookmarkJJJJ", Bookmark)
+        ("toolbar_____", Folder, {
+            ("bookmarkFFFF", Bookmark),
+            ("bookmarkEEEE", Folder)
         }),
-        ("mobile______", Folder, {
-            ("bookmarkFFFF", Bookmark[needs_merge = true, age = 5])
+        ("unfiled_____", Folder, {
+            ("bookmarkJJJJ", Bookmark),
+            ("bookmarkKKKK", Bookmark)
         })
     }))
     .unwrap();
+    remote_tree_builder
+        .mutate(&"bookmarkAAAA".into())
+        .content(Content::Bookmark {
+            title: "A (renamed)".into(),
+            url_href: "http://example.com/a".into(),
+            keyword: None,
+            description: None,
+        });
     remote_tree_builder.deletion("bookmarkIIII".into());
     let remote_tree = remote_tree_builder.into_tree().unwrap();
 
-    let merger = Merger::new(&local_tree, &remote_tree);
-    let merged_root = merger.merge().unwrap();
+    let report = ComparisonReport::new(&local_tree, &remote_tree);
+    assert_eq!(
+        report,
+        ComparisonReport {
+            // G is new locally, so it's expected to be missing. I is deleted
+            // remotely, and J locally, but those deletions haven't synced
+            // yet, so they're expected, too.
+            missing_from_server: vec!["bookmarkHHHH".into()],
+            missing_from_client: vec!["bookmarkKKKK".into()],
+            kind_mismatches: vec!["bookmarkEEEE".into()],
+            parent_mismatches: vec!["bookmarkBBBB".into()],
+            structure_mismatches: vec![
+                "folderCCCCCC".into(),
+                "menu________".into(),
+                "toolbar_____".into(),
+                "unfiled_____".into(),
+            ],
+            content_mismatches: vec!["bookmarkAAAA".into()],
+        }
+    );
+    assert!(!report.is_empty());
 
-    let expected_tree = merged_nodes!({
-        ("menu________", UnchangedWithNewLocalStructure, {
-            ("bookmarkAAAA", Unchanged),
-            ("bookmarkDDDD", Unchanged),
-            ("bookmarkCCCC", Unchanged),
-            ("bookmarkBBBB", Unchanged),
-            ("bookmarkEEEE", Remote)
-        }),
-        ("toolbar_____", UnchangedWithNewLocalStructure, {
-            ("bookmarkGGGG", Remote)
+    assert!(ComparisonReport::new(&local_tree, &local_tree).is_empty());
+}
+
+#[test]
+fn repair_tree() {
+    before_each();
+
+    let mut builder = Builder::try_from(nodes!({
+        ("menu________", Folder, {
+            ("folderAAAAAA", Folder, {
+                ("bookmarkBBBB", Bookmark)
+            }),
+            ("bookmarkCCCC", Bookmark[validity = Validity::Reupload])
         }),
-        ("unfiled_____", LocalWithNewLocalStructure, {
-           ("bookmarkHHHH", Remote)
+        ("toolbar_____", Folder, {
+            ("bookmarkDDDD", Bookmark[validity = Validity::Replace]),
+            ("livemarkEEEE", Livemark)
         }),
-        ("mobile______", Unchanged, {
-            ("bookmarkFFFF", Remote)
+        ("unfiled_____", Folder)
+    }))
+    .unwrap();
+    // F has a nonexistent parent.
+    builder
+        .item(Item::new("bookmarkFFFF".into(), Kind::Bookmark))
+        .and_then(|p| p.by_parent_guid("nonexistent".into()))
+        .unwrap();
+    // G is in the toolbar's children, but its parent is A.
+    builder
+        .item(Item::new("bookmarkGGGG".into(), Kind::Bookmark))
+        .and_then(|p| p.by_parent_guid("folderAAAAAA".into()))
+        .and_then(|b| {
+            b.parent_for(&"bookmarkGGGG".into())
+                .by_children(&"toolbar_____".into())
         })
-    });
-
-    assert_eq!(&expected_tree, merged_root.node());
+        .unwrap();
+    // A mentions a nonexistent child H.
+    builder
+        .parent_for(&"bookmarkHHHH".into())
+        .by_children(&"folderAAAAAA".into())
+        .unwrap();
+    // I doesn't have a parent at all.
+    builder
+        .item(Item::new("bookmarkIIII".into(), Kind::Bookmark))
+        .unwrap();
+    let tree = builder.into_tree().unwrap();
 
-    let ops = merged_root.completion_ops();
-    assert!(ops.change_guids.is_empty());
+    let ops = RepairOps::new(&tree);
+    let items = ops
+        .upload_items
+        .iter()
+        .map(|item| {
+            (
+                item.node.guid.as_str(),
+                item.reasons.iter().copied().collect(),
+            )
+        })
+        .collect::<Vec<(&str, Vec<RepairReason>)>>();
     assert_eq!(
-        to_strings(&ops.apply_remote_items).collect::<Vec<_>>(),
+        items,
         &[
-            "Apply remote bookmarkEEEE",
-            "Apply remote bookmarkGGGG",
-            "Apply remote bookmarkHHHH",
-            "Apply remote bookmarkFFFF",
+            // A's children mention H, which doesn't exist.
+            ("folderAAAAAA", vec![RepairReason::Children]),
+            ("bookmarkCCCC", vec![RepairReason::Reupload]),
+            // The toolbar's children include G, and the livemark E.
+            ("toolbar_____", vec![RepairReason::Children]),
+            ("bookmarkGGGG", vec![RepairReason::DivergedParent]),
+            // F and I were moved to unfiled.
+            ("unfiled_____", vec![RepairReason::Children]),
+            ("bookmarkFFFF", vec![RepairReason::DivergedParent]),
+            ("bookmarkIIII", vec![RepairReason::Orphan]),
         ]
     );
     assert_eq!(
-        to_strings(&ops.apply_new_local_structure).collect::<Vec<_>>(),
+        ops.upload_tombstones
+            .iter()
+            .map(|tombstone| tombstone.guid().as_str())
+            .collect::<Vec<_>>(),
+        &["livemarkEEEE"]
+    );
+    assert_eq!(
+        ops.summarize(),
         &[
-            "Move bookmarkDDDD into menu________ at 1",
-            "Move bookmarkBBBB into menu________ at 3",
-            "Move bookmarkEEEE into menu________ at 4",
-            "Move bookmarkGGGG into toolbar_____ at 0",
-            "Move bookmarkHHHH into unfiled_____ at 0",
+            "Upload item folderAAAAAA (Children)",
+            "Upload item bookmarkCCCC (Reupload)",
+            "Upload item toolbar_____ (Children)",
+            "Upload item bookmarkGGGG (DivergedParent)",
+            "Upload item unfiled_____ (Children)",
+            "Upload item bookmarkFFFF (DivergedParent)",
+            "Upload item bookmarkIIII (Orphan)",
+            "Upload tombstone livemarkEEEE",
         ]
     );
-    assert!(ops.set_local_unmerged.is_empty());
+
+    let valid_tree = nodes!({ ("menu________", Folder, { ("bookmarkAAAA", Bookmark) }) })
+        .into_tree()
+        .unwrap();
+    assert!(RepairOps::new(&valid_tree).is_empty());
+}
+
+#[test]
+fn mutate_tree() {
+    before_each();
+
+    let mut builder = Builder::try_from(nodes!({
+        ("menu________", Folder, {
+            ("folderAAAAAA", Folder, {
+                ("bookmarkBBBB", Bookmark),
+                ("folderCCCCCC", Folder, {
+                    ("bookmarkDDDD", Bookmark)
+                })
+            }),
+            ("bookmarkEEEE", Bookmark)
+        }),
+        ("toolbar_____", Folder, {
+            ("bookmarkFFFF", Bookmark),
+            ("separatorGGG", Separator)
+        }),
+        ("unfiled_____", Folder)
+    }))
+    .unwrap();
+    builder
+        .mutate(&"bookmarkEEEE".into())
+        .content(Content::Bookmark {
+            title: "E".into(),
+            url_href: "http://example.com/e".into(),
+            keyword: None,
+            description: None,
+        });
+    // H doesn't have a parent.
+    builder
+        .item(Item::new("bookmarkHHHH".into(), Kind::Bookmark))
+        .unwrap();
+    let mut tree = builder.into_tree().unwrap();
+
+    tree.insert(
+        &"toolbar_____".into(),
+        1,
+        Item::new("bookmarkIIII".into(), Kind::Bookmark),
+        None,
+    )
+    .unwrap();
+    tree.move_item(&"bookmarkHHHH".into(), &"folderAAAAAA".into(), 0)
+        .unwrap();
+    tree.reorder(
+        &"toolbar_____".into(),
+        &[
+            "separatorGGG".into(),
+            "bookmarkIIII".into(),
+            "bookmarkFFFF".into(),
+        ],
+    )
+    .unwrap();
+    tree.retitle(&"bookmarkEEEE".into(), "E (renamed)".into())
+        .unwrap();
+    tree.delete(&"folderCCCCCC".into()).unwrap();
+
+    let expected_tree = nodes!({
+        ("menu________", Folder, {
+            ("folderAAAAAA", Folder[needs_merge = true], {
+                ("bookmarkHHHH", Bookmark[needs_merge = true]),
+                ("bookmarkBBBB", Bookmark)
+            }),
+            ("bookmarkEEEE", Bookmark[needs_merge = true])
+        }),
+        ("toolbar_____", Folder[needs_merge = true], {
+            ("separatorGGG", Separator),
+            ("bookmarkIIII", Bookmark[needs_merge = true]),
+            ("bookmarkFFFF", Bookmark)
+        }),
+        ("unfiled_____", Folder[needs_merge = true])
+    })
+    .into_tree()
+    .unwrap();
+    // H and unfiled are still diverged, so that the merger reuploads them.
+    assert!(tree
+        .node_for_guid(&"bookmarkHHHH".into())
+        .unwrap()
+        .diverged());
+    assert!(tree
+        .node_for_guid(&"unfiled_____".into())
+        .unwrap()
+        .diverged());
     assert_eq!(
-        to_strings(&ops.set_local_merged).collect::<Vec<_>>(),
-        &["Flag local bookmarkFFFF as merged"]
+        tree.root().to_ascii_string().replace("❗️", ""),
+        expected_tree.root().to_ascii_string()
     );
+    assert_eq!(tree.size(), expected_tree.size());
+    for guid in expected_tree.guids() {
+        let node = tree.node_for_guid(guid).unwrap();
+        assert_eq!(&node.guid, guid);
+        if let Some(parent) = node.parent() {
+            assert!(parent.children().any(|child| &child.guid == guid));
+        }
+        for child in node.children() {
+            assert_eq!(&child.parent().unwrap().guid, guid);
+        }
+    }
     assert_eq!(
-        to_strings(&ops.set_remote_merged).collect::<Vec<_>>(),
-        &[
-            "Flag remote menu________ as merged",
-            "Flag remote bookmarkEEEE as merged",
-            "Flag remote toolbar_____ as merged",
-            "Flag remote bookmarkGGGG as merged",
-            "Flag remote bookmarkHHHH as merged",
-            "Flag remote bookmarkFFFF as merged",
-            "Flag remote bookmarkIIII as merged",
-        ]
+        tree.deletions().iter().cloned().collect::<BTreeSet<_>>(),
+        ["folderCCCCCC".into(), "bookmarkDDDD".into()]
+            .iter()
+            .cloned()
+            .collect::<BTreeSet<Guid>>()
     );
-    let mut delete_local_items = to_strings(&ops.delete_local_items).collect::<Vec<_>>();
-    delete_local_items.sort();
-    assert_eq!(delete_local_items, &["Delete local item bookmarkIIII"]);
-    assert!(ops.insert_local_tombstones.is_empty());
     assert_eq!(
-        to_strings(&ops.upload_items).collect::<Vec<_>>(),
-        &["Upload item unfiled_____"]
+        tree.node_for_guid(&"bookmarkEEEE".into())
+            .and_then(|node| node.content())
+            .and_then(|content| content.title()),
+        Some("E (renamed)")
     );
-    let mut upload_tombstones = to_strings(&ops.upload_tombstones).collect::<Vec<_>>();
-    upload_tombstones.sort();
-    assert_eq!(upload_tombstones, &["Upload tombstone bookmarkJJJJ"]);
-}
-
-#[test]
-fn problems() {
-    let mut problems = Problems::default();
+    // H is no longer an orphan.
+    assert!(tree.problems().is_empty());
+
+    // Inserting a deleted item revives it.
+    tree.insert(
+        &"unfiled_____".into(),
+        0,
+        Item::new("bookmarkDDDD".into(), Kind::Bookmark),
+        None,
+    )
+    .unwrap();
+    assert!(!tree.is_deleted(&"bookmarkDDDD".into()));
 
-    problems
-        .note(&"bookmarkAAAA".into(), Problem::Orphan)
-        .note(
+    let err = tree
+        .insert(
             &"menu________".into(),
-            Problem::MisparentedRoot(vec![DivergedParent::ByChildren("unfiled_____".into())]),
+            0,
+            Item::new("bookmarkBBBB".into(), Kind::Bookmark),
+            None,
         )
-        .note(&"toolbar_____".into(), Problem::MisparentedRoot(Vec::new()))
-        .note(
+        .unwrap_err();
+    assert!(matches!(err.kind(), ErrorKind::DuplicateItem(_)));
+    let err = tree
+        .insert(
             &"bookmarkBBBB".into(),
-            Problem::DivergedParents(vec![
-                DivergedParent::ByChildren("folderCCCCCC".into()),
-                DivergedParentGuid::Folder("folderDDDDDD".into()).into(),
-            ]),
-        )
-        .note(
-            &"bookmarkEEEE".into(),
-            Problem::DivergedParents(vec![
-                DivergedParent::ByChildren("folderFFFFFF".into()),
-                DivergedParentGuid::NonFolder("bookmarkGGGG".into()).into(),
-            ]),
-        )
-        .note(&"bookmarkRRRR".into(), Problem::InvalidItem)
-        .note(
-            &"bookmarkHHHH".into(),
-            Problem::DivergedParents(vec![
-                DivergedParent::ByChildren("folderIIIIII".into()),
-                DivergedParent::ByChildren("folderJJJJJJ".into()),
-                DivergedParentGuid::Missing("folderKKKKKK".into()).into(),
-            ]),
-        )
-        .note(&"bookmarkLLLL".into(), Problem::DivergedParents(Vec::new()))
-        .note(
-            &"folderMMMMMM".into(),
-            Problem::MissingChild {
-                child_guid: "bookmarkNNNN".into(),
-            },
-        )
-        .note(
-            &"folderMMMMMM".into(),
-            Problem::MissingChild {
-                child_guid: "bookmarkOOOO".into(),
-            },
-        )
-        .note(
-            &"bookmarkPPPP".into(),
-            Problem::DivergedParents(vec![
-                DivergedParentGuid::Deleted("folderQQQQQQ".into()).into()
-            ]),
+            0,
+            Item::new("bookmarkJJJJ".into(), Kind::Bookmark),
+            None,
         )
-        .note(&"bookmarkQQQQ".into(), Problem::InvalidItem);
+        .unwrap_err();
+    assert!(matches!(err.kind(), ErrorKind::InvalidParent(_, _)));
+    let err = tree
+        .move_item(&"menu________".into(), &"toolbar_____".into(), 0)
+        .unwrap_err();
+    assert!(matches!(err.kind(), ErrorKind::ImmutableRoot(_)));
+    let err = tree
+        .move_item(&"folderAAAAAA".into(), &"folderAAAAAA".into(), 0)
+        .unwrap_err();
+    assert!(matches!(err.kind(), ErrorKind::Cycle(_)));
+    let err = tree
+        .reorder(&"toolbar_____".into(), &["bookmarkFFFF".into()])
+        .unwrap_err();
+    assert!(matches!(err.kind(), ErrorKind::MismatchedChildren(_)));
+    let err = tree
+        .retitle(&"separatorGGG".into(), "G".into())
+        .unwrap_err();
+    assert!(matches!(err.kind(), ErrorKind::UntitledItem(_)));
+    for guid in &["root________", "menu________"] {
+        let err = tree.retitle(&(*guid).into(), "Root".into()).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::ImmutableRoot(_)));
+    }
+    let err = tree.delete(&"bookmarkJJJJ".into()).unwrap_err();
+    assert!(matches!(err.kind(), ErrorKind::MissingItem(_)));
 
-    let mut summary = problems.summarize().collect::<Vec<_>>();
-    summary.sort_by(|a, b| a.guid().cmp(b.guid()));
+    // Mutations clear problems that no longer apply.
+    let mut builder = Builder::try_from(nodes!({
+        ("menu________", Folder, {
+            ("folderAAAAAA", Folder)
+        }),
+        ("toolbar_____", Folder, {
+            ("bookmarkBBBB", Bookmark)
+        })
+    }))
+    .unwrap();
+    // A mentions a nonexistent child C, and a deleted child D.
+    builder
+        .parent_for(&"bookmarkCCCC".into())
+        .by_children(&"folderAAAAAA".into())
+        .unwrap();
+    builder
+        .parent_for(&"bookmarkDDDD".into())
+        .by_children(&"folderAAAAAA".into())
+        .unwrap();
+    builder.deletion("bookmarkDDDD".into());
+    // B is in the toolbar, but A mentions it, too.
+    builder
+        .parent_for(&"bookmarkBBBB".into())
+        .by_children(&"folderAAAAAA".into())
+        .unwrap();
+    let mut tree = builder.into_tree().unwrap();
+    let mut summary =
+        to_strings(&tree.problems().summarize().collect::<Vec<_>>()).collect::<Vec<_>>();
+    summary.sort();
     assert_eq!(
-        to_strings(&summary).collect::<Vec<_>>(),
+        summary,
         &[
-            "bookmarkAAAA is an orphan",
-            "bookmarkBBBB is in children of folderCCCCCC and has parent folderDDDDDD",
-            "bookmarkEEEE is in children of folderFFFFFF and has non-folder parent bookmarkGGGG",
-            "bookmarkHHHH is in children of folderIIIIII, is in children of folderJJJJJJ, and has \
-             nonexistent parent folderKKKKKK",
-            "bookmarkLLLL has diverged parents",
-            "bookmarkPPPP has deleted parent folderQQQQQQ",
-            "bookmarkQQQQ is invalid",
-            "bookmarkRRRR is invalid",
-            "folderMMMMMM has nonexistent child bookmarkNNNN",
-            "folderMMMMMM has nonexistent child bookmarkOOOO",
-            "menu________ is a user content root, but is in children of unfiled_____",
-            "toolbar_____ is a user content root",
+            "bookmarkBBBB is in children of toolbar_____, has parent toolbar_____, and is in children of \
+             folderAAAAAA",
+            "folderAAAAAA has deleted child bookmarkDDDD",
+            "folderAAAAAA has nonexistent child bookmarkCCCC",
         ]
     );
 
+    // Inserting C and D means that they're no longer missing or deleted.
+    tree.insert(
+        &"menu________".into(),
+        1,
+        Item::new("bookmarkCCCC".into(), Kind::Bookmark),
+        None,
+    )
+    .unwrap();
+    tree.insert(
+        &"menu________".into(),
+        2,
+        Item::new("bookmarkDDDD".into(), Kind::Bookmark),
+        None,
+    )
+    .unwrap();
+    let summary = to_strings(&tree.problems().summarize().collect::<Vec<_>>()).collect::<Vec<_>>();
     assert_eq!(
-        problems.counts(),
-        ProblemCounts {
-            orphans: 1,
-            misparented_roots: 2,
-            multiple_parents_by_children: 3,
-            deleted_parent_guids: 1,
-            missing_parent_guids: 1,
-            non_folder_parent_guids: 1,
-            parent_child_disagreements: 7,
-            deleted_children: 0,
-            missing_children: 2,
-            invalid_items: 2,
-        }
+        summary,
+        &["bookmarkBBBB is in children of toolbar_____, has parent toolbar_____, and is in children of \
+           folderAAAAAA"]
     );
+
+    // Deleting A means that it no longer mentions B, so B's parents agree.
+    tree.delete(&"folderAAAAAA".into()).unwrap();
+    assert!(tree.problems().is_empty());
+    assert_eq!(tree.problems().counts(), ProblemCounts::default());
 }
@@ -15,17 +15,22 @@
 use std::{
     borrow::Cow,
     cmp::Ordering,
-    collections::{HashMap, HashSet},
+    collections::{BTreeSet, HashMap, HashSet},
     convert::{TryFrom, TryInto},
-    fmt, mem,
+    fmt,
+    hash::{Hash, Hasher},
+    mem,
     ops::Deref,
     ptr,
 };
 
 use smallbitvec::SmallBitVec;
 
+use crate::checkpoint::FingerprintHasher;
+use crate::clock::VersionVector;
 use crate::error::{Error, ErrorKind, Result};
-use crate::guid::Guid;
+use crate::guid::{Guid, GuidIndex};
+use crate::roots::Roots;
 
 /// The type for entry indices in the tree.
 type Index = usize;
@@ -43,11 +48,19 @@ pub struct Tree {
     entries: Vec<TreeEntry>,
     deleted_guids: HashSet<Guid>,
     problems: Problems,
+    roots: Roots,
+    clock_offset: i64,
 }
 
 impl Tree {
-    /// Returns a builder for a rooted tree.
+    /// Returns a builder for a rooted tree, with the Places built-in roots.
+    #[inline]
     pub fn with_root(root: Item) -> Builder {
+        Tree::with_roots(root, Roots::default())
+    }
+
+    /// Returns a builder for a rooted tree, with the given built-in roots.
+    pub fn with_roots(root: Item, roots: Roots) -> Builder {
         let mut entry_index_by_guid = HashMap::new();
         entry_index_by_guid.insert(root.guid.clone(), 0);
 
@@ -61,9 +74,25 @@ impl Tree {
             deleted_guids: HashSet::new(),
             entry_index_by_guid,
             reparent_orphans_to: None,
+            migrate_tag_folders: false,
+            roots,
+            clock_offset: 0,
         }
     }
 
+    /// Returns the built-in roots for this tree.
+    #[inline]
+    pub fn roots(&self) -> &Roots {
+        &self.roots
+    }
+
+    /// Returns the offset, in milliseconds, to add to modification times in
+    /// this tree to convert them to server time.
+    #[inline]
+    pub fn clock_offset(&self) -> i64 {
+        self.clock_offset
+    }
+
     /// Returns the number of nodes in the tree.
     #[inline]
     pub fn size(&self) -> usize {
@@ -119,11 +148,330 @@ impl Tree {
             .map(|&index| Node(self, &self.entries[index]))
     }
 
+    /// Returns the index of the node for a given `guid` in the tree, or
+    /// `None` if the node doesn't exist. Indices are stable until an item is
+    /// deleted from the tree, so the merger can use them to track nodes in
+    /// `GuidSet`s without cloning their GUIDs.
+    #[inline]
+    pub fn guid_index(&self, guid: &Guid) -> Option<GuidIndex> {
+        self.entry_index_by_guid
+            .get(guid)
+            .map(|&index| GuidIndex::new(index))
+    }
+
+    /// Returns the GUID of the node at a given index. Panics if the index is
+    /// out of bounds.
+    #[inline]
+    pub fn guid_for_index(&self, index: GuidIndex) -> &Guid {
+        &self.entries[index.as_usize()].item.guid
+    }
+
     /// Returns the structure divergences found when building the tree.
     #[inline]
     pub fn problems(&self) -> &Problems {
         &self.problems
     }
+
+    /// Returns a fingerprint of the tree's items, structure, and tombstones.
+    /// Trees built from the same items have the same fingerprint, so a
+    /// `MergeCheckpoint` can tell if a tree changed after an aborted merge.
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = FingerprintHasher::default();
+        self.clock_offset.hash(&mut hasher);
+        for entry in &self.entries {
+            let node = Node(self, entry);
+            let item = node.item();
+            item.guid.hash(&mut hasher);
+            item.kind.hash(&mut hasher);
+            item.age.hash(&mut hasher);
+            item.modified.hash(&mut hasher);
+            item.version.hash(&mut hasher);
+            item.needs_merge.hash(&mut hasher);
+            item.validity.hash(&mut hasher);
+            item.tags.hash(&mut hasher);
+            node.content().hash(&mut hasher);
+            node.diverged().hash(&mut hasher);
+            node.parent()
+                .map(|parent| parent.guid.clone())
+                .hash(&mut hasher);
+            for child in node.children() {
+                child.guid.hash(&mut hasher);
+            }
+        }
+        let mut deleted_guids = self.deleted_guids.iter().collect::<Vec<_>>();
+        deleted_guids.sort();
+        deleted_guids.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Inserts a new item into a folder at `position`, or at the end if the
+    /// position is out of bounds. If the item was deleted, its tombstone is
+    /// removed, and folders that mentioned it as a missing or deleted child
+    /// no longer have problems for it. The item and the folder are flagged
+    /// as changed.
+    pub fn insert(
+        &mut self,
+        parent_guid: &Guid,
+        position: usize,
+        mut item: Item,
+        content: Option<Content>,
+    ) -> Result<()> {
+        if self.exists(&item.guid) {
+            return Err(ErrorKind::DuplicateItem(item.guid).into());
+        }
+        let parent_index = match self.entry_index_by_guid.get(parent_guid) {
+            Some(&parent_index) => parent_index,
+            None => return Err(ErrorKind::MissingParent(item, parent_guid.clone()).into()),
+        };
+        let parent_item = &self.entries[parent_index].item;
+        if !parent_item.is_folder() {
+            return Err(ErrorKind::InvalidParent(item, parent_item.clone()).into());
+        }
+        self.deleted_guids.remove(&item.guid);
+        self.problems.forget_child(&item.guid);
+        item.needs_merge = true;
+        let index = self.entries.len();
+        self.entry_index_by_guid.insert(item.guid.clone(), index);
+        self.entries.push(TreeEntry {
+            item,
+            content,
+            divergence: Divergence::Consistent,
+            parent_index: Some(parent_index),
+            child_indices: Vec::new(),
+        });
+        let parent = &mut self.entries[parent_index];
+        let position = position.min(parent.child_indices.len());
+        parent.child_indices.insert(position, index);
+        parent.item.needs_merge = true;
+        Ok(())
+    }
+
+    /// Moves an existing item into a folder at `position`, or at the end if
+    /// the position is out of bounds. Since the item now has an explicit
+    /// parent, any structure problems noted for it when building the tree
+    /// are forgotten, though it stays diverged. The item, and its old and new
+    /// parents, are flagged as changed.
+    pub fn move_item(
+        &mut self,
+        guid: &Guid,
+        new_parent_guid: &Guid,
+        position: usize,
+    ) -> Result<()> {
+        let index = self.mutable_index(guid)?;
+        let new_parent_index = match self.entry_index_by_guid.get(new_parent_guid) {
+            Some(&new_parent_index) => new_parent_index,
+            None => {
+                let item = self.entries[index].item.clone();
+                return Err(ErrorKind::MissingParent(item, new_parent_guid.clone()).into());
+            }
+        };
+        let new_parent_item = &self.entries[new_parent_index].item;
+        if !new_parent_item.is_folder() {
+            let item = self.entries[index].item.clone();
+            return Err(ErrorKind::InvalidParent(item, new_parent_item.clone()).into());
+        }
+        // An item can't be moved into itself, or one of its descendants.
+        let mut ancestor_index = Some(new_parent_index);
+        while let Some(current_index) = ancestor_index {
+            if current_index == index {
+                return Err(ErrorKind::Cycle(guid.clone()).into());
+            }
+            ancestor_index = self.entries[current_index].parent_index;
+        }
+
+        let old_parent_index = self.detach(index);
+        let new_parent = &mut self.entries[new_parent_index];
+        let position = position.min(new_parent.child_indices.len());
+        new_parent.child_indices.insert(position, index);
+        new_parent.item.needs_merge = true;
+        let entry = &mut self.entries[index];
+        entry.parent_index = Some(new_parent_index);
+        entry.item.needs_merge = true;
+        self.entries[old_parent_index].item.needs_merge = true;
+        self.problems.forget_structure(guid);
+        Ok(())
+    }
+
+    /// Reorders a folder's children. `child_guids` must have the same GUIDs
+    /// as the folder's existing children. The folder is flagged as changed.
+    pub fn reorder(&mut self, parent_guid: &Guid, child_guids: &[Guid]) -> Result<()> {
+        let parent_index = match self.entry_index_by_guid.get(parent_guid) {
+            Some(&parent_index) => parent_index,
+            None => return Err(ErrorKind::MissingItem(parent_guid.clone()).into()),
+        };
+        let mut child_indices = Vec::with_capacity(child_guids.len());
+        for child_guid in child_guids {
+            match self.entry_index_by_guid.get(child_guid) {
+                Some(&child_index)
+                    if self.entries[child_index].parent_index == Some(parent_index)
+                        && !child_indices.contains(&child_index) =>
+                {
+                    child_indices.push(child_index)
+                }
+                _ => return Err(ErrorKind::MismatchedChildren(parent_guid.clone()).into()),
+            }
+        }
+        let parent = &mut self.entries[parent_index];
+        if child_indices.len() != parent.child_indices.len() {
+            return Err(ErrorKind::MismatchedChildren(parent_guid.clone()).into());
+        }
+        parent.child_indices = child_indices;
+        parent.item.needs_merge = true;
+        Ok(())
+    }
+
+    /// Changes the title of an item with content. The item is flagged as
+    /// changed. Like moving and deleting, retitling the Places root or a
+    /// built-in root is an error.
+    pub fn retitle(&mut self, guid: &Guid, new_title: String) -> Result<()> {
+        let index = self.mutable_index(guid)?;
+        let entry = &mut self.entries[index];
+        match &mut entry.content {
+            Some(Content::Bookmark { title, .. })
+            | Some(Content::Query { title, .. })
+            | Some(Content::Folder { title })
+            | Some(Content::Livemark { title, .. }) => *title = new_title,
+            Some(Content::Separator) | None => {
+                return Err(ErrorKind::UntitledItem(guid.clone()).into())
+            }
+        }
+        entry.item.needs_merge = true;
+        Ok(())
+    }
+
+    /// Deletes an item and all its descendants, and adds tombstones for them.
+    /// Problems for the deleted items are forgotten, and so are problems for
+    /// other items that the deleted folders mentioned in their children. The
+    /// item's parent is flagged as changed.
+    ///
+    /// Deleting moves other entries in the tree, so indices returned from
+    /// `Tree::guid_index` before the deletion are invalid.
+    pub fn delete(&mut self, guid: &Guid) -> Result<()> {
+        let index = self.mutable_index(guid)?;
+        let parent_index = self.detach(index);
+        self.entries[parent_index].item.needs_merge = true;
+
+        let mut deleted_indices = Vec::new();
+        let mut stack = vec![index];
+        while let Some(index) = stack.pop() {
+            deleted_indices.push(index);
+            stack.extend_from_slice(&self.entries[index].child_indices);
+        }
+        // Remove entries from the end, so that the last entry, which replaces
+        // each removed entry, is never one that we're about to remove.
+        deleted_indices.sort_unstable_by(|a, b| b.cmp(a));
+        let mut deleted_guids = HashSet::with_capacity(deleted_indices.len());
+        for index in deleted_indices {
+            let entry = self.entries.swap_remove(index);
+            self.entry_index_by_guid.remove(&entry.item.guid);
+            self.problems.forget(&entry.item.guid);
+            self.deleted_guids.insert(entry.item.guid.clone());
+            deleted_guids.insert(entry.item.guid);
+            if index == self.entries.len() {
+                continue;
+            }
+            // Point the moved entry's parent and children to its new index.
+            let old_index = self.entries.len();
+            let moved = &self.entries[index];
+            self.entry_index_by_guid
+                .insert(moved.item.guid.clone(), index);
+            if let Some(parent_index) = moved.parent_index {
+                for child_index in &mut self.entries[parent_index].child_indices {
+                    if *child_index == old_index {
+                        *child_index = index;
+                    }
+                }
+            }
+            for child_index in self.entries[index].child_indices.clone() {
+                self.entries[child_index].parent_index = Some(index);
+            }
+        }
+        self.problems.forget_parents(&deleted_guids);
+        Ok(())
+    }
+
+    /// Returns the index of an item that can be moved, retitled, or deleted.
+    /// The Places root and built-in roots can't be changed.
+    fn mutable_index(&self, guid: &Guid) -> Result<Index> {
+        let index = match self.entry_index_by_guid.get(guid) {
+            Some(&index) => index,
+            None => return Err(ErrorKind::MissingItem(guid.clone()).into()),
+        };
+        if index == 0 || self.roots.is_built_in_root(guid) {
+            return Err(ErrorKind::ImmutableRoot(guid.clone()).into());
+        }
+        Ok(index)
+    }
+
+    /// Removes an entry from its parent's children, and returns the parent's
+    /// index.
+    fn detach(&mut self, index: Index) -> Index {
+        let parent_index = self.entries[index]
+            .parent_index
+            .expect("Can't detach the Places root");
+        self.entries[parent_index]
+            .child_indices
+            .retain(|&child_index| child_index != index);
+        parent_index
+    }
+
+    /// Adds tags from the folders under the tags root to all bookmarks with
+    /// matching URLs outside the tags root.
+    fn migrate_tag_folders(&mut self) {
+        let tags_index = match self
+            .roots
+            .tags_guid()
+            .and_then(|guid| self.entry_index_by_guid.get(guid))
+        {
+            Some(&tags_index) => tags_index,
+            None => return,
+        };
+        let mut tags_by_url: HashMap<String, Vec<String>> = HashMap::new();
+        for &tag_index in &self.entries[tags_index].child_indices {
+            let tag_entry = &self.entries[tag_index];
+            let tag = match &tag_entry.content {
+                Some(Content::Folder { title }) if !title.is_empty() => title,
+                _ => continue,
+            };
+            for &tagged_index in &tag_entry.child_indices {
+                let tagged_entry = &self.entries[tagged_index];
+                if let Some(Content::Bookmark { url_href, .. }) = &tagged_entry.content {
+                    tags_by_url
+                        .entry(url_href.clone())
+                        .or_default()
+                        .push(tag.clone());
+                }
+            }
+        }
+        if tags_by_url.is_empty() {
+            return;
+        }
+        for index in 0..self.entries.len() {
+            // Skip the tags root and its descendants.
+            let mut ancestor_index = Some(index);
+            while let Some(current_index) = ancestor_index {
+                if current_index == tags_index {
+                    break;
+                }
+                ancestor_index = self.entries[current_index].parent_index;
+            }
+            if ancestor_index.is_some() {
+                continue;
+            }
+            let entry = &mut self.entries[index];
+            if let Some(Content::Bookmark { url_href, .. }) = &entry.content {
+                if let Some(tags) = tags_by_url.get(url_href) {
+                    for tag in tags {
+                        if entry.item.tags.insert(tag.clone()) {
+                            // The bookmark has new tags, so we need to
+                            // upload it.
+                            entry.item.needs_merge = true;
+                        }
+                    }
+                }
+            }
+        }
+    }
 }
 
 impl fmt::Display for Tree {
@@ -243,17 +591,51 @@ pub struct Builder {
     entries: Vec<BuilderEntry>,
     deleted_guids: HashSet<Guid>,
     reparent_orphans_to: Option<Guid>,
+    migrate_tag_folders: bool,
+    roots: Roots,
+    clock_offset: i64,
 }
 
 impl Builder {
-    /// Sets the default folder for reparented orphans. If not set, doesn't
-    /// exist, or not a folder, orphans will be reparented to the root.
+    /// Sets the default folder for reparented orphans. If not set, orphans
+    /// will be reparented to the orphans root, if the tree's roots have one.
+    /// If the folder doesn't exist, or isn't a folder, orphans will be
+    /// reparented to the tree root.
     #[inline]
     pub fn reparent_orphans_to(&mut self, guid: &Guid) -> &mut Builder {
         self.reparent_orphans_to = Some(guid.clone());
         self
     }
 
+    /// Fills in tags for bookmarks from Places-style tag folders when
+    /// building the tree. Each folder under the tags root is a tag, named by
+    /// its title, and contains bookmarks for the tagged URLs. Content info
+    /// must be set for the tag folders, their bookmarks, and the tagged
+    /// bookmarks, so that they can be matched by URL.
+    ///
+    /// The tag folders stay in the tree, so that the local store can remove
+    /// them once it's stored the migrated tags.
+    #[inline]
+    pub fn migrate_tag_folders(&mut self) -> &mut Builder {
+        self.migrate_tag_folders = true;
+        self
+    }
+
+    /// Sets the offset, in milliseconds, between the clock that recorded the
+    /// items' modification times and the server clock. The merger adds the
+    /// offset to each item's `modified` time, so that it can compare local
+    /// and remote times on the same clock.
+    ///
+    /// Remote items are timestamped by the server, so the remote tree's offset
+    /// is usually 0. For the local tree, the store can use the difference
+    /// between the server time from the last sync and the local time when it
+    /// received it.
+    #[inline]
+    pub fn clock_offset(&mut self, offset: i64) -> &mut Builder {
+        self.clock_offset = offset;
+        self
+    }
+
     /// Inserts an `item` into the tree. Returns an error if the item already
     /// exists.
     pub fn item(&mut self, item: Item) -> Result<ItemBuilder<'_>> {
@@ -470,12 +852,18 @@ impl TryFrom<Builder> for Tree {
         }
 
         // Now we have a consistent tree.
-        Ok(Tree {
+        let mut tree = Tree {
             entry_index_by_guid: builder.entry_index_by_guid,
             entries,
             deleted_guids: builder.deleted_guids,
             problems,
-        })
+            roots: builder.roots,
+            clock_offset: builder.clock_offset,
+        };
+        if builder.migrate_tag_folders {
+            tree.migrate_tag_folders();
+        }
+        Ok(tree)
     }
 }
 
@@ -488,7 +876,7 @@ impl<'b> ItemBuilder<'b> {
     /// items with similar contents and different GUIDs.
     #[inline]
     pub fn content<'c>(&'c mut self, content: Content) -> &'c mut ItemBuilder<'b> {
-        mem::replace(&mut self.0.entries[self.1].content, Some(content));
+        self.0.entries[self.1].content = Some(content);
         self
     }
 
@@ -700,7 +1088,7 @@ impl BuilderEntry {
         let old_parent = mem::replace(&mut self.parent, BuilderEntryParent::None);
         let new_parent = match old_parent {
             BuilderEntryParent::Root => {
-                mem::replace(&mut self.parent, BuilderEntryParent::Root);
+                self.parent = BuilderEntryParent::Root;
                 return Err(ErrorKind::DuplicateItem(self.item.guid.clone()).into());
             }
             BuilderEntryParent::None => match new_parents {
@@ -728,7 +1116,7 @@ impl BuilderEntry {
                 BuilderEntryParent::Partial(parents)
             }
         };
-        mem::replace(&mut self.parent, new_parent);
+        self.parent = new_parent;
         Ok(())
     }
 }
@@ -797,7 +1185,7 @@ impl<'a> ResolveParent<'a> {
     }
 
     fn resolve(self) -> ResolvedParent {
-        if self.entry.item.guid.is_built_in_root() {
+        if self.builder.roots.is_built_in_root(&self.entry.item.guid) {
             self.user_content_root()
         } else {
             self.item()
@@ -968,6 +1356,7 @@ impl<'a> ResolveParent<'a> {
         self.builder
             .reparent_orphans_to
             .as_ref()
+            .or_else(|| self.builder.roots.orphans_guid())
             .and_then(|guid| self.builder.entry_index_by_guid.get(guid))
             .cloned()
             .filter(|&parent_index| {
@@ -1181,7 +1570,7 @@ pub enum Problem {
 
 impl Problem {
     /// Returns count deltas for this problem.
-    fn counts(&self) -> ProblemCounts {
+    pub(crate) fn counts(&self) -> ProblemCounts {
         let (parents, deltas) = match self {
             Problem::Orphan => {
                 return ProblemCounts {
@@ -1320,6 +1709,24 @@ impl fmt::Display for DivergedParent {
     }
 }
 
+/// Indicates if all parents are the same existing folder, or there aren't
+/// any parents.
+fn parents_agree(parents: &[DivergedParent]) -> bool {
+    let mut parent_guids = parents.iter().map(|parent| match parent {
+        DivergedParent::ByChildren(parent_guid)
+        | DivergedParent::ByParentGuid(DivergedParentGuid::Folder(parent_guid)) => {
+            Some(parent_guid)
+        }
+        DivergedParent::ByParentGuid(_) => None,
+    });
+    match parent_guids.next() {
+        Some(first_parent_guid) => {
+            first_parent_guid.is_some() && parent_guids.all(|guid| guid == first_parent_guid)
+        }
+        None => true,
+    }
+}
+
 /// Describes an invalid `parentid`.
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum DivergedParentGuid {
@@ -1351,6 +1758,66 @@ impl Problems {
         self.0.is_empty()
     }
 
+    /// Forgets all problems for an item.
+    #[inline]
+    pub(crate) fn forget(&mut self, guid: &Guid) {
+        self.0.remove(guid);
+    }
+
+    /// Forgets problems with an item's parents, keeping problems with its
+    /// children and validity.
+    pub(crate) fn forget_structure(&mut self, guid: &Guid) {
+        if let Some(problems) = self.0.get_mut(guid) {
+            problems.retain(|problem| {
+                !matches!(
+                    problem,
+                    Problem::Orphan | Problem::MisparentedRoot(_) | Problem::DivergedParents(_)
+                )
+            });
+            if problems.is_empty() {
+                self.0.remove(guid);
+            }
+        }
+    }
+
+    /// Forgets problems for folders that mention an item as a missing or
+    /// deleted child.
+    pub(crate) fn forget_child(&mut self, child_guid: &Guid) {
+        self.0.retain(|_, problems| {
+            problems.retain(|problem| match problem {
+                Problem::MissingChild { child_guid: guid }
+                | Problem::DeletedChild { child_guid: guid } => guid != child_guid,
+                _ => true,
+            });
+            !problems.is_empty()
+        });
+    }
+
+    /// Forgets that any of the given folders mention other items in their
+    /// children. Items whose remaining parents agree no longer have problems
+    /// with their parents. Misparented roots are still misparented, even if
+    /// no folders mention them.
+    pub(crate) fn forget_parents(&mut self, parent_guids: &HashSet<Guid>) {
+        let is_kept = |parent: &DivergedParent| match parent {
+            DivergedParent::ByChildren(parent_guid) => !parent_guids.contains(parent_guid),
+            DivergedParent::ByParentGuid(_) => true,
+        };
+        self.0.retain(|_, problems| {
+            problems.retain_mut(|problem| match problem {
+                Problem::MisparentedRoot(parents) => {
+                    parents.retain(is_kept);
+                    true
+                }
+                Problem::DivergedParents(parents) => {
+                    parents.retain(is_kept);
+                    !parents_agree(parents)
+                }
+                _ => true,
+            });
+            !problems.is_empty()
+        });
+    }
+
     /// Returns an iterator for all problems.
     pub fn summarize(&self) -> impl Iterator<Item = ProblemSummary<'_>> {
         self.0.iter().flat_map(|(guid, problems)| {
@@ -1378,13 +1845,13 @@ pub struct ProblemSummary<'a>(&'a Guid, &'a Problem);
 
 impl<'a> ProblemSummary<'a> {
     #[inline]
-    pub fn guid(&self) -> &Guid {
-        &self.0
+    pub fn guid(&self) -> &'a Guid {
+        self.0
     }
 
     #[inline]
-    pub fn problem(&self) -> &Problem {
-        &self.1
+    pub fn problem(&self) -> &'a Problem {
+        self.1
     }
 }
 
@@ -1437,6 +1904,7 @@ impl<'a> fmt::Display for ProblemSummary<'a> {
 /// Records total problem counts for telemetry. An item can have multiple
 /// problems, but each problem is only counted once per item.
 #[derive(Clone, Copy, Default, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ProblemCounts {
     /// Number of items that aren't mentioned in any parent's `children` and
     /// don't have a `parentid`. These are very rare; it's likely that a
@@ -1464,6 +1932,20 @@ pub struct ProblemCounts {
 }
 
 impl ProblemCounts {
+    /// Returns the total number of problems.
+    pub fn total(&self) -> usize {
+        self.orphans
+            + self.misparented_roots
+            + self.multiple_parents_by_children
+            + self.deleted_parent_guids
+            + self.missing_parent_guids
+            + self.non_folder_parent_guids
+            + self.parent_child_disagreements
+            + self.deleted_children
+            + self.missing_children
+            + self.invalid_items
+    }
+
     /// Adds two sets of counts together.
     pub fn add(&self, other: ProblemCounts) -> ProblemCounts {
         ProblemCounts {
@@ -1511,7 +1993,7 @@ impl<'t> Node<'t> {
 
     /// Returns the child at the given index, or `None` if the index is out of
     /// bounds.
-    pub fn child(&self, index: usize) -> Option<Node<'_>> {
+    pub fn child(&self, index: usize) -> Option<Node<'t>> {
         self.1
             .child_indices
             .get(index)
@@ -1535,13 +2017,23 @@ impl<'t> Node<'t> {
 
     /// Returns the resolved parent of this node, or `None` if this is the
     /// root node.
-    pub fn parent(&self) -> Option<Node<'_>> {
+    pub fn parent(&self) -> Option<Node<'t>> {
         self.1
             .parent_index
             .as_ref()
             .map(|&parent_index| Node(self.0, &self.0.entries[parent_index]))
     }
 
+    /// Returns the modification time of this node's item on the server clock,
+    /// or `None` if the item doesn't have an absolute modification time.
+    /// Saturates instead of overflowing for bogus times or offsets.
+    #[inline]
+    pub fn server_modified(&self) -> Option<i64> {
+        self.item()
+            .modified
+            .map(|modified| modified.saturating_add(self.0.clock_offset))
+    }
+
     /// Returns the level of this node in the tree.
     pub fn level(&self) -> i64 {
         if self.is_root() {
@@ -1578,7 +2070,7 @@ impl<'t> Node<'t> {
             Kind::Livemark => false,
             // Exclude orphaned Places queries (bug 1433182).
             Kind::Query if self.diverged() => false,
-            _ => self.parent().map_or(false, |parent| parent.is_syncable()),
+            _ => self.parent().is_some_and(|parent| parent.is_syncable()),
         }
     }
 
@@ -1598,7 +2090,7 @@ impl<'t> Node<'t> {
         self.to_ascii_fragment("")
     }
 
-    fn to_ascii_fragment(&self, prefix: &str) -> String {
+    fn to_ascii_fragment(self, prefix: &str) -> String {
         match self.item().kind {
             Kind::Folder => {
                 let children_prefix = format!("{}| ", prefix);
@@ -1640,11 +2132,11 @@ impl<'t> Node<'t> {
         ptr::eq(self.1, &self.0.entries[0])
     }
 
-    /// Indicates if this node is a Places built-in root. Any other roots except
-    /// these are non-syncable.
+    /// Indicates if this node is one of the tree's built-in roots. Any other
+    /// roots except these are non-syncable.
     #[inline]
     pub fn is_built_in_root(&self) -> bool {
-        self.item().guid.is_built_in_root()
+        self.0.roots.is_built_in_root(&self.item().guid)
     }
 }
 
@@ -1668,9 +2160,23 @@ impl<'t> fmt::Display for Node<'t> {
 pub struct Item {
     pub guid: Guid,
     pub kind: Kind,
+    /// The time since the item was last modified, in milliseconds, relative
+    /// to when the tree was fetched.
     pub age: i64,
+    /// The absolute time when the item was last modified, in milliseconds
+    /// since the epoch, on the clock of the side that fetched it. The tree's
+    /// `clock_offset` converts it to server time. If both sides of a conflict
+    /// have modification times, the merger compares them instead of ages.
+    pub modified: Option<i64>,
+    /// The version vector for the item's last change, if the store tracks
+    /// them. In `ConflictOrdering::Causal` mode, the merger uses it to order
+    /// changes by causality instead of time.
+    pub version: Option<VersionVector>,
     pub needs_merge: bool,
     pub validity: Validity,
+    /// Tags for a bookmark. If the bookmark changed on both sides, the merged
+    /// tags are the union of the local and remote tags.
+    pub tags: BTreeSet<String>,
 }
 
 impl Item {
@@ -1681,8 +2187,11 @@ impl Item {
             guid,
             kind,
             age: 0,
+            modified: None,
+            version: None,
             needs_merge: false,
             validity: Validity::Valid,
+            tags: BTreeSet::new(),
         }
     }
 
@@ -1767,6 +2276,19 @@ pub struct MergedNode<'t> {
     pub guid: Guid,
     pub merge_state: MergeState<'t>,
     pub merged_children: Vec<MergedNode<'t>>,
+    /// The union of the local and remote tags, if the item changed on both
+    /// sides with different tags. `tags()` returns the merged tags for all
+    /// items.
+    pub merged_tags: Option<BTreeSet<String>>,
+    /// The merged content for the item, if the merger changed it. This
+    /// happens when the merger removes a clashing keyword from a bookmark.
+    /// `content()` returns the merged content for all items.
+    pub merged_content: Option<Content>,
+    /// Indicates if the merger changed the item's value, by merging its tags
+    /// or removing a clashing keyword, and it needs to be reuploaded. Unlike
+    /// a new remote structure, this doesn't change the item's parent or
+    /// children, so it doesn't affect the merge state.
+    pub needs_reupload: bool,
 }
 
 impl<'t> MergedNode<'t> {
@@ -1776,16 +2298,62 @@ impl<'t> MergedNode<'t> {
             guid,
             merge_state,
             merged_children: Vec::new(),
+            merged_tags: None,
+            merged_content: None,
+            needs_reupload: false,
+        }
+    }
+
+    /// Returns the merged tags for the item: either the union of the local
+    /// and remote tags, or the tags from the preferred side.
+    pub fn tags(&self) -> &BTreeSet<String> {
+        match &self.merged_tags {
+            Some(merged_tags) => merged_tags,
+            None => &self.merge_state.node().item().tags,
+        }
+    }
+
+    /// Returns the merged content for the item, or the content from the
+    /// preferred side if the merger didn't change it.
+    pub fn content(&self) -> Option<&Content> {
+        match &self.merged_content {
+            Some(merged_content) => Some(merged_content),
+            None => self.merge_state.node().content(),
         }
     }
 
+    /// Returns `true` if the item should be flagged for (re)upload, either
+    /// because of its merge state, or because the merger changed its value.
+    #[inline]
+    pub fn should_upload(&self) -> bool {
+        self.merge_state.should_upload() || self.needs_reupload
+    }
+
+    /// Returns the merged version vector for the item, which happened after
+    /// the local and remote versions. Stores should save it with the merged
+    /// item, so that later changes are ordered after this merge. Returns
+    /// `None` if neither side has a version.
+    pub fn version(&self) -> Option<VersionVector> {
+        let versions = self
+            .merge_state
+            .local_node()
+            .into_iter()
+            .chain(self.merge_state.remote_node())
+            .filter_map(|node| node.version.as_ref());
+        versions.fold(None, |merged_version, version| {
+            let mut merged_version = merged_version.unwrap_or_default();
+            merged_version.merge(version);
+            Some(merged_version)
+        })
+    }
+
     /// Indicates if the merged node exists locally and has a new GUID.
     /// The merger uses this to flag deduped items and items with invalid
     /// GUIDs with new local structure.
     pub fn local_guid_changed(&self) -> bool {
         self.merge_state
             .local_node()
-            .map_or(false, |local_node| local_node.guid != self.guid)
+            .is_some_and(|local_node| local_node.guid != self.guid)
     }
 
     /// Indicates if the merged node exists remotely and has a new GUID. The
@@ -1794,7 +2362,7 @@ impl<'t> MergedNode<'t> {
     pub fn remote_guid_changed(&self) -> bool {
         self.merge_state
             .remote_node()
-            .map_or(false, |remote_node| remote_node.guid != self.guid)
+            .is_some_and(|remote_node| remote_node.guid != self.guid)
     }
 
     /// Returns an ASCII art representation of the root and its descendants,
@@ -2108,8 +2676,8 @@ impl<'t> MergeState<'t> {
 
     /// Returns the node from the preferred side. Unlike `local_node()` and
     /// `remote_node()`, this doesn't indicate which side, so it's only used
-    /// for logging and `try_from()`.
-    fn node(&self) -> &Node<'t> {
+    /// for logging, merged values, and `try_from()`.
+    pub(crate) fn node(&self) -> &Node<'t> {
         match self {
             MergeState::LocalOnly(local_node)
             | MergeState::LocalOnlyWithNewLocalStructure(local_node)
@@ -2146,13 +2714,63 @@ impl<'t> fmt::Display for MergeState<'t> {
 /// new local items to remote items that don't exist locally, with different
 /// GUIDs and similar content.
 ///
-/// - Bookmarks must have the same title and URL.
+/// - Bookmarks must have the same title and URL. Keywords and descriptions
+///   aren't compared.
 /// - Queries must have the same title and query URL.
-/// - Folders and livemarks must have the same title.
+/// - Folders must have the same title.
+/// - Livemarks must have the same title, feed URL, and site URL.
 /// - Separators must have the same position within their parents.
-#[derive(Debug, Eq, Hash, PartialEq)]
+///
+/// The merger also uses bookmark keywords to make sure that each keyword
+/// points to one URL in the merged tree.
+///
+/// Livemarks aren't syncable, so the merger deletes them from both sides.
+/// Their content is still kept in the tree, so that stores can convert them
+/// to and from records, and restore them from an undo journal.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum Content {
-    Bookmark { title: String, url_href: String },
-    Folder { title: String },
+    Bookmark {
+        title: String,
+        url_href: String,
+        keyword: Option<String>,
+        description: Option<String>,
+    },
+    Query {
+        title: String,
+        /// The `place:` URL, which holds the query parameters.
+        url_href: String,
+    },
+    Folder {
+        title: String,
+    },
+    Livemark {
+        title: String,
+        feed_url: String,
+        site_url: Option<String>,
+    },
     Separator,
 }
+
+impl Content {
+    /// Returns the kind of item that this content describes.
+    pub fn kind(&self) -> Kind {
+        match self {
+            Content::Bookmark { .. } => Kind::Bookmark,
+            Content::Query { .. } => Kind::Query,
+            Content::Folder { .. } => Kind::Folder,
+            Content::Livemark { .. } => Kind::Livemark,
+            Content::Separator => Kind::Separator,
+        }
+    }
+
+    /// Returns the title, or `None` for separators.
+    pub fn title(&self) -> Option<&str> {
+        match self {
+            Content::Bookmark { title, .. }
+            | Content::Query { title, .. }
+            | Content::Folder { title }
+            | Content::Livemark { title, .. } => Some(title),
+            Content::Separator => None,
+        }
+    }
+}
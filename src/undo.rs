@@ -0,0 +1,365 @@
+// Copyright 2018-2019 Mozilla
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::HashSet, fmt};
+
+use crate::driver::{AbortSignal, DefaultAbortSignal};
+use crate::error::Result;
+use crate::guid::Guid;
+use crate::merge::{to_strings, MergedRoot};
+use crate::tree::{Content, Item, Node};
+
+/// An undo journal reverses the local changes made by applying a merged tree.
+///
+/// The journal is built from the same merged root that's passed to
+/// `Store::apply`, before it's applied, and owns everything it needs to
+/// restore the local tree to its pre-merge state. Stores should apply the
+/// journal in field order: restore changed GUIDs first, so that the remaining
+/// ops can refer to items by their original local GUIDs, then delete inserted
+/// items, restore items and their children, and finally restore sync flags
+/// and tombstones.
+///
+/// The journal only covers the local tree. Records that were uploaded, and
+/// remote items that were flagged as merged, can't be recalled, so a store
+/// should discard its staged outgoing items when it applies the journal.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct UndoJournal {
+    pub restore_guids: Vec<RestoreGuid>,
+    pub delete_items: Vec<DeleteItem>,
+    pub restore_items: Vec<RestoreItem>,
+    pub restore_children: Vec<RestoreChildren>,
+    pub restore_needs_merge: Vec<RestoreNeedsMerge>,
+    pub delete_tombstones: Vec<DeleteTombstone>,
+    pub restore_tombstones: Vec<RestoreTombstone>,
+}
+
+impl UndoJournal {
+    /// Builds an undo journal for a merged root, without interruption.
+    #[inline]
+    pub fn new(merged_root: &MergedRoot<'_>) -> UndoJournal {
+        UndoJournal::with_signal(merged_root, &DefaultAbortSignal).unwrap()
+    }
+
+    /// Builds an undo journal for a merged root, by inverting its completion
+    /// ops. The abort signal can be used to interrupt building the journal.
+    /// Building the ops reports progress and records telemetry, like
+    /// `MergedRoot::completion_ops_with_signal`.
+    pub fn with_signal(
+        merged_root: &MergedRoot<'_>,
+        signal: &impl AbortSignal,
+    ) -> Result<UndoJournal> {
+        let ops = merged_root.completion_ops_with_signal(signal)?;
+        let mut journal = UndoJournal::default();
+
+        // Local nodes whose values, parents, or positions change, and local
+        // folders whose children change.
+        let mut changed_nodes = Vec::new();
+        let mut changed_folders = Vec::new();
+
+        for op in &ops.change_guids {
+            signal.err_if_aborted()?;
+            journal.restore_guids.push(RestoreGuid {
+                merged_guid: op.merged_node.guid.clone(),
+                local_guid: op.local_node().guid.clone(),
+            });
+        }
+        for op in &ops.apply_remote_items {
+            signal.err_if_aborted()?;
+            match op.merged_node.merge_state.local_node() {
+                Some(local_node) => changed_nodes.push(*local_node),
+                None => journal
+                    .delete_items
+                    .push(DeleteItem(op.merged_node.guid.clone())),
+            }
+        }
+        for op in &ops.apply_merged_tags {
+            signal.err_if_aborted()?;
+            if let Some(local_node) = op.merged_node.merge_state.local_node() {
+                changed_nodes.push(*local_node);
+            }
+        }
+        for op in &ops.apply_merged_content {
+            signal.err_if_aborted()?;
+            if let Some(local_node) = op.merged_node.merge_state.local_node() {
+                changed_nodes.push(*local_node);
+            }
+        }
+        for op in &ops.apply_new_local_structure {
+            signal.err_if_aborted()?;
+            if let Some(local_node) = op.merged_node.merge_state.local_node() {
+                changed_nodes.push(*local_node);
+            }
+            if let Some(local_parent_node) = op.merged_parent_node.merge_state.local_node() {
+                changed_folders.push(*local_parent_node);
+            }
+        }
+        for op in &ops.delete_local_items {
+            signal.err_if_aborted()?;
+            changed_nodes.push(op.local_node());
+        }
+
+        let mut seen_guids = HashSet::new();
+        for local_node in changed_nodes {
+            signal.err_if_aborted()?;
+            if !seen_guids.insert(&local_node.item().guid) {
+                continue;
+            }
+            let local_parent_node = local_node.parent();
+            if let Some(local_parent_node) = local_parent_node {
+                changed_folders.push(local_parent_node);
+            }
+            journal.restore_items.push(RestoreItem::new(local_node));
+        }
+        // Restore parents before children, so that stores can insert deleted
+        // items into their original folders.
+        journal.restore_items.sort_by_key(|op| op.level);
+
+        let mut seen_guids = HashSet::new();
+        for local_folder_node in changed_folders {
+            signal.err_if_aborted()?;
+            if !seen_guids.insert(&local_folder_node.item().guid) {
+                continue;
+            }
+            journal.restore_children.push(RestoreChildren {
+                parent_guid: local_folder_node.guid.clone(),
+                child_guids: local_folder_node
+                    .children()
+                    .map(|node| node.guid.clone())
+                    .collect(),
+            });
+        }
+
+        for op in &ops.set_local_unmerged {
+            signal.err_if_aborted()?;
+            if let Some(local_node) = op.merged_node.merge_state.local_node() {
+                journal.restore_needs_merge.push(RestoreNeedsMerge {
+                    guid: local_node.guid.clone(),
+                    needs_merge: local_node.needs_merge,
+                });
+            }
+        }
+        for op in &ops.set_local_merged {
+            signal.err_if_aborted()?;
+            if let Some(local_node) = op.merged_node.merge_state.local_node() {
+                journal.restore_needs_merge.push(RestoreNeedsMerge {
+                    guid: local_node.guid.clone(),
+                    needs_merge: local_node.needs_merge,
+                });
+            }
+        }
+        for op in &ops.insert_local_tombstones {
+            signal.err_if_aborted()?;
+            journal
+                .delete_tombstones
+                .push(DeleteTombstone(op.remote_node().guid.clone()));
+        }
+        for op in &ops.delete_local_tombstones {
+            signal.err_if_aborted()?;
+            journal
+                .restore_tombstones
+                .push(RestoreTombstone(op.guid().clone()));
+        }
+
+        Ok(journal)
+    }
+
+    /// Returns `true` if there's nothing to undo.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.restore_guids.is_empty()
+            && self.delete_items.is_empty()
+            && self.restore_items.is_empty()
+            && self.restore_children.is_empty()
+            && self.restore_needs_merge.is_empty()
+            && self.delete_tombstones.is_empty()
+            && self.restore_tombstones.is_empty()
+    }
+
+    /// Returns a printable summary of all undo ops to apply.
+    pub fn summarize(&self) -> Vec<String> {
+        std::iter::empty()
+            .chain(to_strings(&self.restore_guids))
+            .chain(to_strings(&self.delete_items))
+            .chain(to_strings(&self.restore_items))
+            .chain(to_strings(&self.restore_children))
+            .chain(to_strings(&self.restore_needs_merge))
+            .chain(to_strings(&self.delete_tombstones))
+            .chain(to_strings(&self.restore_tombstones))
+            .collect()
+    }
+}
+
+/// An undo op to change a merged GUID back to the original local GUID. This
+/// reverses a `ChangeGuid` completion op.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct RestoreGuid {
+    pub merged_guid: Guid,
+    pub local_guid: Guid,
+}
+
+impl fmt::Display for RestoreGuid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Change {} back to {}", self.merged_guid, self.local_guid)
+    }
+}
+
+/// An undo op to delete a remote item that was inserted into the local tree,
+/// without storing a tombstone for it.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct DeleteItem(Guid);
+
+impl DeleteItem {
+    /// Returns the GUID of the item to delete.
+    #[inline]
+    pub fn guid(&self) -> &Guid {
+        &self.0
+    }
+}
+
+impl fmt::Display for DeleteItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Delete inserted item {}", self.0)
+    }
+}
+
+/// An undo op to restore a local item's original value and structure. This
+/// reverses `ApplyRemoteItem`, `ApplyMergedTags`, `ApplyMergedContent`,
+/// `ApplyNewLocalStructure`, and `DeleteLocalItem` completion ops.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RestoreItem {
+    /// The original local item.
+    pub item: Item,
+    /// The original content info for the item, if available.
+    pub content: Option<Content>,
+    /// The GUID of the original parent, or `None` for the root.
+    pub parent_guid: Option<Guid>,
+    /// The original position of the item in its parent.
+    pub position: usize,
+    /// The level of the item in the original local tree.
+    pub level: i64,
+}
+
+impl RestoreItem {
+    fn new(local_node: Node<'_>) -> RestoreItem {
+        let local_parent_node = local_node.parent();
+        RestoreItem {
+            item: local_node.item().clone(),
+            content: local_node.content().cloned(),
+            parent_guid: local_parent_node.map(|node| node.guid.clone()),
+            position: local_parent_node
+                .and_then(|node| {
+                    node.children()
+                        .position(|child_node| child_node.guid == local_node.guid)
+                })
+                .unwrap_or(0),
+            level: local_node.level(),
+        }
+    }
+}
+
+impl fmt::Display for RestoreItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.parent_guid {
+            Some(parent_guid) => write!(
+                f,
+                "Restore local {} into {} at {}",
+                self.item.guid, parent_guid, self.position
+            ),
+            None => write!(f, "Restore local {}", self.item.guid),
+        }
+    }
+}
+
+/// An undo op to restore the original order of a local folder's children.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct RestoreChildren {
+    pub parent_guid: Guid,
+    pub child_guids: Vec<Guid>,
+}
+
+impl fmt::Display for RestoreChildren {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Restore children of {} as [{}]",
+            self.parent_guid,
+            self.child_guids
+                .iter()
+                .map(Guid::as_str)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+}
+
+/// An undo op to restore a local item's original sync flag. This reverses
+/// `SetLocalUnmerged` and `SetLocalMerged` completion ops.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct RestoreNeedsMerge {
+    pub guid: Guid,
+    pub needs_merge: bool,
+}
+
+impl fmt::Display for RestoreNeedsMerge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Flag local {} as {}",
+            self.guid,
+            if self.needs_merge {
+                "unmerged"
+            } else {
+                "merged"
+            }
+        )
+    }
+}
+
+/// An undo op to delete a local tombstone that was inserted by an
+/// `InsertLocalTombstone` completion op.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct DeleteTombstone(Guid);
+
+impl DeleteTombstone {
+    /// Returns the GUID of the tombstone.
+    #[inline]
+    pub fn guid(&self) -> &Guid {
+        &self.0
+    }
+}
+
+impl fmt::Display for DeleteTombstone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Delete inserted tombstone {}", self.0)
+    }
+}
+
+/// An undo op to restore a local tombstone that was deleted by a
+/// `DeleteLocalTombstone` completion op.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct RestoreTombstone(Guid);
+
+impl RestoreTombstone {
+    /// Returns the GUID of the tombstone.
+    #[inline]
+    pub fn guid(&self) -> &Guid {
+        &self.0
+    }
+}
+
+impl fmt::Display for RestoreTombstone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Restore local tombstone {}", self.0)
+    }
+}
@@ -0,0 +1,365 @@
+// Copyright 2018-2019 Mozilla
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+#[cfg(feature = "hmac-sha256")]
+use std::fmt::Write;
+
+#[cfg(feature = "hmac-sha256")]
+use hmac_sha256::HMAC;
+
+use crate::guid::Guid;
+use crate::tree::{DivergedParent, DivergedParentGuid, Node, Problem, ProblemCounts, Tree};
+
+/// A type of structure problem. Each kind matches a field in
+/// `ProblemCounts`.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ProblemKind {
+    Orphan,
+    MisparentedRoot,
+    MultipleParentsByChildren,
+    DeletedParentGuid,
+    MissingParentGuid,
+    NonFolderParentGuid,
+    ParentChildDisagreement,
+    DeletedChild,
+    MissingChild,
+    InvalidItem,
+}
+
+impl ProblemKind {
+    /// All problem kinds, in the same order as the fields in
+    /// `ProblemCounts`.
+    pub const ALL: [ProblemKind; 10] = [
+        ProblemKind::Orphan,
+        ProblemKind::MisparentedRoot,
+        ProblemKind::MultipleParentsByChildren,
+        ProblemKind::DeletedParentGuid,
+        ProblemKind::MissingParentGuid,
+        ProblemKind::NonFolderParentGuid,
+        ProblemKind::ParentChildDisagreement,
+        ProblemKind::DeletedChild,
+        ProblemKind::MissingChild,
+        ProblemKind::InvalidItem,
+    ];
+
+    /// Returns the count for this kind of problem.
+    pub fn count(self, counts: &ProblemCounts) -> usize {
+        match self {
+            ProblemKind::Orphan => counts.orphans,
+            ProblemKind::MisparentedRoot => counts.misparented_roots,
+            ProblemKind::MultipleParentsByChildren => counts.multiple_parents_by_children,
+            ProblemKind::DeletedParentGuid => counts.deleted_parent_guids,
+            ProblemKind::MissingParentGuid => counts.missing_parent_guids,
+            ProblemKind::NonFolderParentGuid => counts.non_folder_parent_guids,
+            ProblemKind::ParentChildDisagreement => counts.parent_child_disagreements,
+            ProblemKind::DeletedChild => counts.deleted_children,
+            ProblemKind::MissingChild => counts.missing_children,
+            ProblemKind::InvalidItem => counts.invalid_items,
+        }
+    }
+}
+
+/// An item with a structure problem, sampled for a validation report.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ProblemSample {
+    /// The GUID of the item. For missing and deleted children, this is the
+    /// child's GUID.
+    pub guid: String,
+    /// Hashes of the item's parent GUIDs: the folders that mention it in
+    /// their `children`, and its `parentid`. For missing and deleted
+    /// children, this is the folder that mentions the child; for orphans and
+    /// invalid items, it's the parent in the built tree. Empty if the
+    /// `hmac-sha256` feature, which is on by default, is turned off.
+    pub parent_hashes: Vec<String>,
+}
+
+/// A report of the structure problems in a tree, with a limited number of
+/// sample items for each kind of problem. Parent GUIDs are hashed, so that
+/// samples with the same parents can be grouped without sending the parents.
+/// With the `serde` feature, reports can be serialized and attached to a
+/// sync ping.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ValidationReport {
+    /// Total counts for each problem.
+    pub counts: ProblemCounts,
+    /// Sample items for each kind of problem, in GUID order.
+    pub samples: BTreeMap<ProblemKind, Vec<ProblemSample>>,
+}
+
+impl ValidationReport {
+    /// Returns the samples for a kind of problem.
+    #[inline]
+    pub fn samples(&self, kind: ProblemKind) -> &[ProblemSample] {
+        self.samples.get(&kind).map_or(&[], Vec::as_slice)
+    }
+}
+
+/// Builds validation reports for trees.
+#[derive(Clone, Debug)]
+pub struct Validator {
+    max_samples: usize,
+    #[cfg_attr(not(feature = "hmac-sha256"), allow(dead_code))]
+    key: Vec<u8>,
+}
+
+impl Validator {
+    /// Creates a validator that keeps up to `max_samples` sample items for
+    /// each kind of problem, and hashes parent GUIDs with HMAC-SHA256, using
+    /// the given key. The key should be random, and not sent with the
+    /// report; using a different key for each report keeps hashes from being
+    /// matched across reports. Hashing needs the default `hmac-sha256`
+    /// feature; without it, samples don't include parent hashes.
+    pub fn new(max_samples: usize, key: impl Into<Vec<u8>>) -> Validator {
+        Validator {
+            max_samples,
+            key: key.into(),
+        }
+    }
+
+    /// Builds a validation report for the problems in a tree.
+    pub fn validate(&self, tree: &Tree) -> ValidationReport {
+        let problems = tree.problems();
+        let mut summaries = problems.summarize().collect::<Vec<_>>();
+        summaries.sort_by(|a, b| a.guid().cmp(b.guid()));
+
+        let mut samples: BTreeMap<ProblemKind, Vec<ProblemSample>> = BTreeMap::new();
+        for summary in summaries {
+            let counts = summary.problem().counts();
+            for kind in ProblemKind::ALL.iter().cloned() {
+                if kind.count(&counts) == 0 {
+                    continue;
+                }
+                let samples_for_kind = samples.entry(kind).or_default();
+                if samples_for_kind.len() >= self.max_samples {
+                    continue;
+                }
+                samples_for_kind.push(self.sample(tree, summary.guid(), summary.problem()));
+            }
+        }
+
+        ValidationReport {
+            counts: problems.counts(),
+            samples,
+        }
+    }
+
+    /// Builds a sample for an item with a problem.
+    fn sample(&self, tree: &Tree, guid: &Guid, problem: &Problem) -> ProblemSample {
+        let (guid, parent_guids) = match problem {
+            Problem::MisparentedRoot(parents) | Problem::DivergedParents(parents) => (
+                guid,
+                parents.iter().map(diverged_parent_guid).collect::<Vec<_>>(),
+            ),
+            Problem::MissingChild { child_guid } | Problem::DeletedChild { child_guid } => {
+                (child_guid, vec![guid])
+            }
+            Problem::Orphan | Problem::InvalidItem => (
+                guid,
+                tree.node_for_guid(guid)
+                    .and_then(|node| node.parent())
+                    .map(|parent| vec![&parent.item().guid])
+                    .unwrap_or_default(),
+            ),
+        };
+        ProblemSample {
+            guid: guid.as_str().into(),
+            parent_hashes: self.hash_parent_guids(parent_guids),
+        }
+    }
+
+    /// Hashes the parent GUIDs for a sample.
+    #[cfg(feature = "hmac-sha256")]
+    fn hash_parent_guids(&self, parent_guids: Vec<&Guid>) -> Vec<String> {
+        parent_guids
+            .into_iter()
+            .map(|parent_guid| self.hash_guid(parent_guid))
+            .collect()
+    }
+
+    /// Without HMAC-SHA256, we can't hash parent GUIDs, so we leave them out
+    /// instead of sending them unhashed.
+    #[cfg(not(feature = "hmac-sha256"))]
+    fn hash_parent_guids(&self, _: Vec<&Guid>) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Hashes a GUID with HMAC-SHA256, and returns the hex-encoded hash.
+    #[cfg(feature = "hmac-sha256")]
+    pub(crate) fn hash_guid(&self, guid: &Guid) -> String {
+        let mac = HMAC::mac(guid.as_str(), &self.key);
+        mac.iter()
+            .fold(String::with_capacity(64), |mut hash, byte| {
+                let _ = write!(hash, "{:02x}", byte);
+                hash
+            })
+    }
+}
+
+/// Lists the differences between a local tree and a remote tree built from
+/// the server, by GUID, without merging them. If both sides are in sync,
+/// the report is empty; any differences point to a corrupt collection or a
+/// sync bug.
+///
+/// Items that changed on either side since the last sync are skipped,
+/// because they're expected to differ until the next merge. So are items
+/// that don't sync, like livemarks and the Places root. Tombstones in either
+/// tree are deletions that haven't synced yet, so items that are deleted on
+/// the other side are skipped, too.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ComparisonReport {
+    /// Items that exist locally, but aren't on the server.
+    pub missing_from_server: Vec<String>,
+    /// Items on the server that don't exist locally.
+    pub missing_from_client: Vec<String>,
+    /// Items with different kinds on each side.
+    pub kind_mismatches: Vec<String>,
+    /// Items with different parents on each side.
+    pub parent_mismatches: Vec<String>,
+    /// Folders with different children, or children in a different order,
+    /// on each side.
+    pub structure_mismatches: Vec<String>,
+    /// Items with different titles, URLs, or other content on each side.
+    pub content_mismatches: Vec<String>,
+}
+
+impl ComparisonReport {
+    /// Compares the syncable items in the local and remote trees.
+    pub fn new(local_tree: &Tree, remote_tree: &Tree) -> ComparisonReport {
+        let mut report = ComparisonReport::default();
+
+        for local_node in syncable_nodes(local_tree) {
+            let guid = &local_node.guid;
+            match remote_tree.node_for_guid(guid) {
+                Some(remote_node) => {
+                    if remote_node.needs_merge {
+                        continue;
+                    }
+                    report.compare_nodes(local_node, remote_node);
+                }
+                None => {
+                    if remote_tree.is_deleted(guid) {
+                        continue;
+                    }
+                    report.missing_from_server.push(guid.as_str().into());
+                }
+            }
+        }
+
+        for remote_node in syncable_nodes(remote_tree) {
+            let guid = &remote_node.guid;
+            if local_tree.exists(guid) || local_tree.is_deleted(guid) {
+                // We already compared items that exist on both sides, and
+                // skip items that are deleted locally.
+                continue;
+            }
+            report.missing_from_client.push(guid.as_str().into());
+        }
+
+        for guids in report.categories_mut() {
+            guids.sort();
+        }
+        report
+    }
+
+    /// Indicates if the trees are in sync.
+    pub fn is_empty(&self) -> bool {
+        self.categories().all(|guids| guids.is_empty())
+    }
+
+    /// Compares an item that exists on both sides.
+    fn compare_nodes(&mut self, local_node: Node<'_>, remote_node: Node<'_>) {
+        let guid = || local_node.guid.as_str().into();
+        if local_node.kind != remote_node.kind {
+            // Items with different kinds can't have the same content or
+            // children, so we don't compare them further.
+            self.kind_mismatches.push(guid());
+            return;
+        }
+        let local_parent_guid = local_node.parent().map(|parent| parent.guid.clone());
+        let remote_parent_guid = remote_node.parent().map(|parent| parent.guid.clone());
+        if local_parent_guid != remote_parent_guid {
+            self.parent_mismatches.push(guid());
+        }
+        if local_node.is_folder() {
+            let local_children = local_node
+                .children()
+                .filter(|child| child.is_syncable())
+                .map(|child| child.guid.clone());
+            let remote_children = remote_node
+                .children()
+                .filter(|child| child.is_syncable())
+                .map(|child| child.guid.clone());
+            if !local_children.eq(remote_children) {
+                self.structure_mismatches.push(guid());
+            }
+        }
+        if let (Some(local_content), Some(remote_content)) =
+            (local_node.content(), remote_node.content())
+        {
+            if local_content != remote_content {
+                self.content_mismatches.push(guid());
+            }
+        }
+    }
+
+    fn categories(&self) -> impl Iterator<Item = &Vec<String>> {
+        vec![
+            &self.missing_from_server,
+            &self.missing_from_client,
+            &self.kind_mismatches,
+            &self.parent_mismatches,
+            &self.structure_mismatches,
+            &self.content_mismatches,
+        ]
+        .into_iter()
+    }
+
+    fn categories_mut(&mut self) -> impl Iterator<Item = &mut Vec<String>> {
+        vec![
+            &mut self.missing_from_server,
+            &mut self.missing_from_client,
+            &mut self.kind_mismatches,
+            &mut self.parent_mismatches,
+            &mut self.structure_mismatches,
+            &mut self.content_mismatches,
+        ]
+        .into_iter()
+    }
+}
+
+/// Returns the syncable nodes in a tree that haven't changed since the last
+/// sync.
+fn syncable_nodes(tree: &Tree) -> impl Iterator<Item = Node<'_>> {
+    tree.guids()
+        .filter_map(move |guid| tree.node_for_guid(guid))
+        .filter(|node| !node.needs_merge && node.is_syncable())
+}
+
+/// Returns the GUID of a diverged parent.
+fn diverged_parent_guid(parent: &DivergedParent) -> &Guid {
+    match parent {
+        DivergedParent::ByChildren(guid) => guid,
+        DivergedParent::ByParentGuid(parent_guid) => match parent_guid {
+            DivergedParentGuid::Folder(guid)
+            | DivergedParentGuid::NonFolder(guid)
+            | DivergedParentGuid::Deleted(guid)
+            | DivergedParentGuid::Missing(guid) => guid,
+        },
+    }
+}